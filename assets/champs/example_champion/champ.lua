@@ -1,6 +1,68 @@
 
 game.register_asset("./model.glb#Scene0")
 
+game.register_ability{
+    id = "example_champion_strike",
+    name = "Strike",
+    cooldown = 6.0,
+    cost = 40.0,
+    cast_time = 0.25,
+    range = 2.0,
+    targeting = "unit",
+    on_cast = function(unit, target)
+        if target:get_team() ~= unit:get_team() then
+            unit:deal_damage{ target = target, amount = 60.0, kind = "a", tags = { "ability" } }
+        end
+    end,
+}
+
+game.register_ability{
+    id = "example_champion_guard",
+    name = "Guard",
+    cooldown = 14.0,
+    cost = 60.0,
+    cast_time = 0.0,
+    range = 0.0,
+    targeting = "self",
+    on_cast = function(unit)
+        unit:add_shield(120.0)
+    end,
+}
+
+game.register_ability{
+    id = "example_champion_blink",
+    name = "Blink",
+    cooldown = 18.0,
+    cost = 80.0,
+    cast_time = 0.0,
+    range = 4.0,
+    targeting = "point",
+    on_cast = function(unit, target)
+        unit:set_position(target)
+    end,
+}
+
+game.register_ability{
+    id = "example_champion_quake",
+    name = "Quake",
+    cooldown = 60.0,
+    cost = 100.0,
+    cast_time = 0.75,
+    range = 5.0,
+    targeting = "area",
+    radius = 2.0,
+    on_cast = function(unit, target)
+        local team = unit:get_team()
+        for _, other in unit:get_visible_units() do
+            local pos = other:get_position()
+            local dx, dy = pos.x - target.x, pos.y - target.y
+            if other:get_team() ~= team and dx * dx + dy * dy <= 2.0 * 2.0 then
+                unit:deal_damage{ target = other, amount = 150.0, kind = "b", tags = { "ability" } }
+            end
+        end
+    end,
+}
+
 game.register_unit{
     id = "example_champion",
     name = "Example Champion",
@@ -17,6 +79,26 @@ game.register_unit{
         resistance_b = 0.0,
         resistance_c = 0.0,
         range = 1.0,
+        max_health_per_level = 80.0,
+        attack_speed_per_level = 0.02,
+        attack_a_per_level = 3.0,
+        resistance_a_per_level = 3.0,
+        resistance_b_per_level = 1.5,
+    },
+    model = "./model.glb#Scene0",
+    resource = {
+        kind = "mana",
+        max = 300.0,
+        regen = 2.0,
+    },
+    xp_reward = 150.0,
+    on_level_up = function(unit, level)
+        unit:restore_resource(50.0)
+    end,
+    abilities = {
+        "example_champion_strike",
+        "example_champion_guard",
+        "example_champion_blink",
+        "example_champion_quake",
     },
-    model = "./model.glb#Scene0"
 }
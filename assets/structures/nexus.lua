@@ -34,7 +34,7 @@ end
 
 nexus.on_destroyed = function(self)
     -- The team this nexus belongs to should lose
-    -- game.make_lose(self.team)
+    game.make_lose(self:get_team())
 end
 
 local function straight_path(from: Vec2, to: Vec2)
@@ -19,6 +19,7 @@ game.register_unit{
         range = 1.0,
     },
     model = "./minion.glb#Scene0",
+    xp_reward = 30.0,
     on_spawn = function (unit)
         unit:apply_effect{
             proto = "minion.ai"
@@ -45,6 +46,7 @@ game.register_unit{
         range = 3.0,
     },
     model = "./minion.glb#Scene0",
+    xp_reward = 25.0,
     on_spawn = function (unit)
         unit:apply_effect{
             proto = "minion.ai"
@@ -89,26 +91,22 @@ game.register_effect{
 
         if closest_unit ~= nil then
             my_unit:set_attack_target(closest_unit)
-        else
-            -- No units close enough to target; we instead try to follow our path
-            -- Our next waypoint is:
-            local data = my_unit:get_custom_data() :: AiData?
-            if data == nil then
-                return
-            end
-            local next_waypoint = data.path[1]
-
-            if next_waypoint == nil then
-                -- No path left to walk
-                return
-            end
+        end
 
-            my_unit:set_movement_target(next_waypoint)
-            -- If we are close enough, we remove it from our path
-            if sqr_dist(my_pos, next_waypoint) < 25.0 then
-                table.remove(data.path, 1)
-                my_unit:set_custom_data(data)
+        -- Hand our path over to the unit's order queue, which walks it once we are done fighting.
+        -- The queue only holds so many orders, whatever doesn't fit is handed over later.
+        local data = my_unit:get_custom_data() :: AiData?
+        if data == nil or #data.path == 0 then
+            return
+        end
+        local queued = 0
+        for _, waypoint in data.path do
+            if not my_unit:queue_attack_move(waypoint) then
+                break
             end
+            queued += 1
         end
+        data.path = table.move(data.path, queued + 1, #data.path, 1, {})
+        my_unit:set_custom_data(data)
     end
 }
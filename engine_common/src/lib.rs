@@ -46,4 +46,11 @@ pub struct MapDef {
     pub id: MapId,
     pub name: String,
     pub script: PathBuf,
+    /// How many teams play on the map, numbered from 0
+    #[serde(default = "default_team_count")]
+    pub team_count: usize,
+}
+
+fn default_team_count() -> usize {
+    2
 }
@@ -0,0 +1,134 @@
+//! Runs any number of bots without a window, each connecting to the lobby server, getting into a
+//! game and playing it like a player would. Used to load test the lobby and game servers.
+
+use std::{path::PathBuf, thread, time::Duration};
+
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    input_focus::InputDispatchPlugin,
+    log::LogPlugin,
+    prelude::*,
+    render::{RenderPlugin, settings::WgpuSettings},
+    window::ExitCondition,
+    winit::WinitPlugin,
+};
+use bevy_enhanced_input::EnhancedInputPlugin;
+use bevy_inspector_egui::bevy_egui::EguiPlugin;
+use clap::Parser;
+use game::{BotBehaviour, BotOrder, BotRng, LobbyMode, Options};
+
+#[derive(Parser)]
+struct BotOptions {
+    /// How many bots to run
+    #[arg(long, default_value_t = 1)]
+    count: usize,
+    /// How many bots play in each lobby, all of them by default
+    #[arg(long)]
+    lobby_size: Option<usize>,
+    /// Lobby server to connect to
+    #[arg(long, default_value = "localhost")]
+    lobby_address: String,
+    /// Follows the orders in this RON file instead of random ones
+    #[arg(long)]
+    script: Option<PathBuf>,
+    /// Seed for random orders, to which each bot adds its index
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+fn main() -> AppExit {
+    let options = BotOptions::parse();
+
+    let behaviour = match &options.script {
+        Some(path) => {
+            let script = std::fs::read_to_string(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|script| Ok(ron::from_str::<Vec<BotOrder>>(&script)?));
+            match script {
+                Ok(orders) => BotBehaviour::Script(orders),
+                Err(err) => {
+                    eprintln!("Failed reading bot script {}: {err}", path.display());
+                    return AppExit::error();
+                }
+            }
+        }
+        None => BotBehaviour::Random,
+    };
+    let lobby_size = options.lobby_size.unwrap_or(options.count).max(1);
+
+    let bots = (0..options.count)
+        .map(|index| {
+            let behaviour = behaviour.clone();
+            let lobby_address = options.lobby_address.clone();
+            let seed = options.seed.wrapping_add(index as u64);
+            thread::Builder::new()
+                .name(format!("bot {index}"))
+                .spawn(move || run_bot(index, lobby_size, lobby_address, behaviour, seed))
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    let failed = bots
+        .into_iter()
+        .filter(|bot| !bot.join().is_ok_and(|exit| exit.is_success()))
+        .count();
+    if failed > 0 {
+        eprintln!("{failed} of {} bots failed", options.count);
+        AppExit::error()
+    } else {
+        AppExit::Success
+    }
+}
+
+/// The first bot of every lobby creates it and starts the game once it is full,
+/// the rest join whatever lobby has room
+fn run_bot(
+    index: usize,
+    lobby_size: usize,
+    lobby_address: String,
+    behaviour: BotBehaviour,
+    seed: u64,
+) -> AppExit {
+    let options = if index % lobby_size == 0 {
+        Options::bot(lobby_address, LobbyMode::AutoCreate, Some(lobby_size))
+    } else {
+        Options::bot(lobby_address, LobbyMode::AutoJoinFirst, None)
+    };
+
+    let mut plugins = DefaultPlugins
+        .set(WindowPlugin {
+            primary_window: None,
+            exit_condition: ExitCondition::DontExit,
+            ..default()
+        })
+        .set(RenderPlugin {
+            render_creation: WgpuSettings {
+                backends: None,
+                ..default()
+            }
+            .into(),
+            ..default()
+        })
+        .disable::<WinitPlugin>();
+    if index > 0 {
+        // Logging can only be set up once per process
+        plugins = plugins.disable::<LogPlugin>();
+    }
+
+    App::new()
+        .insert_resource(options)
+        .insert_resource(behaviour)
+        .insert_resource(BotRng::new(seed))
+        .add_plugins((
+            plugins,
+            ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(1.0 / 60.0)),
+            InputDispatchPlugin,
+            EguiPlugin {
+                enable_multipass_for_primary_context: true,
+            },
+            EnhancedInputPlugin,
+            game::client,
+            game::bot,
+        ))
+        .run()
+}
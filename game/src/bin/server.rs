@@ -1,8 +1,12 @@
-use std::{net::IpAddr, sync::Arc, time::Duration};
+use std::{
+    net::{SocketAddr, SocketAddrV4},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
 use bevy::{
     app::{ScheduleRunnerPlugin, TerminalCtrlCHandlerPlugin},
-    asset::uuid::Uuid,
     diagnostic::DiagnosticsPlugin,
     log::LogPlugin,
     platform::collections::HashMap,
@@ -10,128 +14,194 @@ use bevy::{
     state::app::StatesPlugin,
 };
 use clap::Parser;
-use engine_common::ChampionId;
+use engine_common::{ChampionId, MapDef, MapId};
 use game::{
-    InGamePlayerInfo, PROTOCOL_ID, Players, PrivateKey, ServerFixedUpdateDuration, ServerOptions,
-    Sess,
+    InGamePlayerInfo, LobbyControl, MatchMap, PROTOCOL_ID, Players, PrivateKey, ServerCertificate,
+    ServerFixedUpdateDuration, ServerOptions, Sess, client_id, connect_candidates,
+    direct_player_id, to_hex,
+};
+use lightyear::{
+    connection::netcode::PRIVATE_KEY_BYTES,
+    prelude::{ClientId, ConnectToken, generate_key},
 };
-use lightyear::prelude::{ClientId, ConnectToken, generate_key};
-use lobby_common::{LobbyToServer, PlayerId, ServerToLobby, Team};
+use lobby_common::{LobbyToServer, ServerToLobby, Team};
+use serde::Deserialize;
 use wtransport::{Endpoint, Identity, ServerConfig};
 
+/// A match set up by hand for `--direct-connect`, e.g.
+/// `(map: "default", players: [(name: "Alice", team: 0, champion: "example_champion")])`
+#[derive(Deserialize)]
+struct MatchFile {
+    #[serde(default = "default_map")]
+    map: String,
+    players: Vec<MatchPlayer>,
+}
+
+#[derive(Deserialize)]
+struct MatchPlayer {
+    name: String,
+    team: usize,
+    champion: String,
+    /// What the player passes as `--client-id`, their place in the list by default
+    client_id: Option<u64>,
+}
+
+fn default_map() -> String {
+    "default".into()
+}
+
+impl Default for MatchFile {
+    /// A lone guest, as used before match files existed
+    fn default() -> Self {
+        Self {
+            map: default_map(),
+            players: vec![MatchPlayer {
+                name: "Guest".into(),
+                team: 0,
+                champion: "example_champion".into(),
+                client_id: None,
+            }],
+        }
+    }
+}
+
 // #[tokio::main]
 fn main() -> AppExit {
     let options = ServerOptions::parse();
 
-    let private_key = if options.direct_connect { [0; 32] } else { generate_key() };
+    let private_key = if options.direct_connect {
+        options.private_key.unwrap_or([0; PRIVATE_KEY_BYTES])
+    } else {
+        generate_key()
+    };
 
-    let players = if !options.direct_connect {
-        tokio::runtime::Runtime::new()
-            .unwrap()
-            .block_on(async move {
-                // Wait for connection from lobby server
-                let server = Endpoint::server(
-                    ServerConfig::builder()
-                        .with_bind_default(options.internal_port)
-                        .with_identity(
-                            Identity::self_signed([
-                                "localhost",
-                                "127.0.0.1",
-                                "::1",
-                                "moba.elekrisk.com",
-                            ])
-                            .unwrap(),
-                        )
-                        .build(),
-                )
-                .unwrap();
+    let certificate = ServerCertificate::self_signed();
 
-                let conn = Sess(Arc::new(xwt_wtransport::Connection(
-                    tokio::time::timeout(Duration::from_secs(5), async {
-                        server.accept().await.await.unwrap().accept().await.unwrap()
-                    })
-                    .await
-                    .unwrap(),
-                )));
-
-                #[allow(irrefutable_let_patterns)]
-                let LobbyToServer::Handshake {
-                    settings: _,
-                    players,
-                } = conn.recv().await.unwrap()
-                else {
-                    return None;
-                };
-
-                let mut tokens = std::collections::HashMap::new();
-
-                let mut player_infos = HashMap::new();
-
-                for player in players {
-                    let client_id = player.id.0.as_u64_pair().0;
-                    let ip_addr: IpAddr = match (player.is_ipv4, player.is_local) {
-                        (true, true) => options.local_address_ipv4.into(),
-                        (true, false) => options.public_address_ipv4.into(),
-                        (false, _) => options.address_ipv6.into(),
-                    };
-                    println!("For player {}, use address {}", player.name, ip_addr);
-                    let token = ConnectToken::build(
-                        (ip_addr, options.external_port),
-                        PROTOCOL_ID,
-                        client_id,
-                        private_key,
+    let mut lobby_control = None;
+    let (players, map) = if !options.direct_connect {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let handshake = runtime.block_on(async {
+            // Wait for connection from lobby server
+            let server = Endpoint::server(
+                ServerConfig::builder()
+                    .with_bind_default(options.internal_port)
+                    .with_identity(
+                        Identity::self_signed([
+                            "localhost",
+                            "127.0.0.1",
+                            "::1",
+                            "moba.elekrisk.com",
+                        ])
+                        .unwrap(),
                     )
-                    .generate()
-                    .unwrap();
-
-                    player_infos.insert(
-                        player.id,
-                        InGamePlayerInfo {
-                            id: player.id,
-                            name: player.name,
-                            client_id: ClientId::Netcode(client_id),
-                            team: player.team,
-                            champion: player.champ,
-                            controlled_unit: None,
-                        },
-                    );
-
-                    let bytes = token.try_into_bytes().unwrap();
-
-                    tokens.insert(player.id, bytes.to_vec());
-                }
+                    .build(),
+            )
+            .unwrap();
+
+            let conn = Sess(Arc::new(xwt_wtransport::Connection(
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    server.accept().await.await.unwrap().accept().await.unwrap()
+                })
+                .await
+                .unwrap(),
+            )));
 
-                conn.send(ServerToLobby::PlayerTokens { tokens })
-                    .await
-                    .unwrap();
+            let LobbyToServer::Handshake {
+                settings: _,
+                players,
+            } = conn.recv().await.unwrap()
+            else {
+                return None;
+            };
 
-                conn.0.0.closed().await;
+            let mut tokens = std::collections::HashMap::new();
+
+            let mut player_infos = HashMap::new();
+
+            for player in players {
+                let candidates = connect_candidates(
+                    &options,
+                    private_key,
+                    &certificate,
+                    player.id,
+                    player.is_ipv4,
+                    player.is_local,
+                );
+                println!(
+                    "For player {}, use addresses {:?}",
+                    player.name,
+                    candidates.iter().map(|c| c.address).collect::<Vec<_>>()
+                );
+
+                player_infos.insert(
+                    player.id,
+                    InGamePlayerInfo {
+                        id: player.id,
+                        name: player.name,
+                        client_id: ClientId::Netcode(client_id(player.id)),
+                        team: player.team,
+                        champion: player.champ,
+                        controlled_unit: None,
+                    },
+                );
+
+                tokens.insert(player.id, candidates);
+            }
+
+            conn.send(ServerToLobby::PlayerTokens { tokens })
+                .await
+                .unwrap();
 
-                Some(Players {
+            Some((
+                Players {
                     players: player_infos,
-                })
-            })
+                },
+                conn,
+            ))
+        });
+
+        let Some((players, conn)) = handshake else {
+            return AppExit::error();
+        };
+
+        // Keep talking to the lobby server for as long as the match runs
+        let (control, relay) = LobbyControl::new(conn);
+        std::thread::spawn(move || runtime.block_on(relay));
+        lobby_control = Some(control);
+
+        // The lobby doesn't let players pick a map yet
+        (players, MatchMap(MapId("default".into())))
     } else {
-        Some(Players {
-            players: HashMap::from_iter([(PlayerId(Uuid::nil()), InGamePlayerInfo {
-                id: PlayerId(Uuid::nil()),
-                client_id: ClientId::Netcode(0),
-                name: "Guest".into(),
-                team: Team(0),
-                champion: ChampionId("example_champion".into()),
-                controlled_unit: None,
-            })]),
-        })
+        let match_file = match &options.match_file {
+            Some(path) => match read_match_file(path) {
+                Ok(match_file) => match_file,
+                Err(err) => {
+                    eprintln!("Failed reading match file {}: {err}", path.display());
+                    return AppExit::error();
+                }
+            },
+            None => MatchFile::default(),
+        };
+        let map = MatchMap(MapId(match_file.map.clone()));
+        match direct_connect_players(&options, private_key, match_file) {
+            Ok(players) => (players, map),
+            Err(err) => {
+                eprintln!("Invalid match file: {err}");
+                return AppExit::error();
+            }
+        }
     };
 
-    let Some(players) = players else {
-        return AppExit::error();
-    };
+    let mut app = App::new();
+    if let Some(control) = lobby_control {
+        app.insert_resource(control);
+    }
 
-    App::new()
-        .insert_resource(options)
+    app.insert_resource(options)
         .insert_resource(players)
+        .insert_resource(map)
         .insert_resource(PrivateKey(private_key))
+        .insert_resource(certificate)
         .add_plugins((
             MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(
                 // Run 60 times per second.
@@ -167,3 +237,77 @@ fn main() -> AppExit {
 
 #[derive(Resource)]
 struct Timing(std::time::Instant);
+
+fn read_match_file(path: &Path) -> anyhow::Result<MatchFile> {
+    Ok(ron::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+/// How many teams play on `map`, read straight from its definition as the asset server isn't
+/// running yet
+fn map_team_count(map: &str) -> anyhow::Result<usize> {
+    let path = format!("assets/maps/{map}/def.ron");
+    let def = std::fs::read_to_string(&path)
+        .map_err(|err| anyhow::anyhow!("can't read map definition {path}: {err}"))?;
+    let def: MapDef = ron::from_str(&def)?;
+    Ok(def.team_count)
+}
+
+/// Makes the players of a direct connect match, printing how each of them can join
+fn direct_connect_players(
+    options: &ServerOptions,
+    private_key: [u8; PRIVATE_KEY_BYTES],
+    match_file: MatchFile,
+) -> anyhow::Result<Players> {
+    let address = SocketAddr::V4(SocketAddrV4::new(
+        options.public_address_ipv4,
+        options.external_port,
+    ));
+
+    let team_count = map_team_count(&match_file.map)?;
+
+    let mut players = HashMap::new();
+    for (index, player) in match_file.players.into_iter().enumerate() {
+        let client_id = player.client_id.unwrap_or(index as u64);
+        let id = direct_player_id(client_id);
+        if players.contains_key(&id) {
+            anyhow::bail!("client id {client_id} is used by more than one player");
+        }
+        if player.team >= team_count {
+            anyhow::bail!(
+                "{} is on team {}, but map {} only has {team_count} teams, numbered from 0",
+                player.name,
+                player.team,
+                match_file.map,
+            );
+        }
+
+        // Tokens are printed for players to copy, so they shouldn't run out before being used
+        let token = ConnectToken::build(address, PROTOCOL_ID, client_id, private_key)
+            .expire_seconds(-1)
+            .generate()
+            .unwrap()
+            .try_into_bytes()
+            .unwrap();
+        println!(
+            "{} (team {}) can join with: --direct-connect {address} --client-id {client_id} \
+             --connect-token {}",
+            player.name,
+            player.team,
+            to_hex(&token),
+        );
+
+        players.insert(
+            id,
+            InGamePlayerInfo {
+                id,
+                client_id: ClientId::Netcode(client_id),
+                name: player.name,
+                team: Team(player.team),
+                champion: ChampionId(player.champion),
+                controlled_unit: None,
+            },
+        );
+    }
+
+    Ok(Players { players })
+}
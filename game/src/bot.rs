@@ -0,0 +1,180 @@
+//! Bots that find their way into a game and play it on their own, for load testing.
+//!
+//! Getting into a game reuses the client's own `--connect`, `--lobby-mode`,
+//! `--auto-pick-first-champ` and `--auto-lock` flows. Once in game, bots order their unit around
+//! the same way a player's clicks would.
+
+use std::time::Duration;
+
+use bevy::{prelude::*, time::common_conditions::on_timer};
+use lobby_common::{ClientToLobby, Team};
+use serde::{Deserialize, Deserializer, de::Error as _};
+
+use crate::{
+    GameState, LobbySender, Players,
+    ingame::{
+        targetable::{Health, Position},
+        unit::{MyTeam, Unit, UnitId, movement::OrderIssued},
+    },
+    main_ui::{LobbyMenuState, lobby_list::MyPlayerId},
+};
+
+/// How often a bot waiting for a lobby to join asks for the lobby list again
+const LOBBY_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far from its unit a random move order can go
+const RANDOM_MOVE_RANGE: f32 = 15.0;
+
+/// How close an enemy has to be for a random attack order
+const RANDOM_ATTACK_RANGE: f32 = 12.0;
+
+pub fn plugin(app: &mut App) {
+    app.add_systems(
+        Update,
+        (
+            refetch_lobby_list
+                .run_if(in_state(LobbyMenuState::LobbyList).and(on_timer(LOBBY_RETRY_INTERVAL))),
+            issue_orders.run_if(
+                in_state(GameState::InGame)
+                    .and(resource_exists::<Players>)
+                    .and(resource_exists::<MyPlayerId>)
+                    .and(resource_exists::<MyTeam>),
+            ),
+        ),
+    )
+    .add_systems(OnExit(GameState::InGame), leave_after_game);
+}
+
+/// What a bot does once in game
+#[derive(Resource, Debug, Clone)]
+pub enum BotBehaviour {
+    /// Wanders around, attacking enemies that come close
+    Random,
+    /// Follows these orders, starting over once done
+    Script(Vec<BotOrder>),
+}
+
+/// One step of a bot script.
+/// Scripts are RON lists like `[Move((10.0, 5.0)), Wait(2.0), AttackNearest]`.
+#[derive(Debug, Clone, Deserialize)]
+pub enum BotOrder {
+    /// Walks to this point on the map
+    Move(Vec2),
+    /// Attacks the closest enemy unit we can see, if any
+    AttackNearest,
+    /// Waits this many seconds before the next order
+    Wait(#[serde(deserialize_with = "wait_duration")] Duration),
+}
+
+/// Rejects waits that aren't a valid number of seconds when the script is read, rather than once
+/// the bot gets to them
+fn wait_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let secs = f32::deserialize(deserializer)?;
+    Duration::try_from_secs_f32(secs)
+        .map_err(|err| D::Error::custom(format!("invalid wait of {secs} seconds: {err}")))
+}
+
+/// Where random orders come from, seeded so runs can be repeated
+#[derive(Resource)]
+pub struct BotRng(u64);
+
+impl BotRng {
+    pub fn new(seed: u64) -> Self {
+        // Xorshift never leaves zero
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    /// A number in `0.0..1.0`
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next()
+    }
+}
+
+fn refetch_lobby_list(sender: Res<LobbySender>) {
+    _ = sender.send(ClientToLobby::FetchLobbyList);
+}
+
+fn issue_orders(
+    behaviour: Res<BotBehaviour>,
+    mut rng: ResMut<BotRng>,
+    players: Res<Players>,
+    my_id: Res<MyPlayerId>,
+    my_team: Res<MyTeam>,
+    units: Query<(&UnitId, &Position, &Team), (With<Unit>, With<Health>)>,
+    time: Res<Time>,
+    mut next_order_at: Local<Duration>,
+    mut script_step: Local<usize>,
+    mut commands: Commands,
+) {
+    if time.elapsed() < *next_order_at {
+        return;
+    }
+    let Some((_, my_pos, _)) = players
+        .players
+        .get(&my_id.0)
+        .and_then(|me| me.controlled_unit)
+        .and_then(|unit| units.get(unit).ok())
+    else {
+        return;
+    };
+
+    let nearest_enemy = |range: f32| {
+        units
+            .iter()
+            .filter(|(_, _, team)| **team != my_team.0)
+            .map(|(id, pos, _)| (*id, pos.distance(**my_pos)))
+            .filter(|(_, distance)| *distance <= range)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(id, _)| id)
+    };
+
+    let order = match &*behaviour {
+        BotBehaviour::Random => {
+            *next_order_at = time.elapsed() + Duration::from_secs_f32(rng.range(1.0, 4.0));
+            match nearest_enemy(RANDOM_ATTACK_RANGE) {
+                Some(enemy) if rng.next() < 0.3 => OrderIssued::Attack(enemy),
+                _ => OrderIssued::Move(Position(
+                    my_pos.0
+                        + vec2(
+                            rng.range(-RANDOM_MOVE_RANGE, RANDOM_MOVE_RANGE),
+                            rng.range(-RANDOM_MOVE_RANGE, RANDOM_MOVE_RANGE),
+                        ),
+                )),
+            }
+        }
+        BotBehaviour::Script(orders) => {
+            let Some(step) = orders.get(*script_step % orders.len().max(1)) else {
+                return;
+            };
+            *script_step += 1;
+            match step {
+                BotOrder::Move(target) => OrderIssued::Move(Position(*target)),
+                BotOrder::AttackNearest => match nearest_enemy(f32::INFINITY) {
+                    Some(enemy) => OrderIssued::Attack(enemy),
+                    None => return,
+                },
+                BotOrder::Wait(duration) => {
+                    *next_order_at = time.elapsed().saturating_add(*duration);
+                    return;
+                }
+            }
+        }
+    };
+
+    order.send(&mut commands);
+}
+
+/// Bots play a single game, then leave the lobby server and stop
+fn leave_after_game(sender: Option<Res<LobbySender>>, mut exit: EventWriter<AppExit>) {
+    if let Some(sender) = sender {
+        _ = sender.send(ClientToLobby::Disconnect);
+    }
+    exit.write(AppExit::Success);
+}
@@ -0,0 +1,292 @@
+//! Text chat between players during a match.
+//!
+//! Clients send what they type to the server, which puts the sender's name on it and passes it on
+//! to their team, or to everyone for all chat.
+
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::{platform::collections::HashMap, prelude::*};
+use bevy_inspector_egui::bevy_egui::{
+    EguiContextPass, EguiContexts, input::egui_wants_any_keyboard_input,
+};
+use egui_dock::egui;
+use lightyear::prelude::*;
+use lobby_common::{PlayerId, Team};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppExt, GameState, Players,
+    ingame::{
+        map::MessageChannel,
+        unit::MyTeam,
+        validation::{CommandValidator, RejectedCommand, Rejection, reject},
+    },
+};
+
+/// Longer messages are cut off
+const MAX_MESSAGE_CHARS: usize = 200;
+
+/// How many messages a player can send within [`RATE_LIMIT_WINDOW`]
+const RATE_LIMIT_MESSAGES: usize = 5;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(5);
+
+/// How many of the latest messages are shown
+const SHOWN_MESSAGES: usize = 10;
+
+/// How long a message stays on screen while the chat box is closed, and how long it then takes
+/// to fade out
+const MESSAGE_SHOW_TIME: Duration = Duration::from_secs(6);
+const MESSAGE_FADE_TIME: Duration = Duration::from_secs(1);
+
+pub fn plugin(app: &mut App) {
+    app.register_trigger::<SendChatMessage>(ChannelDirection::ClientToServer);
+    app.register_trigger::<ChatMessage>(ChannelDirection::ServerToClient);
+
+    if app.is_server() {
+        app.init_resource::<SentMessages>()
+            .add_observer(on_send_chat_message);
+    } else {
+        app.init_resource::<ChatBox>()
+            .init_resource::<ChatLog>()
+            .add_observer(on_chat_message)
+            .add_systems(OnEnter(GameState::InGame), clear_chat)
+            .add_systems(
+                Update,
+                open_chat.run_if(
+                    in_state(GameState::InGame)
+                        .and(not(typing_in_chat))
+                        .and(not(egui_wants_any_keyboard_input)),
+                ),
+            )
+            .add_systems(EguiContextPass, chat_ui.run_if(in_state(GameState::InGame)));
+    }
+}
+
+/// Who a chat message goes to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChatChannel {
+    #[default]
+    Team,
+    All,
+}
+
+/// Sent by a client to say something in chat
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct SendChatMessage {
+    pub channel: ChatChannel,
+    pub text: String,
+}
+
+/// A chat message passed on by the server to everyone it was meant for
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub channel: ChatChannel,
+    pub sender: PlayerId,
+    pub name: String,
+    pub team: Team,
+    pub text: String,
+}
+
+/// When each client last sent messages, for rate limiting
+#[derive(Resource, Default)]
+struct SentMessages(HashMap<ClientId, VecDeque<Duration>>);
+
+fn on_send_chat_message(
+    trigger: Trigger<FromClients<SendChatMessage>>,
+    validator: CommandValidator,
+    players: Res<Players>,
+    mut sent: ResMut<SentMessages>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    let from = trigger.from;
+    let player = match validator.player(from) {
+        Ok(player) => player,
+        Err(rejection) => {
+            return reject(&mut commands, from, RejectedCommand::ChatMessage, rejection);
+        }
+    };
+
+    let text = trigger
+        .message
+        .text
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_MESSAGE_CHARS)
+        .collect::<String>();
+    if text.is_empty() {
+        return;
+    }
+
+    let sent = sent.0.entry(from).or_default();
+    while sent
+        .front()
+        .is_some_and(|&at| at + RATE_LIMIT_WINDOW <= time.elapsed())
+    {
+        sent.pop_front();
+    }
+    if sent.len() >= RATE_LIMIT_MESSAGES {
+        return reject(
+            &mut commands,
+            from,
+            RejectedCommand::ChatMessage,
+            Rejection::TooManyMessages,
+        );
+    }
+    sent.push_back(time.elapsed());
+
+    let channel = trigger.message.channel;
+    let target = match channel {
+        ChatChannel::Team => players.team_target(player.team),
+        ChatChannel::All => NetworkTarget::All,
+    };
+    let message = ChatMessage {
+        channel,
+        sender: player.id,
+        name: player.name.clone(),
+        team: player.team,
+        text,
+    };
+    debug!("Chat from {}: {:?}", message.name, message.text);
+
+    commands.queue(move |world: &mut World| {
+        world.server_trigger::<MessageChannel>(message, target);
+    });
+}
+
+/// The chat box at the bottom left of the screen
+#[derive(Resource, Default)]
+pub struct ChatBox {
+    /// Whether the player is typing a message
+    open: bool,
+    /// Set on the frame the box opens, so the Enter that opened it doesn't also send
+    just_opened: bool,
+    draft: String,
+    channel: ChatChannel,
+}
+
+/// Whether the player is typing in chat, so keys should not control the game
+pub fn typing_in_chat(chat: Option<Res<ChatBox>>) -> bool {
+    chat.is_some_and(|chat| chat.open)
+}
+
+/// Messages received this match, oldest first
+#[derive(Resource, Default)]
+struct ChatLog(VecDeque<(Duration, ChatMessage)>);
+
+fn on_chat_message(
+    trigger: Trigger<FromServer<ChatMessage>>,
+    mut log: ResMut<ChatLog>,
+    time: Res<Time>,
+) {
+    log.0.push_back((time.elapsed(), trigger.message.clone()));
+    if log.0.len() > SHOWN_MESSAGES {
+        log.0.pop_front();
+    }
+}
+
+fn clear_chat(mut chat: ResMut<ChatBox>, mut log: ResMut<ChatLog>) {
+    *chat = ChatBox::default();
+    log.0.clear();
+}
+
+fn open_chat(input: Res<ButtonInput<KeyCode>>, mut chat: ResMut<ChatBox>) {
+    if input.just_pressed(KeyCode::Enter) {
+        chat.open = true;
+        chat.just_opened = true;
+    }
+}
+
+/// Enter sends, Escape closes without sending and Tab switches between team and all chat
+fn chat_ui(
+    mut contexts: EguiContexts,
+    mut chat: ResMut<ChatBox>,
+    log: Res<ChatLog>,
+    my_team: Option<Res<MyTeam>>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    // Bots have no window to draw on
+    let Some(ctx) = contexts.try_ctx_mut() else {
+        return;
+    };
+    let chat = &mut *chat;
+
+    egui::Area::new(egui::Id::new("chat"))
+        .anchor(egui::Align2::LEFT_BOTTOM, egui::vec2(10.0, -150.0))
+        .interactable(chat.open)
+        .show(ctx, |ui| {
+            ui.set_max_width(400.0);
+
+            for (received, message) in &log.0 {
+                let shown_for = time.elapsed() - *received;
+                let alpha = if chat.open || shown_for < MESSAGE_SHOW_TIME {
+                    1.0
+                } else {
+                    1.0 - (shown_for - MESSAGE_SHOW_TIME).as_secs_f32()
+                        / MESSAGE_FADE_TIME.as_secs_f32()
+                };
+                if alpha <= 0.0 {
+                    continue;
+                }
+
+                let color = match &my_team {
+                    Some(my_team) if my_team.0 != message.team => egui::Color32::LIGHT_RED,
+                    _ if message.channel == ChatChannel::All => egui::Color32::WHITE,
+                    _ => egui::Color32::LIGHT_BLUE,
+                };
+                let prefix = match message.channel {
+                    ChatChannel::Team => "",
+                    ChatChannel::All => "[All] ",
+                };
+                ui.label(
+                    egui::RichText::new(format!("{prefix}{}: {}", message.name, message.text))
+                        .color(color.gamma_multiply(alpha)),
+                );
+            }
+
+            if !chat.open {
+                return;
+            }
+
+            if chat.just_opened {
+                chat.just_opened = false;
+                ui.input_mut(|input| input.consume_key(egui::Modifiers::NONE, egui::Key::Enter));
+            }
+            if ui.input_mut(|input| input.consume_key(egui::Modifiers::NONE, egui::Key::Tab)) {
+                chat.channel = match chat.channel {
+                    ChatChannel::Team => ChatChannel::All,
+                    ChatChannel::All => ChatChannel::Team,
+                };
+            }
+
+            let send = ui
+                .horizontal(|ui| {
+                    ui.label(match chat.channel {
+                        ChatChannel::Team => "[Team]",
+                        ChatChannel::All => "[All]",
+                    });
+                    let response = ui.add(
+                        egui::TextEdit::singleline(&mut chat.draft)
+                            .char_limit(MAX_MESSAGE_CHARS)
+                            .desired_width(f32::INFINITY),
+                    );
+                    response.request_focus();
+                    ui.input(|input| input.key_pressed(egui::Key::Enter))
+                })
+                .inner;
+            let cancel = ui.input(|input| input.key_pressed(egui::Key::Escape));
+
+            if send || cancel {
+                let text = std::mem::take(&mut chat.draft);
+                if send && !text.trim().is_empty() {
+                    commands.client_trigger::<MessageChannel>(SendChatMessage {
+                        channel: chat.channel,
+                        text,
+                    });
+                }
+                chat.open = false;
+            }
+        });
+}
@@ -14,7 +14,9 @@ use crate::{
     ingame::{
         lua::{AppLuaExt, AssetPathExt, LuaCtx, LuaExt, LuaScript, ScriptCompleted, W},
         map::{LoadMap, MapDefAsset, MessageChannel},
+        replay::AppReplayExt,
         unit::champion::ChampionDefAsset,
+        validation::{CommandValidator, RejectedCommand, reject},
     },
     new_ui::{
         View, ViewExt, container::ContainerView, image::ImageView, list::ListView, tree::UiTree,
@@ -25,6 +27,8 @@ pub fn plugin(app: &mut App) {
     app.register_resource::<WhatToLoad>(ChannelDirection::ServerToClient);
     app.register_resource::<ClientLoadStates>(ChannelDirection::ServerToClient);
     app.register_trigger::<UpdateClientLoadState>(ChannelDirection::ClientToServer);
+    app.record_resource::<WhatToLoad>()
+        .record_resource::<ClientLoadStates>();
 
     app.add_sub_state::<LoadingState>();
 
@@ -57,8 +61,7 @@ pub fn plugin(app: &mut App) {
             },
         );
         app.insert_resource(WhatToLoad {
-            // Hard code default map currently
-            map: MapId("default".into()),
+            map: app.world().resource::<MatchMap>().0.clone(),
             champs: app
                 .world()
                 .resource::<Players>()
@@ -119,6 +122,10 @@ pub enum LoadingState {
     LoadCompleted,
 }
 
+/// The map the server hosts the match on
+#[derive(Debug, Resource, Clone)]
+pub struct MatchMap(pub MapId);
+
 #[derive(Debug, Resource, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct WhatToLoad {
     map: MapId,
@@ -573,8 +580,19 @@ pub struct UpdateClientLoadState {
 
 fn on_update_client_load_state(
     trigger: Trigger<FromClients<UpdateClientLoadState>>,
+    validator: CommandValidator,
     mut states: ResMut<ClientLoadStates>,
+    mut commands: Commands,
 ) {
+    if let Err(rejection) = validator.player(trigger.from()) {
+        return reject(
+            &mut commands,
+            trigger.from(),
+            RejectedCommand::LoadState,
+            rejection,
+        );
+    }
+
     states
         .load_states
         .insert(trigger.from(), trigger.message().state);
@@ -0,0 +1,328 @@
+//! The control channel between a game server and the lobby server that started it.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use bevy::{
+    asset::uuid::Uuid,
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+    time::common_conditions::on_timer,
+};
+use lightyear::{
+    connection::netcode::PRIVATE_KEY_BYTES,
+    prelude::{
+        ClientId, ConnectToken, ServerConnectEvent, ServerConnectionManager,
+        server::ServerCommandsExt as _,
+    },
+};
+use lobby_common::{ConnectCandidate, LobbyToServer, MatchPhase, PlayerId, ServerToLobby};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+
+use crate::{
+    GameState, InGamePlayerInfo, Players, Sess,
+    ingame::{
+        network::{PROTOCOL_ID, PrivateKey, ServerCertificate, ServerOptions},
+        outcome::GameOutcome,
+    },
+};
+
+/// How often the lobby server is told how the match is going
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn server(app: &mut App) {
+    app.init_resource::<KickedPlayers>()
+        .add_systems(OnEnter(GameState::InGame), start_match_clock)
+        .add_systems(Update, disconnect_kicked_players)
+        .add_systems(
+            Update,
+            (
+                handle_lobby_messages,
+                send_status.run_if(on_timer(STATUS_INTERVAL)),
+            )
+                .run_if(resource_exists::<LobbyControl>),
+        );
+}
+
+/// Messages to and from the lobby server, only present when started by one
+#[derive(Resource)]
+pub struct LobbyControl {
+    receiver: UnboundedReceiver<LobbyToServer>,
+    sender: UnboundedSender<ServerToLobby>,
+}
+
+impl LobbyControl {
+    /// Creates the resource, and the future that passes messages between it and `conn`.
+    /// The future finishes once either side goes away.
+    pub fn new(conn: Sess<xwt_wtransport::Connection>) -> (Self, impl Future<Output = ()>) {
+        let (to_game, receiver) = unbounded_channel();
+        let (sender, mut from_game) = unbounded_channel();
+
+        let relay = async move {
+            let forward = async {
+                while let Some(message) = from_game.recv().await {
+                    if let Err(err) = conn.send(message).await {
+                        warn!("Failed sending to lobby server: {err}");
+                        break;
+                    }
+                }
+            };
+            let receive = async {
+                loop {
+                    match conn.recv::<LobbyToServer>().await {
+                        Ok(message) => {
+                            if to_game.send(message).is_err() {
+                                break;
+                            }
+                        }
+                        Err(err) => {
+                            warn!("Lost connection to lobby server: {err}");
+                            break;
+                        }
+                    }
+                }
+            };
+            tokio::select! {
+                _ = forward => {}
+                _ = receive => {}
+            }
+        };
+
+        (Self { receiver, sender }, relay)
+    }
+}
+
+/// Players the lobby server had kicked, who don't get new tokens
+/// and are disconnected again if they come back with an old one
+#[derive(Resource, Default)]
+struct KickedPlayers(HashSet<PlayerId>);
+
+impl KickedPlayers {
+    /// The player behind `client`, if they were kicked
+    fn kicked_player<'a>(
+        &self,
+        players: &'a Players,
+        client: ClientId,
+    ) -> Option<&'a InGamePlayerInfo> {
+        players
+            .players
+            .values()
+            .find(|player| player.client_id == client && self.0.contains(&player.id))
+    }
+}
+
+/// When the match left the loading phase, as elapsed time
+#[derive(Resource)]
+struct MatchStarted(Duration);
+
+fn start_match_clock(time: Res<Time>, mut commands: Commands) {
+    commands.insert_resource(MatchStarted(time.elapsed()));
+}
+
+/// Every address we can be reached on, starting with the one most likely to work
+/// judging by how the player reached the lobby server.
+/// Each address is offered over UDP, and over WebTransport for browsers if we listen on it.
+pub fn connect_candidates(
+    options: &ServerOptions,
+    private_key: [u8; PRIVATE_KEY_BYTES],
+    certificate: &ServerCertificate,
+    player: PlayerId,
+    is_ipv4: bool,
+    is_local: bool,
+) -> Vec<ConnectCandidate> {
+    let local_ipv4 = IpAddr::from(options.local_address_ipv4);
+    let public_ipv4 = IpAddr::from(options.public_address_ipv4);
+    let ipv6 = IpAddr::from(options.address_ipv6);
+    let mut addresses = match (is_ipv4, is_local) {
+        (true, true) => vec![local_ipv4, public_ipv4, ipv6],
+        (true, false) => vec![public_ipv4, ipv6, local_ipv4],
+        (false, _) => vec![ipv6, public_ipv4, local_ipv4],
+    };
+    let mut seen = std::collections::HashSet::new();
+    addresses.retain(|ip| seen.insert(*ip));
+
+    let candidate = |address, certificate_digest| {
+        let token = ConnectToken::build(address, PROTOCOL_ID, client_id(player), private_key)
+            .generate()
+            .unwrap();
+        ConnectCandidate {
+            address,
+            token: token.try_into_bytes().unwrap().to_vec(),
+            certificate_digest,
+        }
+    };
+
+    addresses
+        .into_iter()
+        .flat_map(|ip_addr| {
+            let udp = candidate(SocketAddr::new(ip_addr, options.external_port), None);
+            let webtransport = options.webtransport_port.map(|port| {
+                candidate(
+                    SocketAddr::new(ip_addr, port),
+                    Some(certificate.digest.clone()),
+                )
+            });
+            [Some(udp), webtransport].into_iter().flatten()
+        })
+        .collect()
+}
+
+/// The netcode client id a player connects with
+pub fn client_id(player: PlayerId) -> u64 {
+    player.0.as_u64_pair().0
+}
+
+/// The player connecting with `client_id` to a server started with `--direct-connect`,
+/// the reverse of [`client_id`]
+pub fn direct_player_id(client_id: u64) -> PlayerId {
+    PlayerId(Uuid::from_u64_pair(client_id, 0))
+}
+
+fn handle_lobby_messages(
+    mut control: ResMut<LobbyControl>,
+    options: Res<ServerOptions>,
+    key: Res<PrivateKey>,
+    certificate: Res<ServerCertificate>,
+    players: Res<Players>,
+    mut kicked: ResMut<KickedPlayers>,
+    mut exit: EventWriter<AppExit>,
+    mut commands: Commands,
+) {
+    while let Ok(message) = control.receiver.try_recv() {
+        match message {
+            LobbyToServer::Handshake { .. } => warn!("Lobby server sent a second handshake"),
+            LobbyToServer::EndMatch => {
+                info!("Lobby server ended the match");
+                exit.write(AppExit::Success);
+            }
+            LobbyToServer::KickPlayer(player) => {
+                let Some(info) = players.players.get(&player) else {
+                    warn!("Lobby server kicked unknown player {}", player.0);
+                    continue;
+                };
+                info!("Kicking {}", info.name);
+                kicked.0.insert(player);
+                commands.disconnect(info.client_id);
+            }
+            LobbyToServer::IssueToken {
+                player,
+                is_ipv4,
+                is_local,
+            } => {
+                if !players.players.contains_key(&player) || kicked.0.contains(&player) {
+                    warn!("Refusing token for player {}", player.0);
+                    continue;
+                }
+                let candidates =
+                    connect_candidates(&options, key.0, &certificate, player, is_ipv4, is_local);
+                _ = control.sender.send(ServerToLobby::PlayerTokens {
+                    tokens: std::collections::HashMap::from([(player, candidates)]),
+                });
+            }
+        }
+    }
+}
+
+/// Connect tokens stay valid for a while, so a kicked player could reconnect with the one they
+/// were given before being kicked
+fn disconnect_kicked_players(
+    mut events: EventReader<ServerConnectEvent>,
+    players: Res<Players>,
+    kicked: Res<KickedPlayers>,
+    mut commands: Commands,
+) {
+    for event in events.read() {
+        if let Some(player) = kicked.kicked_player(&players, event.client_id) {
+            info!("Disconnecting {}, who was kicked", player.name);
+            commands.disconnect(event.client_id);
+        }
+    }
+}
+
+fn send_status(
+    control: Res<LobbyControl>,
+    state: Res<State<GameState>>,
+    outcome: Res<GameOutcome>,
+    time: Res<Time>,
+    started: Option<Res<MatchStarted>>,
+    players: Res<Players>,
+    server: Option<Res<ServerConnectionManager>>,
+) {
+    let phase = match state.get() {
+        _ if outcome.is_over() => MatchPhase::Ended,
+        GameState::InGame => MatchPhase::InProgress,
+        GameState::NotInGame | GameState::Loading => MatchPhase::Loading,
+    };
+    let game_time = started.map_or(0, |started| (time.elapsed() - started.0).as_secs());
+
+    let client_players = players
+        .players
+        .values()
+        .map(|info| (info.client_id, info.id))
+        .collect::<HashMap<ClientId, PlayerId>>();
+    let connected_players = server
+        .iter()
+        .flat_map(|server| server.connected_clients())
+        .filter_map(|client| client_players.get(&client).copied())
+        .collect();
+
+    _ = control.sender.send(ServerToLobby::Status {
+        phase,
+        game_time,
+        connected_players,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use engine_common::ChampionId;
+    use lobby_common::Team;
+
+    use super::*;
+
+    fn players(ids: &[PlayerId]) -> Players {
+        Players {
+            players: ids
+                .iter()
+                .map(|&id| {
+                    let info = InGamePlayerInfo {
+                        id,
+                        client_id: ClientId::Netcode(client_id(id)),
+                        name: format!("{}", id.0),
+                        team: Team(0),
+                        champion: ChampionId("champ".to_string()),
+                        controlled_unit: None,
+                    };
+                    (id, info)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn kicked_player_reconnecting_is_found() {
+        let (kicked_id, other_id) = (PlayerId::new(), PlayerId::new());
+        let players = players(&[kicked_id, other_id]);
+        let kicked = KickedPlayers(HashSet::from([kicked_id]));
+
+        let reconnected = kicked.kicked_player(&players, ClientId::Netcode(client_id(kicked_id)));
+        assert_eq!(reconnected.map(|player| player.id), Some(kicked_id));
+        let other = kicked.kicked_player(&players, ClientId::Netcode(client_id(other_id)));
+        assert!(other.is_none());
+    }
+
+    #[test]
+    fn unknown_client_is_not_kicked() {
+        let kicked_id = PlayerId::new();
+        let players = players(&[kicked_id]);
+        let kicked = KickedPlayers(HashSet::from([kicked_id]));
+
+        assert!(
+            kicked
+                .kicked_player(&players, ClientId::Netcode(1))
+                .is_none()
+        );
+    }
+}
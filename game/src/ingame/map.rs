@@ -17,6 +17,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     ingame::{
+        replay::AppReplayExt,
         structure::Model,
         targetable::Health,
         unit::{ControlledByClient, SpawnUnit, SpawnUnitArgs, Unit},
@@ -36,6 +37,7 @@ pub fn common(app: &mut App) {
         .init_resource::<Protos<MapProto>>();
 
     app.register_component::<MapEntity>(lightyear::prelude::ChannelDirection::ServerToClient);
+    app.record_component::<MapEntity>();
 
     app.add_channel::<MessageChannel>(lightyear::prelude::ChannelSettings {
         mode: lightyear::prelude::ChannelMode::OrderedReliable(ReliableSettings::default()),
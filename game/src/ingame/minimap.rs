@@ -0,0 +1,212 @@
+//! The minimap in the bottom right corner.
+//!
+//! Terrain is drawn into an image whenever it changes, and every unit and structure gets a dot
+//! that follows it around, hidden whenever the unit itself is.
+
+use bevy::{
+    asset::RenderAssetUsages,
+    color::palettes,
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use lobby_common::Team;
+
+use crate::{
+    GameState,
+    ingame::{
+        navmesh::{TerrainData, map_bounds},
+        structure::Structure,
+        targetable::Position,
+        unit::{MyTeam, Unit, UnitType},
+        vision,
+    },
+};
+
+/// Width and height of the minimap, in pixels
+const MINIMAP_SIZE: f32 = 180.0;
+
+/// Width and height of the terrain image, in pixels
+const TERRAIN_RESOLUTION: u32 = 128;
+
+const TERRAIN_COLOR: [u8; 4] = [90, 90, 100, 255];
+
+pub fn client(app: &mut App) {
+    app.add_systems(OnEnter(GameState::InGame), spawn_minimap)
+        .add_systems(
+            Update,
+            (
+                draw_minimap_terrain,
+                add_minimap_icons,
+                move_minimap_icons.after(vision::change_visibility),
+            )
+                .chain()
+                .run_if(in_state(GameState::InGame)),
+        );
+}
+
+#[derive(Component)]
+pub struct Minimap;
+
+#[derive(Component)]
+struct MinimapTerrain;
+
+#[derive(Component)]
+#[relationship_target(relationship = MinimapIconOf, linked_spawn)]
+struct MinimapIconRef(Entity);
+
+#[derive(Component)]
+#[relationship(relationship_target = MinimapIconRef)]
+struct MinimapIconOf(Entity);
+
+/// Where `position` is on the minimap, as a fraction of its size from the top left corner
+pub fn minimap_fraction(position: Position) -> Vec2 {
+    // Positions go up the map, while the minimap goes down the screen
+    let bounds = map_bounds();
+    let fraction = (position.0 - bounds.min) / bounds.size();
+    vec2(fraction.x, 1.0 - fraction.y)
+}
+
+fn spawn_minimap(mut commands: Commands) {
+    commands.spawn((
+        StateScoped(GameState::InGame),
+        Minimap,
+        Node {
+            position_type: PositionType::Absolute,
+            right: Val::Px(10.0),
+            bottom: Val::Px(10.0),
+            width: Val::Px(MINIMAP_SIZE),
+            height: Val::Px(MINIMAP_SIZE),
+            border: UiRect::all(Val::Px(1.0)),
+            ..default()
+        },
+        BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+        BorderColor(Color::srgba(1.0, 1.0, 1.0, 0.3)),
+        Pickable::IGNORE,
+        children![(
+            MinimapTerrain,
+            Node {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                ..default()
+            },
+            ImageNode::default(),
+            Pickable::IGNORE,
+        )],
+    ));
+}
+
+/// Redraws the terrain image from scratch whenever a terrain object is added, changed or removed
+fn draw_minimap_terrain(
+    terrain: Query<&TerrainData>,
+    changed: Query<(), Changed<TerrainData>>,
+    mut removed: RemovedComponents<TerrainData>,
+    mut image: Single<&mut ImageNode, With<MinimapTerrain>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    if changed.is_empty() && removed.read().count() == 0 {
+        return;
+    }
+
+    let bounds = map_bounds();
+    let mut data = Vec::with_capacity((TERRAIN_RESOLUTION * TERRAIN_RESOLUTION * 4) as usize);
+    for row in 0..TERRAIN_RESOLUTION {
+        for column in 0..TERRAIN_RESOLUTION {
+            let fraction = (vec2(column as f32, row as f32) + 0.5) / TERRAIN_RESOLUTION as f32;
+            let position = bounds.min + vec2(fraction.x, 1.0 - fraction.y) * bounds.size();
+            // Terrain vertices are on the xz plane, where z goes the other way
+            let vertex = vec2(position.x, -position.y);
+
+            let solid = terrain
+                .iter()
+                .any(|object| polygon_contains(&object.vertices, vertex));
+            data.extend(if solid { TERRAIN_COLOR } else { [0; 4] });
+        }
+    }
+
+    image.image = images.add(Image::new(
+        Extent3d {
+            width: TERRAIN_RESOLUTION,
+            height: TERRAIN_RESOLUTION,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    ));
+}
+
+/// Even-odd rule, so it works for any simple polygon no matter which way it winds
+fn polygon_contains(polygon: &[Vec2], point: Vec2) -> bool {
+    let mut inside = false;
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+fn add_minimap_icons(
+    units: Query<
+        (Entity, &Team, Option<&UnitType>),
+        (Or<(With<Unit>, With<Structure>)>, Without<MinimapIconRef>),
+    >,
+    minimap: Single<Entity, With<Minimap>>,
+    my_team: Res<MyTeam>,
+    mut commands: Commands,
+) {
+    for (unit, team, unit_type) in &units {
+        let color = if *team == my_team.0 {
+            palettes::tailwind::GREEN_500
+        } else {
+            palettes::tailwind::RED_500
+        };
+        // Structures are the only ones left square
+        let (size, radius) = match unit_type {
+            Some(UnitType::Champion) => (8.0, BorderRadius::MAX),
+            Some(UnitType::Normal) => (4.0, BorderRadius::MAX),
+            None => (7.0, BorderRadius::ZERO),
+        };
+
+        commands.entity(*minimap).with_child((
+            MinimapIconOf(unit),
+            Node {
+                position_type: PositionType::Absolute,
+                width: Val::Px(size),
+                height: Val::Px(size),
+                // Centered on the unit
+                margin: UiRect {
+                    left: Val::Px(-size / 2.0),
+                    top: Val::Px(-size / 2.0),
+                    ..default()
+                },
+                ..default()
+            },
+            BackgroundColor(color.into()),
+            radius,
+            Visibility::Hidden,
+            Pickable::IGNORE,
+        ));
+    }
+}
+
+/// Icons follow where units are drawn, rather than where the server last had them
+fn move_minimap_icons(
+    units: Query<(&Transform, &Visibility, &MinimapIconRef)>,
+    mut icons: Query<(&mut Node, &mut Visibility), Without<MinimapIconRef>>,
+) {
+    for (trans, vis, icon) in &units {
+        let Ok((mut node, mut icon_vis)) = icons.get_mut(icon.0) else {
+            continue;
+        };
+        let on_minimap = minimap_fraction(Position::from(trans.translation));
+        node.left = Val::Percent(on_minimap.x * 100.0);
+        node.top = Val::Percent(on_minimap.y * 100.0);
+        icon_vis.set_if_neq(*vis);
+    }
+}
@@ -1,42 +1,52 @@
+use std::collections::VecDeque;
+
 use bevy::{ecs::entity::MapEntities, platform::collections::HashMap, prelude::*};
 use engine_common::ChampionId;
 use lightyear::{
     client::config::ClientConfig,
     prelude::{
-        AppResourceExt, ClientDisconnectEvent, ClientId, ConnectToken, ReplicateResourceExt,
+        AppResourceExt, ClientConnectEvent, ClientDisconnectEvent, ClientId, ConnectToken,
+        NetworkTarget, ReplicateResourceExt,
         client::{self, Authentication, ClientCommandsExt},
     },
 };
-use lobby_common::{PlayerId, Team};
+use lobby_common::{ConnectCandidate, PlayerId, Team};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     AppExt, GameState,
-    ingame::{map::MessageChannel, unit::MyTeam},
+    ingame::{map::MessageChannel, replay::AppReplayExt, unit::MyTeam},
     main_ui::lobby_list::MyPlayerId,
 };
 
 #[macro_use]
 pub mod lua;
 pub mod camera;
+pub mod chat;
 pub mod loading;
+pub mod lobby_control;
 pub mod map;
+pub mod minimap;
 pub mod navmesh;
 pub mod network;
+pub mod outcome;
+pub mod ping;
 pub mod projectile;
+pub mod replay;
 pub mod structure;
 pub mod targetable;
 pub mod terrain;
 pub mod unit;
+pub mod validation;
 pub mod vision;
 
 pub fn client(app: &mut App) {
-    app.add_plugins((network::client, camera::client, terrain::client))
-        .add_systems(Update, on_disconnect);
+    app.add_plugins((network::client, camera::client, terrain::client, minimap::client))
+        .add_systems(Update, (on_connect, on_disconnect));
     common(app);
 }
 pub fn server(app: &mut App) {
-    app.add_plugins(network::server)
+    app.add_plugins((network::server, lobby_control::server))
         .add_systems(Startup, network::init_server);
     common(app);
 }
@@ -53,9 +63,15 @@ pub fn common(app: &mut App) {
         loading::plugin,
         vision::plugin,
         projectile::plugin,
+        outcome::plugin,
+        replay::plugin,
+        validation::plugin,
     ));
+    // Plugin tuples only go up to 15
+    app.add_plugins((chat::plugin, ping::plugin));
 
     app.register_resource::<Players>(lightyear::prelude::ChannelDirection::ServerToClient);
+    app.record_mapped_resource::<Players>();
     app.add_systems(Startup, |mut commands: Commands| {
         commands
             .replicate_resource::<Players, MessageChannel>(lightyear::prelude::NetworkTarget::All);
@@ -70,11 +86,12 @@ pub fn common(app: &mut App) {
         // TODO: Temporary until game menu is implemented
         app.add_systems(
             Update,
-            |input: Res<ButtonInput<KeyCode>>, mut commands: Commands| {
+            (|input: Res<ButtonInput<KeyCode>>, mut commands: Commands| {
                 if input.just_pressed(KeyCode::Escape) {
                     commands.disconnect_client();
                 }
-            },
+            })
+            .run_if(not(chat::typing_in_chat)),
         );
     }
 }
@@ -90,26 +107,84 @@ fn players_added(players: Res<Players>, my_id: Res<MyPlayerId>, mut commands: Co
     }
 }
 
-pub struct ConnectToGameServer(pub ConnectToken);
+/// Connects to the game server through the first candidate that works
+pub struct ConnectToGameServer(pub Vec<ConnectCandidate>);
 
 impl Command for ConnectToGameServer {
     fn apply(self, world: &mut World) {
-        let client::NetConfig::Netcode { auth, .. } = &mut world.resource_mut::<ClientConfig>().net
-        else {
-            unreachable!()
-        };
+        world.insert_resource(RemainingCandidates(self.0.into()));
+        world.commands().set_state(GameState::Loading);
+        TryNextCandidate.apply(world);
+    }
+}
 
-        *auth = Authentication::Token(self.0);
+/// Candidates not yet tried for the current connection attempt.
+/// Removed once we are connected.
+#[derive(Resource)]
+struct RemainingCandidates(VecDeque<ConnectCandidate>);
 
-        world.connect_client();
-        world.commands().set_state(GameState::Loading);
+struct TryNextCandidate;
+
+impl Command for TryNextCandidate {
+    fn apply(self, world: &mut World) {
+        loop {
+            let Some(candidate) = world
+                .get_resource_mut::<RemainingCandidates>()
+                .and_then(|mut remaining| remaining.0.pop_front())
+            else {
+                warn!("Could not connect to the game server on any address");
+                world.remove_resource::<RemainingCandidates>();
+                world.commands().set_state(GameState::NotInGame);
+                return;
+            };
+
+            let Some(transport) = network::candidate_transport(&candidate) else {
+                continue;
+            };
+
+            let token = match ConnectToken::try_from_bytes(&candidate.token) {
+                Ok(token) => token,
+                Err(err) => {
+                    warn!("Invalid connect token for {}: {err:?}", candidate.address);
+                    continue;
+                }
+            };
+
+            let client::NetConfig::Netcode { auth, io, .. } =
+                &mut world.resource_mut::<ClientConfig>().net
+            else {
+                unreachable!()
+            };
+
+            *auth = Authentication::Token(token);
+            io.transport = transport;
+
+            info!("Connecting to game server at {}", candidate.address);
+            world.connect_client();
+            return;
+        }
+    }
+}
+
+fn on_connect(mut events: EventReader<ClientConnectEvent>, mut commands: Commands) {
+    if events.read().next().is_some() {
+        commands.remove_resource::<RemainingCandidates>();
     }
 }
 
-fn on_disconnect(mut events: EventReader<ClientDisconnectEvent>, mut commands: Commands) {
+fn on_disconnect(
+    mut events: EventReader<ClientDisconnectEvent>,
+    remaining: Option<Res<RemainingCandidates>>,
+    mut commands: Commands,
+) {
     for event in events.read() {
         info!("Disconnect: {event:?}");
-        commands.set_state(GameState::NotInGame);
+        if remaining.is_some() {
+            // We never got connected, so try the next address
+            commands.queue(TryNextCandidate);
+        } else {
+            commands.set_state(GameState::NotInGame);
+        }
     }
 }
 
@@ -118,6 +193,19 @@ pub struct Players {
     pub players: HashMap<PlayerId, InGamePlayerInfo>,
 }
 
+impl Players {
+    /// Every client playing for `team`
+    pub fn team_target(&self, team: Team) -> NetworkTarget {
+        NetworkTarget::Only(
+            self.players
+                .values()
+                .filter(|player| player.team == team)
+                .map(|player| player.client_id)
+                .collect(),
+        )
+    }
+}
+
 impl MapEntities for Players {
     fn map_entities<E: EntityMapper>(&mut self, entity_mapper: &mut E) {
         for info in self.players.values_mut() {
@@ -11,10 +11,18 @@ use vleue_navigator::{
     },
 };
 
-use crate::AppExt;
+use crate::{AppExt, ingame::replay::AppReplayExt};
 
 use super::{targetable::Position, terrain::Terrain};
 
+/// Width and depth of the map, which is centered on the origin
+pub const MAP_SIZE: f32 = 100.0;
+
+/// Every position on the map
+pub fn map_bounds() -> Rect {
+    Rect::from_center_size(Vec2::ZERO, Vec2::splat(MAP_SIZE))
+}
+
 pub fn common(app: &mut App) {
     app.add_plugins((
         VleueNavigatorPlugin,
@@ -22,6 +30,7 @@ pub fn common(app: &mut App) {
     ));
 
     app.register_component::<TerrainData>(ChannelDirection::ServerToClient);
+    app.record_component::<TerrainData>();
 
     // Temporary setup
     app.add_systems(Startup, setup);
@@ -50,9 +59,9 @@ fn setup(mut commands: Commands) {
         NavMeshSettings {
             fixed: Triangulation::from_outer_edges(&[
                 vec2(0.0, 0.0),
-                vec2(100.0, 0.0),
-                vec2(100.0, 100.0),
-                vec2(0.0, 100.0),
+                vec2(MAP_SIZE, 0.0),
+                vec2(MAP_SIZE, MAP_SIZE),
+                vec2(0.0, MAP_SIZE),
             ]),
             agent_radius: 0.5,
             merge_steps: 3,
@@ -62,7 +71,7 @@ fn setup(mut commands: Commands) {
             ..default()
         },
         NavMeshUpdateMode::Direct,
-        Transform::from_translation(vec3(-50.0, 0.05, 50.0))
+        Transform::from_translation(vec3(-MAP_SIZE / 2.0, 0.05, MAP_SIZE / 2.0))
             .with_rotation(Quat::from_rotation_x(-FRAC_PI_2)),
     ));
 }
@@ -1,5 +1,6 @@
 use std::{
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    path::PathBuf,
     time::Duration,
 };
 
@@ -10,9 +11,9 @@ use lightyear::{
     prelude::{client::ComponentSyncMode, server::ServerCommandsExt as _, *},
     server::{config::ServerConfig, plugin::ServerPlugins},
 };
-use lobby_common::Team;
+use lobby_common::{ConnectCandidate, Team};
 
-use crate::AppExt;
+use crate::{AppExt, Options, ingame::replay::AppReplayExt};
 
 fn shared_config() -> SharedConfig {
     SharedConfig {
@@ -26,15 +27,97 @@ fn shared_config() -> SharedConfig {
 
 pub const PROTOCOL_ID: u64 = 2478926748297;
 
+/// Formats bytes as hex, for keys and tokens passed around on the command line
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+pub fn parse_private_key(hex: &str) -> Result<[u8; PRIVATE_KEY_BYTES], String> {
+    from_hex(hex)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("expected {} hex encoded bytes", PRIVATE_KEY_BYTES))
+}
+
+/// Simulates a worse connection than the real one, for testing
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct LinkConditionerOptions {
+    /// Milliseconds of latency added to every incoming packet
+    #[arg(long, default_value_t = 0)]
+    pub latency_ms: u64,
+    /// Random variation in the added latency, in milliseconds
+    #[arg(long, default_value_t = 0)]
+    pub jitter_ms: u64,
+    /// Share of incoming packets dropped, from 0 to 1
+    #[arg(long, default_value_t = 0.0)]
+    pub packet_loss: f32,
+}
+
+impl LinkConditionerOptions {
+    fn config(&self) -> Option<LinkConditionerConfig> {
+        if self.latency_ms == 0 && self.jitter_ms == 0 && self.packet_loss == 0.0 {
+            return None;
+        }
+
+        warn!(
+            "Simulating {}±{}ms latency and {}% packet loss",
+            self.latency_ms,
+            self.jitter_ms,
+            self.packet_loss * 100.0
+        );
+        Some(LinkConditionerConfig {
+            incoming_latency: Duration::from_millis(self.latency_ms),
+            incoming_jitter: Duration::from_millis(self.jitter_ms),
+            incoming_loss: self.packet_loss,
+        })
+    }
+}
+
+/// How to reach `candidate`, or `None` if this build can't use it.
+/// Browsers can only reach the game server over WebTransport, everything else uses plain UDP.
+pub fn candidate_transport(candidate: &ConnectCandidate) -> Option<client::ClientTransport> {
+    let client_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+    #[cfg(target_family = "wasm")]
+    let transport = match &candidate.certificate_digest {
+        Some(digest) => Some(client::ClientTransport::WebTransportClient {
+            client_addr,
+            server_addr: candidate.address,
+            certificate_digest: digest.clone(),
+        }),
+        None => None,
+    };
+
+    #[cfg(not(target_family = "wasm"))]
+    let transport = match candidate.certificate_digest {
+        Some(_) => None,
+        None => Some(client::ClientTransport::UdpSocket(client_addr)),
+    };
+
+    transport
+}
+
 pub fn client(app: &mut App) {
+    let mut io = client::IoConfig::from_transport(client::ClientTransport::UdpSocket(
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
+    ));
+    io.conditioner = app.world().resource::<Options>().link_conditioner.config();
+
     let config = ClientConfig {
         shared: shared_config(),
         net: client::NetConfig::Netcode {
             auth: client::Authentication::None,
             config: default(),
-            io: client::IoConfig::from_transport(client::ClientTransport::UdpSocket(
-                SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)),
-            )),
+            io,
         },
         ..default()
     };
@@ -43,14 +126,31 @@ pub fn client(app: &mut App) {
 }
 
 pub fn server(app: &mut App) {
+    let options = app.world().resource::<ServerOptions>();
+    let conditioner = options.link_conditioner.config();
+    let netcode = |transport| {
+        let mut io = server::IoConfig::from_transport(transport);
+        io.conditioner = conditioner.clone();
+        server::NetConfig::Netcode {
+            config: server::NetcodeConfig::default().with_protocol_id(PROTOCOL_ID),
+            io,
+        }
+    };
+
+    let mut net = vec![netcode(server::ServerTransport::UdpSocket(SocketAddr::V6(
+        SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 54655, 0, 0),
+    )))];
+    if let Some(port) = options.webtransport_port {
+        let certificate = app.world().resource::<ServerCertificate>();
+        net.push(netcode(server::ServerTransport::WebTransportServer {
+            server_addr: SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0)),
+            certificate: certificate.identity.clone_identity(),
+        }));
+    }
+
     let config = ServerConfig {
         shared: shared_config(),
-        net: vec![server::NetConfig::Netcode {
-            config: server::NetcodeConfig::default().with_protocol_id(PROTOCOL_ID),
-            io: server::IoConfig::from_transport(server::ServerTransport::UdpSocket(
-                SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 54655, 0, 0)),
-            )),
-        }],
+        net,
         ..default()
     };
 
@@ -74,6 +174,7 @@ pub fn common(app: &mut App) {
     //             .with_scale(scale)
     //     });
     app.register_component::<Team>(ChannelDirection::ServerToClient);
+    app.record_component::<Team>();
 }
 
 #[derive(Resource, clap::Parser)]
@@ -83,24 +184,60 @@ pub struct ServerOptions {
     pub address_ipv6: Ipv6Addr,
     pub internal_port: u16,
     pub external_port: u16,
+    /// Also lets browsers connect, over WebTransport on this port
+    #[arg(long)]
+    pub webtransport_port: Option<u16>,
+    /// Hosts a match without a lobby server
     #[arg(long)]
     pub direct_connect: bool,
+    /// The players, their teams and champions, and the map for `--direct-connect`, as RON
+    #[arg(long, requires = "direct_connect")]
+    pub match_file: Option<PathBuf>,
+    /// Hex encoded key for `--direct-connect`, all zeroes by default
+    #[arg(long, requires = "direct_connect", value_parser = parse_private_key)]
+    pub private_key: Option<[u8; PRIVATE_KEY_BYTES]>,
+    /// Records the match to a replay file at this path
+    #[arg(long)]
+    pub record_replay: Option<PathBuf>,
+    #[command(flatten)]
+    pub link_conditioner: LinkConditionerOptions,
 }
 
 #[derive(Resource)]
 pub struct PrivateKey(pub [u8; PRIVATE_KEY_BYTES]);
 
+/// The certificate the WebTransport transport presents.
+/// Browsers accept it by its hash instead of checking who signed it, so a new one is made for
+/// every match.
+#[derive(Resource)]
+pub struct ServerCertificate {
+    pub identity: server::Identity,
+    /// Hex SHA-256 hash of the certificate, handed to browsers in their connect candidates
+    pub digest: String,
+}
+
+impl ServerCertificate {
+    pub fn self_signed() -> Self {
+        // Browsers only pin certificates that are valid for at most two weeks,
+        // which self-signed ones are
+        let identity = server::Identity::self_signed(["localhost", "127.0.0.1", "::1"]).unwrap();
+        let digest = to_hex(identity.certificate_chain().as_slice()[0].hash().as_ref());
+        Self { identity, digest }
+    }
+}
+
 pub fn init_server(
     options: Res<ServerOptions>,
     key: Res<PrivateKey>,
     mut config: ResMut<ServerConfig>,
     mut commands: Commands,
 ) {
-    let server::NetConfig::Netcode { config, io } = &mut config.net[0];
-    config.private_key = key.0;
-    let server::ServerTransport::UdpSocket(port) = &mut io.transport else {
-        unreachable!()
-    };
-    port.set_port(options.external_port);
+    for net in &mut config.net {
+        let server::NetConfig::Netcode { config, io } = net;
+        config.private_key = key.0;
+        if let server::ServerTransport::UdpSocket(port) = &mut io.transport {
+            port.set_port(options.external_port);
+        }
+    }
     commands.start_server();
 }
@@ -0,0 +1,229 @@
+//! Deciding which team won, showing it to players, and ending the game once it is known.
+
+use std::time::Duration;
+
+use bevy::prelude::*;
+use lightyear::prelude::{client::ClientCommandsExt as _, *};
+use lobby_common::Team;
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppExt, GameState, Players,
+    ingame::{
+        lua::{AppLuaExt, LuaExt},
+        map::MessageChannel,
+        replay::AppReplayExt,
+        structure::Structure,
+        unit::MyTeam,
+    },
+    new_ui::{View, ViewExt, button::ButtonView, list::ListView, text::TextView, tree::UiTree},
+};
+
+/// How long players get to look at the outcome before the server shuts down
+const GAME_OVER_GRACE: Duration = Duration::from_secs(15);
+
+pub fn plugin(app: &mut App) {
+    app.register_resource::<GameOutcome>(ChannelDirection::ServerToClient);
+    app.record_resource::<GameOutcome>();
+    app.setup_lua(setup_lua);
+
+    if app.is_server() {
+        app.init_resource::<GameOutcome>();
+        app.add_systems(Startup, |mut commands: Commands| {
+            commands.replicate_resource::<GameOutcome, MessageChannel>(NetworkTarget::All);
+        });
+        app.add_systems(Update, shut_down_after_game_over);
+    } else {
+        app.add_systems(
+            Update,
+            spawn_outcome_screen.run_if(resource_exists_and_changed::<GameOutcome>),
+        );
+        app.add_systems(OnExit(GameState::InGame), |mut commands: Commands| {
+            commands.remove_resource::<GameOutcome>();
+        });
+    }
+}
+
+#[derive(Resource, Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GameOutcome {
+    /// Teams that have lost, in the order they lost
+    pub defeated: Vec<Team>,
+    /// Set once the game is over
+    pub winner: Option<Team>,
+}
+
+impl GameOutcome {
+    pub fn is_over(&self) -> bool {
+        self.winner.is_some()
+    }
+
+    /// Ends the game with `winner` winning, and every other team in `teams` losing
+    pub fn declare_winner(&mut self, winner: Team, teams: impl IntoIterator<Item = Team>) {
+        if self.is_over() {
+            return;
+        }
+
+        for team in teams {
+            if team != winner && !self.defeated.contains(&team) {
+                self.defeated.push(team);
+            }
+        }
+        self.winner = Some(winner);
+    }
+
+    /// Makes `loser` lose. Once only one of `teams` (each listed once) is left, it wins.
+    pub fn make_lose(&mut self, loser: Team, teams: impl IntoIterator<Item = Team>) {
+        if self.is_over() || self.defeated.contains(&loser) {
+            return;
+        }
+
+        self.defeated.push(loser);
+        let remaining = teams
+            .into_iter()
+            .filter(|team| !self.defeated.contains(team))
+            .collect::<Vec<_>>();
+        if let [winner] = remaining[..] {
+            self.winner = Some(winner);
+        }
+    }
+
+    /// `Some(true)` if `team` won, `Some(false)` if it lost, `None` while it is still playing
+    pub fn result_for(&self, team: Team) -> Option<bool> {
+        if self.winner == Some(team) {
+            Some(true)
+        } else if self.defeated.contains(&team) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Every team taking part in the game, whether it has players or just structures
+fn teams(world: &mut World) -> Vec<Team> {
+    let mut teams = world
+        .query_filtered::<&Team, With<Structure>>()
+        .iter(world)
+        .copied()
+        .chain(world.resource::<Players>().players.values().map(|p| p.team))
+        .collect::<Vec<_>>();
+    teams.sort();
+    teams.dedup();
+    teams
+}
+
+fn setup_lua(lua: &Lua) -> LuaResult<()> {
+    let game = lua.table("game")?;
+
+    game.set(
+        "declare_winner",
+        lua.create_function(|lua, team: usize| {
+            if lua.is_client() {
+                return Ok(());
+            }
+
+            let mut world = lua.world();
+            let teams = teams(&mut world);
+            world
+                .resource_mut::<GameOutcome>()
+                .declare_winner(Team(team), teams);
+            Ok(())
+        })?,
+    )?;
+
+    game.set(
+        "make_lose",
+        lua.create_function(|lua, team: usize| {
+            if lua.is_client() {
+                return Ok(());
+            }
+
+            let mut world = lua.world();
+            let teams = teams(&mut world);
+            world
+                .resource_mut::<GameOutcome>()
+                .make_lose(Team(team), teams);
+            Ok(())
+        })?,
+    )?;
+
+    Ok(())
+}
+
+fn shut_down_after_game_over(
+    outcome: Res<GameOutcome>,
+    time: Res<Time>,
+    mut over_at: Local<Option<Duration>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let Some(winner) = outcome.winner else {
+        return;
+    };
+
+    let over_at = *over_at.get_or_insert_with(|| {
+        info!("Game over, team {} won", winner.0);
+        time.elapsed()
+    });
+    if time.elapsed() - over_at >= GAME_OVER_GRACE {
+        exit.write(AppExit::Success);
+    }
+}
+
+#[derive(Component)]
+struct OutcomeScreen;
+
+fn spawn_outcome_screen(
+    outcome: Res<GameOutcome>,
+    my_team: Option<Res<MyTeam>>,
+    screens: Query<(), With<OutcomeScreen>>,
+    mut commands: Commands,
+) {
+    let Some(my_team) = my_team else {
+        return;
+    };
+    if !screens.is_empty() || outcome.result_for(my_team.0).is_none() {
+        return;
+    }
+
+    commands.spawn((
+        StateScoped(GameState::InGame),
+        OutcomeScreen,
+        Node {
+            position_type: PositionType::Absolute,
+            width: Val::Percent(100.0),
+            height: Val::Percent(100.0),
+            ..default()
+        },
+        UiTree::new(outcome_screen),
+    ));
+}
+
+fn outcome_screen(outcome: Res<GameOutcome>, my_team: Res<MyTeam>) -> Option<impl View + use<>> {
+    if !outcome.is_changed() {
+        return None;
+    }
+
+    let title = match outcome.result_for(my_team.0)? {
+        true => "Victory",
+        false => "Defeat",
+    };
+
+    Some(
+        ListView::new()
+            .with(TextView::new(title))
+            .with(ButtonView::new(
+                TextView::new("Leave game"),
+                "leave_game",
+                |mut commands: Commands| commands.disconnect_client(),
+            ))
+            .styled()
+            .flex_direction(FlexDirection::Column)
+            .width(Val::Percent(100.0))
+            .height(Val::Percent(100.0))
+            .align_items(AlignItems::Center)
+            .justify_content(JustifyContent::Center)
+            .row_gap(Val::Px(10.0))
+            .background_color(Color::BLACK.with_alpha(0.6)),
+    )
+}
@@ -0,0 +1,414 @@
+//! Map pings, for pointing teammates at a spot on the map.
+//!
+//! Alt+click pings where the mouse is. Dragging before letting go picks what kind of ping it is:
+//! up for danger, right for on my way, down for assist and left for missing.
+//! The server passes pings on to the pinging player's team, and they show up on the map and the
+//! minimap for a few seconds.
+
+use std::{f32::consts::FRAC_PI_2, time::Duration};
+
+use anyhow::anyhow;
+use bevy::{platform::collections::HashMap, prelude::*, window::PrimaryWindow};
+use bevy_enhanced_input::prelude::*;
+use lightyear::prelude::*;
+use lobby_common::{PlayerId, Team};
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppExt, GameState, Players,
+    ingame::{
+        camera::MousePos,
+        lua::{AppLuaExt, LuaExt, W},
+        map::MessageChannel,
+        minimap::{Minimap, minimap_fraction},
+        targetable::Position,
+        validation::{CommandValidator, RejectedCommand, Rejection, reject},
+    },
+};
+
+/// How long a player has to wait between pings
+const PING_COOLDOWN: Duration = Duration::from_millis(750);
+
+/// How long a ping stays on screen
+const PING_LIFETIME: Duration = Duration::from_secs(3);
+
+/// How far the mouse has to be dragged, in pixels, to pick something other than danger
+const PING_DRAG_THRESHOLD: f32 = 20.0;
+
+pub fn plugin(app: &mut App) {
+    app.register_trigger::<SendPing>(ChannelDirection::ClientToServer);
+    app.register_trigger::<Ping>(ChannelDirection::ServerToClient);
+
+    if app.is_server() {
+        app.init_resource::<LastPings>().add_observer(on_send_ping);
+    } else {
+        app.add_input_context::<PingContext>()
+            .add_observer(bind_input)
+            .add_observer(start_ping)
+            .add_observer(finish_ping)
+            .add_observer(on_ping)
+            .add_systems(Startup, |mut commands: Commands| {
+                commands.spawn(Actions::<PingContext>::default());
+            })
+            .add_systems(
+                Update,
+                (draw_ping_markers, draw_pending_ping, animate_minimap_pings)
+                    .run_if(in_state(GameState::InGame)),
+            );
+    }
+
+    app.setup_lua(setup_lua);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PingKind {
+    Danger,
+    OnMyWay,
+    Missing,
+    Assist,
+}
+
+impl PingKind {
+    fn color(self) -> Color {
+        match self {
+            PingKind::Danger => Color::srgb(1.0, 0.3, 0.2),
+            PingKind::OnMyWay => Color::srgb(0.3, 0.6, 1.0),
+            PingKind::Missing => Color::srgb(1.0, 0.85, 0.2),
+            PingKind::Assist => Color::srgb(0.3, 1.0, 0.4),
+        }
+    }
+}
+
+impl FromLua for PingKind {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        match value.to_string()?.as_str() {
+            "danger" => Ok(Self::Danger),
+            "on_my_way" => Ok(Self::OnMyWay),
+            "missing" => Ok(Self::Missing),
+            "assist" => Ok(Self::Assist),
+            other => Err(LuaError::external(anyhow!(
+                "{other} is not a valid ping kind"
+            ))),
+        }
+    }
+}
+
+impl IntoLua for PingKind {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        lua.create_string(match self {
+            PingKind::Danger => "danger",
+            PingKind::OnMyWay => "on_my_way",
+            PingKind::Missing => "missing",
+            PingKind::Assist => "assist",
+        })?
+        .into_lua(lua)
+    }
+}
+
+/// Sent by a client to ping a spot for its team
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct SendPing {
+    pub kind: PingKind,
+    pub position: Position,
+}
+
+/// A ping shown to a team
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct Ping {
+    pub kind: PingKind,
+    pub position: Position,
+    /// The player who pinged, or `None` for pings from scripts
+    pub sender: Option<PlayerId>,
+}
+
+/// When each client last pinged, for the cooldown
+#[derive(Resource, Default)]
+struct LastPings(HashMap<ClientId, Duration>);
+
+fn on_send_ping(
+    trigger: Trigger<FromClients<SendPing>>,
+    validator: CommandValidator,
+    players: Res<Players>,
+    mut last_pings: ResMut<LastPings>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    let from = trigger.from;
+    let checked = validator.player(from).and_then(|player| {
+        let position = validator.position(from, trigger.message.position)?;
+        Ok((player, position))
+    });
+    let (player, position) = match checked {
+        Ok(checked) => checked,
+        Err(rejection) => return reject(&mut commands, from, RejectedCommand::Ping, rejection),
+    };
+
+    if let Some(&last) = last_pings.0.get(&from)
+        && time.elapsed() < last + PING_COOLDOWN
+    {
+        return reject(
+            &mut commands,
+            from,
+            RejectedCommand::Ping,
+            Rejection::PingOnCooldown,
+        );
+    }
+    last_pings.0.insert(from, time.elapsed());
+
+    let ping = Ping {
+        kind: trigger.message.kind,
+        position,
+        sender: Some(player.id),
+    };
+    let target = players.team_target(player.team);
+    commands.queue(move |world: &mut World| {
+        world.server_trigger::<MessageChannel>(ping, target);
+    });
+}
+
+struct PingArgs {
+    kind: PingKind,
+    position: Vec2,
+    team: Team,
+}
+
+from_into_lua_table!(
+    struct PingArgs {
+        kind: PingKind,
+        position: {W} Vec2,
+        team: {W} Team,
+    }
+);
+
+fn setup_lua(lua: &Lua) -> LuaResult<()> {
+    let game = lua.table("game")?;
+
+    game.set(
+        "ping",
+        lua.create_function(|lua, args: PingArgs| {
+            if lua.is_client() {
+                return Ok(());
+            }
+
+            let mut world = lua.world();
+            let target = world.resource::<Players>().team_target(args.team);
+            world.server_trigger::<MessageChannel>(
+                Ping {
+                    kind: args.kind,
+                    position: Position(args.position),
+                    sender: None,
+                },
+                target,
+            );
+            Ok(())
+        })?,
+    )?;
+
+    Ok(())
+}
+
+#[derive(InputContext)]
+pub struct PingContext;
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct PingClick;
+
+fn bind_input(
+    trigger: Trigger<Binding<PingContext>>,
+    mut actions: Query<&mut Actions<PingContext>>,
+) {
+    let mut actions = actions.get_mut(trigger.target()).unwrap();
+    actions
+        .bind::<PingClick>()
+        .to(MouseButton::Left.with_mod_keys(ModKeys::ALT));
+}
+
+/// A ping being placed, waiting for the mouse to be let go to pick its kind
+#[derive(Resource)]
+struct PendingPing {
+    position: Position,
+    cursor: Vec2,
+}
+
+impl PendingPing {
+    fn kind(&self, cursor: Vec2) -> PingKind {
+        let drag = cursor - self.cursor;
+        if drag.length() < PING_DRAG_THRESHOLD {
+            return PingKind::Danger;
+        }
+        // The cursor's y goes down the screen
+        if drag.x.abs() > drag.y.abs() {
+            if drag.x > 0.0 {
+                PingKind::OnMyWay
+            } else {
+                PingKind::Missing
+            }
+        } else if drag.y > 0.0 {
+            PingKind::Assist
+        } else {
+            PingKind::Danger
+        }
+    }
+}
+
+fn start_ping(
+    _trigger: Trigger<Started<PingClick>>,
+    window: Single<&Window, With<PrimaryWindow>>,
+    mouse_pos: Res<MousePos>,
+    state: Res<State<GameState>>,
+    mut commands: Commands,
+) {
+    if *state.get() != GameState::InGame {
+        return;
+    }
+    let Some(cursor) = window.cursor_position() else {
+        return;
+    };
+    commands.insert_resource(PendingPing {
+        position: mouse_pos.plane_pos,
+        cursor,
+    });
+}
+
+fn finish_ping(
+    _trigger: Trigger<Completed<PingClick>>,
+    pending: Option<Res<PendingPing>>,
+    window: Single<&Window, With<PrimaryWindow>>,
+    mut commands: Commands,
+) {
+    let Some(pending) = pending else {
+        return;
+    };
+    commands.remove_resource::<PendingPing>();
+
+    let kind = window
+        .cursor_position()
+        .map_or(PingKind::Danger, |cursor| pending.kind(cursor));
+    commands.client_trigger::<MessageChannel>(SendPing {
+        kind,
+        position: pending.position,
+    });
+}
+
+/// Shows what kind of ping will be sent while the mouse is held
+fn draw_pending_ping(
+    pending: Option<Res<PendingPing>>,
+    window: Single<&Window, With<PrimaryWindow>>,
+    mut gizmos: Gizmos,
+) {
+    let (Some(pending), Some(cursor)) = (pending, window.cursor_position()) else {
+        return;
+    };
+    gizmos.circle(
+        Isometry3d::new(
+            Vec3::from(pending.position).with_y(0.1),
+            Quat::from_rotation_x(FRAC_PI_2),
+        ),
+        0.8,
+        pending.kind(cursor).color(),
+    );
+}
+
+#[derive(Component)]
+struct PingMarker {
+    kind: PingKind,
+    position: Position,
+    shown_at: Duration,
+}
+
+#[derive(Component)]
+struct MinimapPing {
+    shown_at: Duration,
+}
+
+fn on_ping(
+    trigger: Trigger<FromServer<Ping>>,
+    minimap: Option<Single<Entity, With<Minimap>>>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    let ping = &trigger.message;
+    commands.spawn((
+        StateScoped(GameState::InGame),
+        PingMarker {
+            kind: ping.kind,
+            position: ping.position,
+            shown_at: time.elapsed(),
+        },
+    ));
+
+    let Some(minimap) = minimap else {
+        return;
+    };
+    let on_minimap = minimap_fraction(ping.position);
+    commands.entity(*minimap).with_child((
+        MinimapPing {
+            shown_at: time.elapsed(),
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            left: Val::Percent(on_minimap.x * 100.0),
+            top: Val::Percent(on_minimap.y * 100.0),
+            ..default()
+        },
+        // Above the unit icons
+        ZIndex(1),
+        BackgroundColor(ping.kind.color()),
+        BorderRadius::MAX,
+        Pickable::IGNORE,
+    ));
+}
+
+/// Rings that shrink onto the pinged spot, repeating until the ping is gone
+fn draw_ping_markers(
+    markers: Query<(Entity, &PingMarker)>,
+    time: Res<Time>,
+    mut gizmos: Gizmos,
+    mut commands: Commands,
+) {
+    for (entity, marker) in &markers {
+        let shown_for = time.elapsed() - marker.shown_at;
+        if shown_for >= PING_LIFETIME {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let center = Vec3::from(marker.position).with_y(0.1);
+        let rotation = Quat::from_rotation_x(FRAC_PI_2);
+        let pulse = (shown_for.as_secs_f32() * 2.0).fract();
+        gizmos.circle(Isometry3d::new(center, rotation), 0.6, marker.kind.color());
+        gizmos.circle(
+            Isometry3d::new(center, rotation),
+            0.6 + 1.5 * (1.0 - pulse),
+            marker.kind.color().with_alpha(pulse),
+        );
+        gizmos.line(center, center.with_y(2.0), marker.kind.color());
+    }
+}
+
+fn animate_minimap_pings(
+    mut pings: Query<(Entity, &MinimapPing, &mut Node)>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, ping, mut node) in &mut pings {
+        let shown_for = time.elapsed() - ping.shown_at;
+        if shown_for >= PING_LIFETIME {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let pulse = (shown_for.as_secs_f32() * 2.0).fract();
+        let size = 6.0 + 6.0 * (1.0 - pulse);
+        node.width = Val::Px(size);
+        node.height = Val::Px(size);
+        // Centered on the pinged spot
+        node.margin = UiRect {
+            left: Val::Px(-size / 2.0),
+            top: Val::Px(-size / 2.0),
+            ..default()
+        };
+    }
+}
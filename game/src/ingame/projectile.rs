@@ -8,14 +8,16 @@ use crate::{
     ingame::{
         lua::{AppLuaExt, AssetPathExt, LuaCtx, LuaExt, Protos, W},
         map::MapEntity,
+        replay::AppReplayExt,
         structure::Model,
         targetable::{Facing, Position},
-        unit::{effect::CustomData, UnitProxy}, vision::VisibleBy,
+        unit::{effect::CustomData, UnitProxy}, vision::{VisibleBy, replicate_to_viewers},
     }, AppExt, GameState
 };
 
 pub fn plugin(app: &mut App) {
     app.register_component::<Projectile>(ChannelDirection::ServerToClient);
+    app.record_mapped_component::<Projectile>();
 
     app.init_resource::<Protos<ProjectileProto>>();
 
@@ -91,7 +93,7 @@ impl SpawnProjectile {
                 speed: args.speed,
             },
             StateScoped(GameState::InGame),
-            ServerReplicate::default(),
+            replicate_to_viewers(),
         )).id();
 
         (proto, id)
@@ -0,0 +1,689 @@
+//! Recording matches on the server, and watching them again on the client without a server.
+//!
+//! A replay is a JSON lines file. The first line is a [`ReplayHeader`], and every line after it
+//! a [`ReplayFrame`] with the replicated state that changed on that frame. The first frame holds
+//! everything that existed when recording started, so playback can always start over from it.
+//!
+//! Replicated components and resources are recorded with [`AppReplayExt`], next to where they
+//! are registered with lightyear. Triggers, like effects being applied, are not recorded.
+
+use std::{
+    any::type_name,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::bail;
+use bevy::{
+    ecs::entity::{Entities, EntityMapper, MapEntities},
+    platform::collections::{HashMap, HashSet},
+    prelude::*,
+};
+use lightyear::prelude::Replicating;
+use lobby_common::PlayerId;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+    AppExt, GameState, Players,
+    ingame::{network::ServerOptions, unit::MyTeam, vision::FullVision},
+    main_ui::lobby_list::MyPlayerId,
+};
+
+/// Bumped whenever replays change in a way older builds can't read
+pub const REPLAY_FORMAT_VERSION: u32 = 1;
+
+/// How far the arrow keys jump when watching a replay
+const SEEK_STEP: Duration = Duration::from_secs(10);
+
+/// Playback speeds to step through
+const SPEEDS: [f32; 6] = [0.25, 0.5, 1.0, 2.0, 4.0, 8.0];
+
+pub fn plugin(app: &mut App) {
+    if app.is_server() {
+        app.add_systems(OnEnter(GameState::InGame), start_recording);
+        app.add_systems(
+            PostUpdate,
+            (record_despawns, write_frame)
+                .chain()
+                .after(RecordReplay)
+                .run_if(resource_exists::<ReplayRecorder>),
+        );
+    } else {
+        app.add_systems(
+            OnEnter(GameState::Loading),
+            load_replay_resources.run_if(resource_exists::<ReplayPlayback>),
+        );
+        app.add_systems(
+            OnEnter(GameState::InGame),
+            spawn_timeline.run_if(resource_exists::<ReplayPlayback>),
+        );
+        app.add_systems(
+            Update,
+            (control_playback, advance_playback, update_timeline)
+                .chain()
+                .run_if(in_state(GameState::InGame).and(resource_exists::<ReplayPlayback>)),
+        );
+        app.add_systems(OnExit(GameState::InGame), |mut commands: Commands| {
+            commands.remove_resource::<ReplayPlayback>();
+            commands.remove_resource::<FullVision>();
+        });
+    }
+}
+
+pub trait AppReplayExt {
+    /// Records `C` on replicated entities, so that it is part of replays
+    fn record_component<C: Component + Serialize + DeserializeOwned>(&mut self) -> &mut Self;
+    /// Like [`record_component`](Self::record_component), for components referring to other entities
+    fn record_mapped_component<C: Component + MapEntities + Serialize + DeserializeOwned>(
+        &mut self,
+    ) -> &mut Self;
+    /// Records the resource `R`, so that it is part of replays
+    fn record_resource<R: Resource + Serialize + DeserializeOwned>(&mut self) -> &mut Self;
+    /// Like [`record_resource`](Self::record_resource), for resources referring to entities
+    fn record_mapped_resource<R: Resource + MapEntities + Serialize + DeserializeOwned>(
+        &mut self,
+    ) -> &mut Self;
+}
+
+impl AppReplayExt for App {
+    fn record_component<C: Component + Serialize + DeserializeOwned>(&mut self) -> &mut Self {
+        add_recorded_component::<C>(self, |_, _| {})
+    }
+
+    fn record_mapped_component<C: Component + MapEntities + Serialize + DeserializeOwned>(
+        &mut self,
+    ) -> &mut Self {
+        add_recorded_component::<C>(self, |component, mapper| component.map_entities(mapper))
+    }
+
+    fn record_resource<R: Resource + Serialize + DeserializeOwned>(&mut self) -> &mut Self {
+        add_recorded_resource::<R>(self, |_, _| {})
+    }
+
+    fn record_mapped_resource<R: Resource + MapEntities + Serialize + DeserializeOwned>(
+        &mut self,
+    ) -> &mut Self {
+        add_recorded_resource::<R>(self, |resource, mapper| resource.map_entities(mapper))
+    }
+}
+
+fn add_recorded_component<C: Component + Serialize + DeserializeOwned>(
+    app: &mut App,
+    map: fn(&mut C, &mut ReplayEntityMapper<'_>),
+) -> &mut App {
+    if app.is_server() {
+        app.add_systems(
+            PostUpdate,
+            record_component_changes::<C>
+                .in_set(RecordReplay)
+                .run_if(resource_exists::<ReplayRecorder>),
+        );
+    }
+
+    app.world_mut()
+        .get_resource_or_init::<ReplayRegistry>()
+        .components
+        .insert(
+            type_name::<C>(),
+            RecordedComponent {
+                insert: Box::new(move |mapper, entity, value| {
+                    let mut component = serde_json::from_value::<C>(value)?;
+                    map(&mut component, mapper);
+                    mapper.world.entity_mut(entity).insert(component);
+                    Ok(())
+                }),
+                remove: |entity| {
+                    entity.remove::<C>();
+                },
+            },
+        );
+    app
+}
+
+fn add_recorded_resource<R: Resource + Serialize + DeserializeOwned>(
+    app: &mut App,
+    map: fn(&mut R, &mut ReplayEntityMapper<'_>),
+) -> &mut App {
+    if app.is_server() {
+        app.add_systems(
+            PostUpdate,
+            record_resource_changes::<R>
+                .in_set(RecordReplay)
+                .run_if(resource_exists::<ReplayRecorder>.and(resource_exists::<R>)),
+        );
+    }
+
+    app.world_mut()
+        .get_resource_or_init::<ReplayRegistry>()
+        .resources
+        .insert(
+            type_name::<R>(),
+            Box::new(move |mapper, value| {
+                let mut resource = serde_json::from_value::<R>(value)?;
+                map(&mut resource, mapper);
+                mapper.world.insert_resource(resource);
+                Ok(())
+            }),
+        );
+    app
+}
+
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+struct RecordReplay;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    pub format_version: u32,
+    /// Version of the game that recorded the replay
+    pub game_version: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplayFrame {
+    /// Time since recording started
+    pub time: Duration,
+    pub changes: Vec<ReplayChange>,
+}
+
+/// Components and resources are named by their type name.
+/// Entities are the ones used on the server that recorded the replay.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplayChange {
+    Insert {
+        entity: Entity,
+        component: String,
+        value: serde_json::Value,
+    },
+    Remove {
+        entity: Entity,
+        component: String,
+    },
+    Despawn {
+        entity: Entity,
+    },
+    Resource {
+        resource: String,
+        value: serde_json::Value,
+    },
+}
+
+impl ReplayChange {
+    fn apply(
+        &self,
+        world: &mut World,
+        registry: &ReplayRegistry,
+        entities: &mut HashMap<Entity, Entity>,
+    ) {
+        let mut mapper = ReplayEntityMapper { world, entities };
+        let result = match self {
+            ReplayChange::Insert {
+                entity,
+                component,
+                value,
+            } => {
+                let Some(recorded) = registry.components.get(component.as_str()) else {
+                    warn_once!("Replay contains unknown component {component}");
+                    return;
+                };
+                let entity = mapper.local(*entity);
+                (recorded.insert)(&mut mapper, entity, value.clone())
+            }
+            ReplayChange::Remove { entity, component } => {
+                if let Some(recorded) = registry.components.get(component.as_str())
+                    && let Some(&entity) = mapper.entities.get(entity)
+                    && let Ok(mut entity) = mapper.world.get_entity_mut(entity)
+                {
+                    (recorded.remove)(&mut entity);
+                }
+                Ok(())
+            }
+            ReplayChange::Despawn { entity } => {
+                if let Some(entity) = mapper.entities.remove(entity)
+                    && let Ok(entity) = mapper.world.get_entity_mut(entity)
+                {
+                    entity.despawn();
+                }
+                Ok(())
+            }
+            ReplayChange::Resource { resource, value } => {
+                let Some(insert) = registry.resources.get(resource.as_str()) else {
+                    warn_once!("Replay contains unknown resource {resource}");
+                    return;
+                };
+                insert(&mut mapper, value.clone())
+            }
+        };
+
+        if let Err(err) = result {
+            warn!("Could not apply replay change: {err}");
+        }
+    }
+}
+
+/// How to play back every recorded component and resource, keyed by type name
+#[derive(Resource, Default)]
+struct ReplayRegistry {
+    components: HashMap<&'static str, RecordedComponent>,
+    resources: HashMap<&'static str, InsertResource>,
+}
+
+struct RecordedComponent {
+    insert: Box<
+        dyn Fn(&mut ReplayEntityMapper<'_>, Entity, serde_json::Value) -> serde_json::Result<()>
+            + Send
+            + Sync,
+    >,
+    remove: fn(&mut EntityWorldMut),
+}
+
+type InsertResource = Box<
+    dyn Fn(&mut ReplayEntityMapper<'_>, serde_json::Value) -> serde_json::Result<()> + Send + Sync,
+>;
+
+/// Maps entities of the recording server to the entities we play them back as,
+/// spawning them as they are first seen
+struct ReplayEntityMapper<'a> {
+    world: &'a mut World,
+    entities: &'a mut HashMap<Entity, Entity>,
+}
+
+impl ReplayEntityMapper<'_> {
+    fn local(&mut self, recorded: Entity) -> Entity {
+        *self
+            .entities
+            .entry(recorded)
+            .or_insert_with(|| self.world.spawn(StateScoped(GameState::InGame)).id())
+    }
+}
+
+impl EntityMapper for ReplayEntityMapper<'_> {
+    fn get_mapped(&mut self, source: Entity) -> Entity {
+        self.local(source)
+    }
+
+    fn set_mapped(&mut self, source: Entity, target: Entity) {
+        self.entities.insert(source, target);
+    }
+}
+
+/// Present on the server while a replay is being recorded
+#[derive(Resource)]
+struct ReplayRecorder {
+    writer: BufWriter<File>,
+    /// When recording started, as elapsed time
+    started: Duration,
+    /// Changes made this frame
+    changes: Vec<ReplayChange>,
+    /// Entities that have been recorded and not yet despawned
+    entities: HashSet<Entity>,
+}
+
+impl ReplayRecorder {
+    fn create(path: &Path, started: Duration) -> anyhow::Result<Self> {
+        let mut recorder = Self {
+            writer: BufWriter::new(File::create(path)?),
+            started,
+            changes: vec![],
+            entities: HashSet::new(),
+        };
+        recorder.write_line(&ReplayHeader {
+            format_version: REPLAY_FORMAT_VERSION,
+            game_version: env!("CARGO_PKG_VERSION").into(),
+        })?;
+        Ok(recorder)
+    }
+
+    fn write_line(&mut self, line: &impl Serialize) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.writer, line)?;
+        self.writer.write_all(b"\n")?;
+        // Flushed every frame so that the replay survives the server being killed
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+fn start_recording(options: Res<ServerOptions>, time: Res<Time>, mut commands: Commands) {
+    let Some(path) = &options.record_replay else {
+        return;
+    };
+
+    match ReplayRecorder::create(path, time.elapsed()) {
+        Ok(recorder) => {
+            info!("Recording replay to {}", path.display());
+            commands.insert_resource(recorder);
+        }
+        Err(err) => error!("Could not record replay to {}: {err}", path.display()),
+    }
+}
+
+fn record_component_changes<C: Component + Serialize>(
+    components: Query<(Entity, Ref<C>, Ref<Replicating>)>,
+    mut removed: RemovedComponents<C>,
+    entities: &Entities,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    for (entity, component, replicating) in &components {
+        if !component.is_changed() && !replicating.is_added() {
+            continue;
+        }
+
+        match serde_json::to_value(&*component) {
+            Ok(value) => {
+                recorder.entities.insert(entity);
+                recorder.changes.push(ReplayChange::Insert {
+                    entity,
+                    component: type_name::<C>().into(),
+                    value,
+                });
+            }
+            Err(err) => warn!("Could not record {}: {err}", type_name::<C>()),
+        }
+    }
+
+    for entity in removed.read() {
+        // Despawned entities are recorded once, by record_despawns
+        if entities.contains(entity) && recorder.entities.contains(&entity) {
+            recorder.changes.push(ReplayChange::Remove {
+                entity,
+                component: type_name::<C>().into(),
+            });
+        }
+    }
+}
+
+fn record_resource_changes<R: Resource + Serialize>(
+    resource: Res<R>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    if !resource.is_changed() {
+        return;
+    }
+
+    match serde_json::to_value(&*resource) {
+        Ok(value) => recorder.changes.push(ReplayChange::Resource {
+            resource: type_name::<R>().into(),
+            value,
+        }),
+        Err(err) => warn!("Could not record {}: {err}", type_name::<R>()),
+    }
+}
+
+fn record_despawns(entities: &Entities, mut recorder: ResMut<ReplayRecorder>) {
+    let recorder = &mut *recorder;
+    recorder.entities.retain(|&entity| {
+        let alive = entities.contains(entity);
+        if !alive {
+            recorder.changes.push(ReplayChange::Despawn { entity });
+        }
+        alive
+    });
+}
+
+fn write_frame(mut recorder: ResMut<ReplayRecorder>, time: Res<Time>, mut commands: Commands) {
+    if recorder.changes.is_empty() {
+        return;
+    }
+
+    let frame = ReplayFrame {
+        time: time.elapsed() - recorder.started,
+        changes: std::mem::take(&mut recorder.changes),
+    };
+    if let Err(err) = recorder.write_line(&frame) {
+        error!("Stopped recording replay: {err}");
+        commands.remove_resource::<ReplayRecorder>();
+    }
+}
+
+/// Starts watching the replay at the given path
+pub struct StartReplay(pub PathBuf);
+
+impl Command for StartReplay {
+    fn apply(self, world: &mut World) {
+        match ReplayPlayback::load(&self.0) {
+            Ok(playback) => {
+                info!(
+                    "Watching replay {}, {} frames long",
+                    self.0.display(),
+                    playback.frames.len()
+                );
+                world.insert_resource(playback);
+                world.commands().set_state(GameState::Loading);
+            }
+            Err(err) => error!("Could not load replay {}: {err}", self.0.display()),
+        }
+    }
+}
+
+/// Run condition for systems that don't apply to replays
+pub fn watching_replay(playback: Option<Res<ReplayPlayback>>) -> bool {
+    playback.is_some()
+}
+
+/// Present on the client while watching a replay
+#[derive(Resource)]
+struct ReplayPlayback {
+    frames: Vec<ReplayFrame>,
+    /// How many frames have been applied to the world
+    applied: usize,
+    /// Recorded entities, and the entities we play them back as
+    entities: HashMap<Entity, Entity>,
+    time: Duration,
+    speed: f32,
+    paused: bool,
+    /// The player whose vision we watch with, or `None` to see everything
+    pov: Option<PlayerId>,
+}
+
+impl ReplayPlayback {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+
+        let Some(header) = lines.next() else {
+            bail!("Replay is empty");
+        };
+        let header = serde_json::from_str::<ReplayHeader>(&header?)?;
+        if header.format_version != REPLAY_FORMAT_VERSION {
+            bail!(
+                "Replay has format version {}, but we can only play version {REPLAY_FORMAT_VERSION}",
+                header.format_version
+            );
+        }
+        if header.game_version != env!("CARGO_PKG_VERSION") {
+            warn!(
+                "Replay was recorded with game version {}, it may not play back correctly",
+                header.game_version
+            );
+        }
+
+        let mut frames = vec![];
+        for line in lines {
+            match serde_json::from_str::<ReplayFrame>(&line?) {
+                Ok(frame) => frames.push(frame),
+                Err(err) => {
+                    // Most likely the server was stopped while writing the last frame
+                    warn!("Replay ends early, after {} frames: {err}", frames.len());
+                    break;
+                }
+            }
+        }
+        if frames.is_empty() {
+            bail!("Replay has no frames");
+        }
+
+        Ok(Self {
+            frames,
+            applied: 0,
+            entities: HashMap::new(),
+            time: Duration::ZERO,
+            speed: 1.0,
+            paused: false,
+            pov: None,
+        })
+    }
+
+    fn duration(&self) -> Duration {
+        self.frames
+            .last()
+            .map_or(Duration::ZERO, |frame| frame.time)
+    }
+}
+
+/// Applies the resources of the first frame, which tell us what to load, and picks a player
+/// to watch as until another is picked
+fn load_replay_resources(world: &mut World) {
+    world.resource_scope(|world, registry: Mut<ReplayRegistry>| {
+        world.resource_scope(|world, mut playback: Mut<ReplayPlayback>| {
+            let playback = &mut *playback;
+            for change in &playback.frames[0].changes {
+                if let ReplayChange::Resource { .. } = change {
+                    change.apply(world, &registry, &mut playback.entities);
+                }
+            }
+        });
+    });
+
+    let first_player = world.get_resource::<Players>().and_then(|players| {
+        players
+            .players
+            .values()
+            .min_by_key(|player| (player.team, player.name.clone()))
+            .map(|player| player.id)
+    });
+    match first_player {
+        Some(player) => {
+            world.insert_resource(MyPlayerId(player));
+            world.insert_resource(FullVision);
+        }
+        None => warn!("Replay has no players"),
+    }
+}
+
+fn control_playback(
+    input: Res<ButtonInput<KeyCode>>,
+    mut playback: ResMut<ReplayPlayback>,
+    players: Option<Res<Players>>,
+    mut commands: Commands,
+) {
+    if input.just_pressed(KeyCode::KeyP) {
+        playback.paused = !playback.paused;
+    }
+    if input.just_pressed(KeyCode::ArrowRight) {
+        playback.time = (playback.time + SEEK_STEP).min(playback.duration());
+    }
+    if input.just_pressed(KeyCode::ArrowLeft) {
+        playback.time = playback.time.saturating_sub(SEEK_STEP);
+    }
+    if input.just_pressed(KeyCode::ArrowUp) {
+        let speed = playback.speed;
+        playback.speed = SPEEDS.into_iter().find(|&s| s > speed).unwrap_or(speed);
+    }
+    if input.just_pressed(KeyCode::ArrowDown) {
+        let speed = playback.speed;
+        playback.speed = SPEEDS.into_iter().rfind(|&s| s < speed).unwrap_or(speed);
+    }
+
+    if input.just_pressed(KeyCode::KeyV)
+        && let Some(players) = players
+    {
+        // Cycle through every player, then back to seeing everything
+        let mut povs = players.players.values().collect::<Vec<_>>();
+        povs.sort_by_key(|player| (player.team, player.name.clone()));
+        let next = match playback.pov {
+            None => povs.first(),
+            Some(current) => povs.iter().skip_while(|p| p.id != current).nth(1),
+        };
+
+        playback.pov = next.map(|player| player.id);
+        match next {
+            Some(player) => {
+                commands.remove_resource::<FullVision>();
+                commands.insert_resource(MyPlayerId(player.id));
+                commands.insert_resource(MyTeam(player.team));
+            }
+            None => commands.insert_resource(FullVision),
+        }
+    }
+
+    if input.just_pressed(KeyCode::Escape) {
+        commands.set_state(GameState::NotInGame);
+    }
+}
+
+fn advance_playback(world: &mut World) {
+    let delta = world.resource::<Time>().delta();
+
+    world.resource_scope(|world, registry: Mut<ReplayRegistry>| {
+        world.resource_scope(|world, mut playback: Mut<ReplayPlayback>| {
+            let playback = &mut *playback;
+            if !playback.paused {
+                playback.time =
+                    (playback.time + delta.mul_f32(playback.speed)).min(playback.duration());
+            }
+
+            // When seeking backwards, start over from the snapshot in the first frame
+            if playback.applied > 0 && playback.frames[playback.applied - 1].time > playback.time {
+                for (_, entity) in playback.entities.drain() {
+                    if let Ok(entity) = world.get_entity_mut(entity) {
+                        entity.despawn();
+                    }
+                }
+                playback.applied = 0;
+            }
+
+            while let Some(frame) = playback.frames.get(playback.applied)
+                && frame.time <= playback.time
+            {
+                for change in &frame.changes {
+                    change.apply(world, &registry, &mut playback.entities);
+                }
+                playback.applied += 1;
+            }
+        });
+    });
+}
+
+#[derive(Component)]
+struct ReplayTimeline;
+
+fn spawn_timeline(mut commands: Commands) {
+    commands.spawn((
+        StateScoped(GameState::InGame),
+        ReplayTimeline,
+        Text::default(),
+        Node {
+            position_type: PositionType::Absolute,
+            left: Val::Px(10.0),
+            bottom: Val::Px(10.0),
+            ..default()
+        },
+    ));
+}
+
+fn update_timeline(
+    playback: Res<ReplayPlayback>,
+    players: Option<Res<Players>>,
+    mut timeline: Single<&mut Text, With<ReplayTimeline>>,
+) {
+    let state = if playback.paused { "Paused" } else { "Playing" };
+    let pov = match playback.pov {
+        None => "everyone".to_string(),
+        Some(pov) => players
+            .and_then(|players| players.players.get(&pov).map(|player| player.name.clone()))
+            .unwrap_or_else(|| "unknown player".into()),
+    };
+
+    timeline.0 = format!(
+        "{state} {} / {}, speed x{}, watching as {pov}\n\
+         P: pause  Left/Right: seek  Up/Down: speed  V: change vision  Esc: leave",
+        format_time(playback.time),
+        format_time(playback.duration()),
+        playback.speed,
+    );
+}
+
+fn format_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
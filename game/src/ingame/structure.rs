@@ -10,17 +10,18 @@ use mlua::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    AppExt,
+    AppExt, GameState,
     ingame::{
         map::MapEntity,
         navmesh::TerrainData,
+        replay::AppReplayExt,
         unit::{UnitId, UnitProxy, effect::EffectList},
         vision::SightRange,
     },
 };
 
 use super::{
-    lua::{AppLuaExt, AssetPathExt, LuaExt, Protos},
+    lua::{AppLuaExt, AssetPathExt, LuaCtx, LuaExt, Protos},
     targetable::{Health, Position},
 };
 
@@ -30,10 +31,17 @@ pub fn common(app: &mut App) {
 
     if app.is_client() {
         app.add_observer(on_insert_model);
+    } else {
+        app.add_systems(
+            FixedUpdate,
+            destroy_structures.run_if(in_state(GameState::InGame)),
+        );
     }
 
     app.register_component::<Model>(ChannelDirection::ServerToClient);
     app.register_component::<Structure>(ChannelDirection::ServerToClient);
+    app.record_component::<Model>()
+        .record_component::<Structure>();
 }
 
 #[derive(PartialEq)]
@@ -44,7 +52,7 @@ pub struct StructProto {
     pub health: f32,
     pub radius: f32,
     pub on_spawn: Option<LuaFunction>,
-    pub on_destroy: Option<LuaFunction>,
+    pub on_destroyed: Option<LuaFunction>,
     pub custom_data: Option<LuaValue>,
 }
 
@@ -56,7 +64,7 @@ proto!(
         health: f32,
         radius: f32,
         on_spawn: Option<LuaFunction>,
-        on_destroy: Option<LuaFunction>,
+        on_destroyed: Option<LuaFunction>,
         custom_data: Option<LuaValue>,
     }
 );
@@ -117,6 +125,7 @@ fn setup_lua(lua: &Lua) -> LuaResult<()> {
                         SightRange(25.0),
                         MapEntity,
                         Structure,
+                        StructProtoId(proto.id.clone()),
                         UnitId(Uuid::new_v4()),
                         ServerReplicate::default(),
                     ))
@@ -162,9 +171,49 @@ from_into_lua_table!(
 #[require(EffectList)]
 pub struct Structure;
 
+/// The proto a structure was spawned from, only present on the server
+#[derive(Component, Debug, Clone, PartialEq, Eq)]
+pub struct StructProtoId(pub String);
+
 #[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Model(pub AssetPath<'static>);
 
+/// Runs `on_destroyed` for structures that ran out of health, then despawns them.
+/// Done here rather than in `kill_if_low_health` so the hook still sees the structure.
+fn destroy_structures(world: &mut World) {
+    let destroyed = world
+        .query_filtered::<(Entity, &Health, &StructProtoId), With<Structure>>()
+        .iter(world)
+        .filter(|(_, health, _)| health.0 <= 0.0)
+        .map(|(entity, _, id)| (entity, id.0.clone()))
+        .collect::<Vec<_>>();
+
+    if destroyed.is_empty() {
+        return;
+    }
+
+    let lua = world.resource::<LuaCtx>().0.clone();
+
+    for (entity, id) in destroyed {
+        match world.resource::<Protos<StructProto>>().get(&id) {
+            Ok((proto, _)) => {
+                if let Some(on_destroyed) = proto.on_destroyed {
+                    lua.with_world(world, |_lua| {
+                        if let Err(e) = on_destroyed.call::<()>(UnitProxy { entity }) {
+                            error!("Lua error during structure {} destruction: {}", proto.id, e);
+                        }
+                    });
+                }
+            }
+            Err(e) => error!("Missing proto for destroyed structure {id}: {e}"),
+        }
+
+        if let Ok(entity) = world.get_entity_mut(entity) {
+            entity.despawn();
+        }
+    }
+}
+
 fn on_insert_model(
     trigger: Trigger<OnInsert, Model>,
     query: Query<&Model>,
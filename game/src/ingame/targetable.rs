@@ -3,15 +3,21 @@ use crate::ingame::{
         AnchorPoint, AnchorUiConfig, AnchorUiPlugin, AnchoredUiNodes, HorizontalAnchor,
         VerticalAnchor,
     },
+    replay::AppReplayExt,
     unit::attack::AutoAttackTimer,
 };
+use std::{collections::VecDeque, f32::consts::TAU};
+
 use bevy::{color::palettes, prelude::*};
-use lightyear::prelude::*;
+use lightyear::{client::config::ClientConfig, prelude::*};
 use lobby_common::Team;
 
-use crate::{AppExt, GameState, UiCameraMarker, ingame::unit::MyTeam};
+use crate::{AppExt, GameState, Options, UiCameraMarker, ingame::unit::MyTeam};
 
-use super::unit::stats::StatBlock;
+use super::{
+    structure::Structure,
+    unit::{prediction::Predicted, stats::StatBlock},
+};
 
 pub mod healthbar;
 
@@ -24,15 +30,23 @@ pub fn common(app: &mut App) {
     app.register_component::<Health>(ChannelDirection::ServerToClient);
     app.register_component::<Position>(ChannelDirection::ServerToClient);
     app.register_component::<Facing>(ChannelDirection::ServerToClient);
+    app.register_component::<SnapshotTick>(ChannelDirection::ServerToClient);
+    app.record_component::<Health>()
+        .record_component::<Position>()
+        .record_component::<Facing>()
+        .record_component::<SnapshotTick>();
 
     if app.is_client() {
         app.add_plugins(AnchorUiPlugin::<UiCameraMarker>::new());
+        app.init_resource::<ServerClock>();
         app.add_systems(
             Update,
             (
-                (update_previous_position, interpolate_transform).chain(),
-                update_facing,
-            ),
+                advance_server_clock,
+                record_snapshots,
+                interpolate_transform,
+            )
+                .chain(),
         );
         app.add_observer(on_insert_position);
         // app.add_observer(spawn_floating_health_bars);
@@ -42,11 +56,13 @@ pub fn common(app: &mut App) {
         // );
     } else {
         app.add_systems(FixedUpdate, kill_if_low_health);
+        app.add_systems(FixedPostUpdate, stamp_snapshots);
     }
 }
 
+/// Structures are left to [`super::structure`], which runs their Lua hook first
 fn kill_if_low_health(
-    query: Query<(Entity, &Health /*Option<&LuaObject>*/)>,
+    query: Query<(Entity, &Health /*Option<&LuaObject>*/), Without<Structure>>,
     mut commands: Commands,
 ) {
     for (e, hp /*obj*/) in query {
@@ -59,7 +75,7 @@ fn kill_if_low_health(
 #[derive(
     Default, Debug, Component, Clone, Copy, PartialEq, Serialize, Deserialize, Deref, DerefMut,
 )]
-#[require(CachedPosition, PreviousPosition, PostitionLastChanged, Transform)]
+#[require(Snapshots, SnapshotTick, Transform)]
 #[repr(transparent)]
 pub struct Position(pub Vec2);
 
@@ -87,62 +103,245 @@ impl From<&Position> for Vec3 {
     }
 }
 
-#[derive(
-    Default, Debug, Component, Clone, Copy, PartialEq, Serialize, Deserialize, Deref, DerefMut,
-)]
-pub struct CachedPosition(pub Vec2);
+/// Snapshots are kept for this many ticks past the oldest one still needed
+const MAX_SNAPSHOT_TICKS: f64 = 8.0;
 
-#[derive(
-    Default, Debug, Component, Clone, Copy, PartialEq, Serialize, Deserialize, Deref, DerefMut,
-)]
-pub struct PreviousPosition(pub Vec2);
+/// How many ticks an entity keeps moving past its newest snapshot before waiting for the next
+const MAX_EXTRAPOLATION_TICKS: f64 = 1.0;
 
-#[derive(Default, Debug, Component, Clone, Copy)]
-pub struct PostitionLastChanged(f32);
+/// How much of the way the [`ServerClock`] moves back towards an update that arrived late
+const SERVER_CLOCK_DRIFT: f64 = 0.05;
 
-fn interpolate_transform(
-    q: Query<(
-        Ref<Position>,
-        &PreviousPosition,
-        &mut PostitionLastChanged,
-        &mut Transform,
-    )>,
+/// Stamps of entities that come into view are read as at most this many ticks ahead of the
+/// [`ServerClock`], and as being from the past otherwise
+const MAX_PLACED_TICKS_AHEAD: u16 = 64;
+
+/// The server tick at which an entity last moved or turned, replicated along with its
+/// [`Position`] and [`Facing`] so the client knows when the server had it there
+#[derive(Default, Debug, Component, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotTick(pub u16);
+
+fn stamp_snapshots(
+    q: Query<&mut SnapshotTick, Or<(Changed<Position>, Changed<Facing>)>>,
+    tick_manager: Res<TickManager>,
+) {
+    for mut stamp in q {
+        stamp.set_if_neq(SnapshotTick(tick_manager.tick().0));
+    }
+}
+
+/// Our estimate of the server tick that updates are arriving from right now, counted from the
+/// first one we got so it doesn't wrap around like [`Tick`] does.
+/// Moves forward with our own clock, jumps ahead when an update is newer than expected and slowly
+/// drifts back when updates keep arriving late, so jitter doesn't shake the whole timeline.
+#[derive(Default, Debug, Resource)]
+pub struct ServerClock {
+    tick: Option<f64>,
+}
+
+impl ServerClock {
+    /// The estimated server tick, if we have heard from the server yet
+    pub fn tick(&self) -> Option<f64> {
+        self.tick
+    }
+
+    /// Unwraps the tick of an update that just arrived and corrects the clock by it.
+    /// Only for stamps from the last few ticks, as the clock would follow older ones back.
+    fn observe(&mut self, stamp: SnapshotTick) -> f64 {
+        let Some(clock) = self.tick else {
+            self.tick = Some(stamp.0 as f64);
+            return stamp.0 as f64;
+        };
+
+        // Ticks wrap around, so only how far the stamp is from the clock is meaningful
+        let wrapped = clock.round().rem_euclid(u16::MAX as f64 + 1.0) as u16;
+        let tick = clock.round() + stamp.0.wrapping_sub(wrapped) as i16 as f64;
+        let error = tick - clock;
+        self.tick = Some(if error > 0.0 {
+            tick
+        } else {
+            clock + error * SERVER_CLOCK_DRIFT
+        });
+        tick
+    }
+
+    /// Unwraps the tick of a stamp that may be arbitrarily old, like the one of a structure that
+    /// never moved, without correcting the clock by it
+    fn place(&self, stamp: SnapshotTick) -> Option<f64> {
+        let clock = self.tick?.round();
+        let wrapped = clock.rem_euclid(u16::MAX as f64 + 1.0) as u16;
+        let ahead = stamp.0.wrapping_sub(wrapped);
+        Some(if ahead <= MAX_PLACED_TICKS_AHEAD {
+            clock + ahead as f64
+        } else {
+            clock - wrapped.wrapping_sub(stamp.0) as f64
+        })
+    }
+}
+
+fn advance_server_clock(
+    mut clock: ResMut<ServerClock>,
+    config: Res<ClientConfig>,
     time: Res<Time>,
 ) {
-    for (pos, prev, mut last_changed, mut trans) in q {
-        if pos.is_changed() {
-            last_changed.0 = time.elapsed_secs();
+    let tick = config.shared.tick.tick_duration.as_secs_f64();
+    if let Some(clock) = &mut clock.tick {
+        *clock += time.delta_secs_f64() / tick;
+    }
+}
+
+/// Where the server had an entity and at which server tick, oldest first.
+/// Entities are drawn a few ticks in the past so there is usually a snapshot on either side.
+#[derive(Default, Debug, Component)]
+pub struct Snapshots(VecDeque<Snapshot>);
+
+#[derive(Debug, Clone, Copy)]
+struct Snapshot {
+    tick: f64,
+    position: Vec2,
+    facing: f32,
+}
+
+impl Snapshots {
+    /// Where the entity was at server tick `at`, extrapolating at most `max_extrapolation` ticks
+    /// past the newest snapshot
+    fn sample(&self, at: f64, max_extrapolation: f64) -> Option<(Vec2, f32)> {
+        let newest = *self.0.back()?;
+
+        let (from, to) = match self.0.iter().position(|snapshot| snapshot.tick > at) {
+            Some(0) => return Some((self.0[0].position, self.0[0].facing)),
+            Some(i) => (self.0[i - 1], self.0[i]),
+            None => {
+                let Some(&previous) = self.0.iter().rev().nth(1) else {
+                    return Some((newest.position, newest.facing));
+                };
+
+                // Keep going the way it was heading for a little while, then settle back on the
+                // newest snapshot in case it actually stopped there
+                let past = at - newest.tick;
+                let ahead = if past <= max_extrapolation {
+                    past
+                } else {
+                    (2.0 * max_extrapolation - past).max(0.0)
+                };
+                let step = newest.tick - previous.tick;
+                let t = 1.0 + ahead / step.max(f64::EPSILON);
+                return Some((
+                    previous.position.lerp(newest.position, t as f32),
+                    newest.facing,
+                ));
+            }
+        };
+
+        let span = to.tick - from.tick;
+        let t = ((at - from.tick) / span.max(f64::EPSILON)).clamp(0.0, 1.0) as f32;
+        Some((
+            from.position.lerp(to.position, t),
+            lerp_angle(from.facing, to.facing, t),
+        ))
+    }
+
+    fn push(&mut self, snapshot: Snapshot) {
+        // Updates can arrive out of order, only the newest one is worth keeping then
+        if self
+            .0
+            .back()
+            .is_some_and(|newest| newest.tick > snapshot.tick)
+        {
+            return;
         }
+        self.0.push_back(snapshot);
+    }
+}
 
-        let t = ((time.elapsed_secs() - last_changed.0) * 20.0).clamp(0.0, 1.0);
+/// Turns from `from` to `to` the short way around
+fn lerp_angle(from: f32, to: f32, t: f32) -> f32 {
+    let diff = (to - from + TAU / 2.0).rem_euclid(TAU) - TAU / 2.0;
+    from + diff * t
+}
 
-        trans.translation = Vec3::from(Position(prev.lerp(**pos, t))).with_y(trans.translation.y);
+fn record_snapshots(
+    q: Query<
+        (
+            &Position,
+            Option<&Facing>,
+            Ref<SnapshotTick>,
+            &mut Snapshots,
+        ),
+        (Or<(Changed<Position>, Changed<Facing>)>, Without<Predicted>),
+    >,
+    mut clock: ResMut<ServerClock>,
+) {
+    for (pos, facing, stamp, mut snapshots) in q {
+        // The first snapshot is placed by `on_insert_position`, after that every update is fresh
+        if stamp.is_added() {
+            continue;
+        }
+        let tick = clock.observe(*stamp);
+        snapshots.push(Snapshot {
+            tick,
+            position: pos.0,
+            facing: facing.map_or(0.0, |facing| facing.0),
+        });
+
+        // Always keep two, so there is something to extrapolate from
+        while snapshots.0.len() > 2 && tick - snapshots.0[1].tick > MAX_SNAPSHOT_TICKS {
+            snapshots.0.pop_front();
+        }
     }
 }
 
-fn update_previous_position(
-    q: Query<(&Position, &mut PreviousPosition, &mut CachedPosition), Changed<Position>>,
+fn interpolate_transform(
+    q: Query<(&Snapshots, Has<Facing>, &mut Transform), Without<Predicted>>,
+    clock: Res<ServerClock>,
+    options: Res<Options>,
 ) {
-    for (pos, mut prev, mut cache) in q {
-        prev.0 = cache.0;
-        cache.0 = pos.0;
+    let Some(now) = clock.tick() else {
+        return;
+    };
+    let render_at = now - options.interpolation_ticks as f64;
+
+    for (snapshots, has_facing, mut trans) in q {
+        let Some((position, facing)) = snapshots.sample(render_at, MAX_EXTRAPOLATION_TICKS) else {
+            continue;
+        };
+
+        trans.translation = Vec3::from(Position(position)).with_y(trans.translation.y);
+        if has_facing {
+            trans.look_to(Vec3::from(Facing(facing)), Vec3::Y);
+        }
     }
 }
 
 fn on_insert_position(
     trigger: Trigger<OnAdd, Position>,
-    mut q: Query<(&Position, &mut PreviousPosition, &mut CachedPosition, &mut Transform)>,
+    mut q: Query<(
+        &Position,
+        Option<&Facing>,
+        &SnapshotTick,
+        &mut Snapshots,
+        &mut Transform,
+    )>,
+    clock: Res<ServerClock>,
 ) {
-    let (pos, mut prev, mut cache, mut trans) = q.get_mut(trigger.target()).unwrap();
-    prev.0 = pos.0;
-    cache.0 = pos.0;
+    let (pos, facing, &stamp, mut snapshots, mut trans) = q.get_mut(trigger.target()).unwrap();
+    snapshots.0.clear();
+    // Entities coming into view bring the stamp of when they last moved, which can be long ago.
+    // Without a clock to place it on the entity just stays where it is until it moves.
+    if let Some(tick) = clock.place(stamp) {
+        snapshots.0.push_back(Snapshot {
+            tick,
+            position: pos.0,
+            facing: facing.map_or(0.0, |facing| facing.0),
+        });
+    }
     trans.translation = pos.into();
 }
 
 #[derive(
     Default, Debug, Component, Clone, Copy, PartialEq, Serialize, Deserialize, Deref, DerefMut,
 )]
-#[require(CachedPosition, PreviousPosition, PostitionLastChanged, Transform)]
+#[require(Transform)]
 #[repr(transparent)]
 pub struct Facing(pub f32);
 
@@ -169,9 +368,3 @@ impl From<Facing> for Vec3 {
         Position(Vec2::from_angle(val.0)).into()
     }
 }
-
-fn update_facing(q: Query<(&Facing, &mut Transform), Changed<Facing>>) {
-    for (facing, mut trans) in q {
-        trans.look_to(Vec3::from(*facing), Vec3::Y);
-    }
-}
@@ -6,7 +6,9 @@ use crate::{
     ingame::{
         camera::{AnchorUiConfig, AnchoredUiNodes, HorizontalAnchor, VerticalAnchor},
         structure::Structure,
-        unit::{MyTeam, UnitType, stats::StatBlock},
+        unit::{
+            MyTeam, UnitType, experience::Experience, resource::UnitResource, stats::StatBlock,
+        },
         vision,
     },
 };
@@ -18,7 +20,7 @@ pub fn plugin(app: &mut App) {
         app.add_observer(on_health_added);
         app.add_systems(
             Update,
-            update_healthbar
+            (update_healthbar, update_resource_bar, update_level_text)
                 .run_if(in_state(GameState::InGame))
                 .after(vision::change_visibility),
         );
@@ -41,6 +43,55 @@ struct HealthBarValueRef(Entity);
 #[relationship(relationship_target = HealthBarValueRef)]
 struct HealthBarValueOf(Entity);
 
+#[derive(Component)]
+#[relationship_target(relationship = ResourceBarOf)]
+struct ResourceBarRef(Entity);
+
+#[derive(Component)]
+#[relationship(relationship_target = ResourceBarRef)]
+struct ResourceBarOf(Entity);
+
+#[derive(Component)]
+#[relationship_target(relationship = ResourceBarValueOf)]
+struct ResourceBarValueRef(Entity);
+
+#[derive(Component)]
+#[relationship(relationship_target = ResourceBarValueRef)]
+struct ResourceBarValueOf(Entity);
+
+#[derive(Component)]
+#[relationship_target(relationship = LevelTextOf)]
+struct LevelTextRef(Entity);
+
+#[derive(Component)]
+#[relationship(relationship_target = LevelTextRef)]
+struct LevelTextOf(Entity);
+
+/// The bar under the healthbar, hidden until it turns out the unit has a resource
+fn resource_bar(unit: Entity, width: f32, height: f32) -> impl Bundle {
+    (
+        Node {
+            width: Val::Px(width),
+            height: Val::Px(height),
+            border: UiRect::all(Val::Px(1.0)),
+            display: Display::None,
+            ..default()
+        },
+        BorderColor(Color::Srgba(palettes::tailwind::AMBER_500)),
+        BackgroundColor(Color::Srgba(palettes::tailwind::GRAY_500)),
+        ResourceBarOf(unit),
+        Children::spawn_one((
+            Node {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                ..default()
+            },
+            BackgroundColor(Color::NONE),
+            ResourceBarValueOf(unit),
+        )),
+    )
+}
+
 pub fn on_health_added(
     trigger: Trigger<OnInsert, Health>,
     q: Query<&UnitType>,
@@ -62,26 +113,32 @@ pub fn on_health_added(
                         .with_vertical_anchoring(VerticalAnchor::Bottom)
                         .with_offset(vec3(0.0, 1.2, -0.2)),
                     GlobalZIndex(0),
-                    Node::default(),
-                    Children::spawn_one((
-                        Node {
-                            width: Val::Px(80.0),
-                            height: Val::Px(5.0),
-                            border: UiRect::all(Val::Px(1.0)),
-                            ..default()
-                        },
-                        BorderColor(Color::Srgba(palettes::tailwind::AMBER_500)),
-                        BackgroundColor(Color::Srgba(palettes::tailwind::GRAY_500)),
-                        HealthBarOf(trigger.target()),
-                        Children::spawn_one((
+                    Node {
+                        flex_direction: FlexDirection::Column,
+                        ..default()
+                    },
+                    Children::spawn((
+                        Spawn((
                             Node {
-                                width: Val::Percent(100.0),
-                                height: Val::Percent(100.0),
+                                width: Val::Px(80.0),
+                                height: Val::Px(5.0),
+                                border: UiRect::all(Val::Px(1.0)),
                                 ..default()
                             },
-                            BackgroundColor(Color::Srgba(palettes::tailwind::GREEN_500)),
-                            HealthBarValueOf(trigger.target()),
+                            BorderColor(Color::Srgba(palettes::tailwind::AMBER_500)),
+                            BackgroundColor(Color::Srgba(palettes::tailwind::GRAY_500)),
+                            HealthBarOf(trigger.target()),
+                            Children::spawn_one((
+                                Node {
+                                    width: Val::Percent(100.0),
+                                    height: Val::Percent(100.0),
+                                    ..default()
+                                },
+                                BackgroundColor(Color::Srgba(palettes::tailwind::GREEN_500)),
+                                HealthBarValueOf(trigger.target()),
+                            )),
                         )),
+                        Spawn(resource_bar(trigger.target(), 80.0, 3.0)),
                     )),
                 )));
         }
@@ -95,26 +152,47 @@ pub fn on_health_added(
                         .with_vertical_anchoring(VerticalAnchor::Bottom)
                         .with_offset(vec3(0.0, 2.2, -0.2)),
                     GlobalZIndex(1),
-                    Node::default(),
-                    Children::spawn_one((
-                        Node {
-                            width: Val::Px(160.0),
-                            height: Val::Px(20.0),
-                            border: UiRect::all(Val::Px(2.0)),
-                            ..default()
-                        },
-                        BorderColor(Color::Srgba(palettes::tailwind::AMBER_500)),
-                        BackgroundColor(Color::Srgba(palettes::tailwind::GRAY_500)),
-                        HealthBarOf(trigger.target()),
-                        Children::spawn_one((
+                    Node {
+                        flex_direction: FlexDirection::Column,
+                        ..default()
+                    },
+                    Children::spawn((
+                        Spawn((
                             Node {
-                                width: Val::Percent(100.0),
-                                height: Val::Percent(100.0),
+                                width: Val::Px(160.0),
+                                height: Val::Px(20.0),
+                                border: UiRect::all(Val::Px(2.0)),
                                 ..default()
                             },
-                            BackgroundColor(Color::Srgba(palettes::tailwind::GREEN_500)),
-                            HealthBarValueOf(trigger.target()),
+                            BorderColor(Color::Srgba(palettes::tailwind::AMBER_500)),
+                            BackgroundColor(Color::Srgba(palettes::tailwind::GRAY_500)),
+                            HealthBarOf(trigger.target()),
+                            Children::spawn((
+                                Spawn((
+                                    Node {
+                                        width: Val::Percent(100.0),
+                                        height: Val::Percent(100.0),
+                                        ..default()
+                                    },
+                                    BackgroundColor(Color::Srgba(palettes::tailwind::GREEN_500)),
+                                    HealthBarValueOf(trigger.target()),
+                                )),
+                                // Level, just left of the bar
+                                Spawn((
+                                    Node {
+                                        position_type: PositionType::Absolute,
+                                        left: Val::Px(-26.0),
+                                        width: Val::Px(22.0),
+                                        justify_content: JustifyContent::Center,
+                                        ..default()
+                                    },
+                                    Text::default(),
+                                    TextFont::from_font_size(14.0),
+                                    LevelTextOf(trigger.target()),
+                                )),
+                            )),
                         )),
+                        Spawn(resource_bar(trigger.target(), 160.0, 8.0)),
                     )),
                 )));
         }
@@ -123,18 +201,30 @@ pub fn on_health_added(
 
 fn update_healthbar(
     q: Query<
-        (&Health, &StatBlock, &HealthBarRef, &HealthBarValueRef, &Visibility, &Team),
-        Or<(Changed<Health>, Changed<Visibility>)>,
+        (
+            &Health,
+            &StatBlock,
+            &HealthBarRef,
+            &HealthBarValueRef,
+            Option<&ResourceBarRef>,
+            &Visibility,
+            &Team,
+        ),
+        Or<(Changed<Health>, Changed<StatBlock>, Changed<Visibility>)>,
     >,
     mut q2: Query<(&mut Node, &mut BackgroundColor, &mut Visibility), Without<Health>>,
     my_team: Res<MyTeam>,
 ) {
-    for (hp, stats, healthbar, healthbar_value, vis, team) in q {
+    for (hp, stats, healthbar, healthbar_value, resource_bar, vis, team) in q {
         let (_, _, mut bar_vis) = q2.get_mut(healthbar.0).unwrap();
         bar_vis.set_if_neq(*vis);
+        if let Some(resource_bar) = resource_bar {
+            let (_, _, mut bar_vis) = q2.get_mut(resource_bar.0).unwrap();
+            bar_vis.set_if_neq(*vis);
+        }
 
         let (mut node, mut bg, _) = q2.get_mut(healthbar_value.0).unwrap();
-        node.width = Val::Percent(hp.0 / stats.max_health.base * 100.0);
+        node.width = Val::Percent(hp.0 / stats.max_health.current() * 100.0);
         bg.0 = if *team == my_team.0 {
             palettes::tailwind::GREEN_500.into()
         } else {
@@ -142,3 +232,31 @@ fn update_healthbar(
         };
     }
 }
+
+fn update_resource_bar(
+    q: Query<
+        (&UnitResource, &ResourceBarRef, &ResourceBarValueRef),
+        Or<(Changed<UnitResource>, Changed<ResourceBarRef>)>,
+    >,
+    mut q2: Query<(&mut Node, &mut BackgroundColor), Without<UnitResource>>,
+) {
+    for (resource, resource_bar, resource_bar_value) in q {
+        let (mut node, _) = q2.get_mut(resource_bar.0).unwrap();
+        node.display = Display::Flex;
+
+        let (mut node, mut bg) = q2.get_mut(resource_bar_value.0).unwrap();
+        node.width = Val::Percent(resource.current / resource.max.max(f32::EPSILON) * 100.0);
+        bg.0 = resource.color();
+    }
+}
+
+fn update_level_text(
+    q: Query<(&Experience, &LevelTextRef), Or<(Changed<Experience>, Changed<LevelTextRef>)>>,
+    mut texts: Query<&mut Text>,
+) {
+    for (experience, level_text) in q {
+        if let Ok(mut text) = texts.get_mut(level_text.0) {
+            text.0 = experience.level.to_string();
+        }
+    }
+}
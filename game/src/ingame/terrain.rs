@@ -18,6 +18,7 @@ use serde::{Deserialize, Serialize};
 
 use super::{
     camera::MousePos,
+    chat::typing_in_chat,
     lua::{AppLuaExt, LuaExt},
 };
 
@@ -63,9 +64,11 @@ pub fn client(app: &mut App) {
             (
                 enable_input.run_if(not(
                     egui_wants_any_input.and(in_state(IsTerrainEditingState::Yes))
-                )),
-                disable_input
-                    .run_if(egui_wants_any_input.and(in_state(IsTerrainEditingState::Yes))),
+                ).and(not(typing_in_chat))),
+                disable_input.run_if(
+                    egui_wants_any_input.and(in_state(IsTerrainEditingState::Yes))
+                        .or(typing_in_chat),
+                ),
             ),
         );
 }
@@ -1,6 +1,7 @@
 use super::{
     lua::{AppLuaExt, AssetPathExt, LuaExt, Protos},
     map::MapEntity,
+    replay::AppReplayExt,
     structure::Model,
     targetable::{Facing, Position},
 };
@@ -10,12 +11,17 @@ use crate::{
         lua::{LuaCtx, W},
         targetable::Health,
         unit::{
+            ability::Abilities,
             attack::{AutoAttackTarget, AutoAttackTimer, AutoAttackType},
+            damage::DamageHook,
             effect::{CustomData, EffectList},
+            experience::Experience,
+            orders::{OrderQueue, QueuedOrder},
+            resource::{ResourceProto, UnitResource},
             state::StateList,
             stats::{BaseStats, StatBlock},
         },
-        vision::{SightRange, VisibleBy},
+        vision::{SightRange, VisibleBy, replicate_to_viewers},
     },
 };
 use anyhow::anyhow;
@@ -28,12 +34,18 @@ use lightyear::prelude::*;
 use lobby_common::Team;
 use mlua::prelude::*;
 
+pub mod ability;
 pub mod animation;
 pub mod attack;
 pub mod champion;
 pub mod collision;
+pub mod damage;
 pub mod effect;
+pub mod experience;
 pub mod movement;
+pub mod orders;
+pub mod prediction;
+pub mod resource;
 pub mod state;
 pub mod stats;
 
@@ -47,6 +59,12 @@ pub fn common(app: &mut App) {
         attack::plugin,
         state::plugin,
         collision::plugin,
+        prediction::plugin,
+        orders::plugin,
+        damage::plugin,
+        ability::plugin,
+        resource::plugin,
+        experience::plugin,
     ));
 
     app.register_trigger::<SetUnitMovementTarget>(ChannelDirection::ClientToServer);
@@ -55,6 +73,11 @@ pub fn common(app: &mut App) {
     app.register_component::<UnitId>(ChannelDirection::ServerToClient);
     app.register_component::<UnitType>(ChannelDirection::ServerToClient);
     app.register_component::<ControlledByClient>(ChannelDirection::ServerToClient);
+    app.record_component::<MovementTarget>()
+        .record_component::<Unit>()
+        .record_component::<UnitId>()
+        .record_component::<UnitType>()
+        .record_component::<ControlledByClient>();
 
     app.init_resource::<Protos<UnitProto>>();
     app.init_resource::<UnitMap>();
@@ -112,24 +135,31 @@ impl SpawnUnit {
             *proto_cont = Some(proj_proto);
         }
 
-        let id = world
-            .spawn((
-                Transform::from_translation(Position(args.position).into()),
-                Position(args.position),
-                Model(proto.model.clone().relative(&origin)),
-                Unit,
-                args.team,
-                args.data,
-                proto.unit_type,
-                attack_type,
-                MapEntity,
-                Health(proto.base_stats.max_health),
-                StatBlock::from(proto.base_stats.clone()),
-                UnitId(Uuid::new_v4()),
-                StateScoped(GameState::InGame),
-                ServerReplicate::default(),
-            ))
-            .id();
+        let mut unit = world.spawn((
+            Transform::from_translation(Position(args.position).into()),
+            Position(args.position),
+            Model(proto.model.clone().relative(&origin)),
+            Unit,
+            args.team,
+            args.data,
+            proto.unit_type,
+            attack_type,
+            MapEntity,
+            Health(proto.base_stats.max_health),
+            StatBlock::from(proto.base_stats.clone()),
+            UnitId(Uuid::new_v4()),
+            UnitProtoId(proto.id.clone()),
+            StateScoped(GameState::InGame),
+            replicate_to_viewers(),
+        ));
+        unit.insert(Abilities::new(proto.abilities.clone().unwrap_or_default()));
+        if let Some(resource) = &proto.resource {
+            unit.insert(UnitResource::from_proto(resource));
+        }
+        if proto.unit_type == UnitType::Champion {
+            unit.insert(Experience::default());
+        }
+        let id = unit.id();
 
         (proto, id)
     }
@@ -167,6 +197,10 @@ impl Command<Entity> for SpawnUnit {
 #[derive(Debug, Component, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct UnitId(pub Uuid);
 
+/// The proto a unit was spawned from, only present on the server
+#[derive(Component, Debug, Clone, PartialEq, Eq)]
+pub struct UnitProtoId(pub String);
+
 pub struct SpawnUnitArgs {
     pub proto: String,
     pub position: Vec2,
@@ -211,6 +245,27 @@ struct UnitProto {
     base_stats: BaseStats,
     model: AssetPath<'static>,
     on_spawn: Option<LuaFunction>,
+    modify_damage_dealt: Option<LuaFunction>,
+    modify_damage_taken: Option<LuaFunction>,
+    on_damage_dealt: Option<LuaFunction>,
+    on_damage_taken: Option<LuaFunction>,
+    /// Ability protos, in the order of the keys they are cast with
+    abilities: Option<Vec<String>>,
+    resource: Option<ResourceProto>,
+    /// Shared between the enemy champions near the unit when it dies
+    xp_reward: Option<f32>,
+    on_level_up: Option<LuaFunction>,
+}
+
+impl UnitProto {
+    fn damage_hook(&self, hook: DamageHook) -> Option<LuaFunction> {
+        match hook {
+            DamageHook::ModifyDealt => self.modify_damage_dealt.clone(),
+            DamageHook::ModifyTaken => self.modify_damage_taken.clone(),
+            DamageHook::Dealt => self.on_damage_dealt.clone(),
+            DamageHook::Taken => self.on_damage_taken.clone(),
+        }
+    }
 }
 
 proto!(
@@ -223,12 +278,20 @@ proto!(
         base_stats: BaseStats,
         model: {W} AssetPath<'static>,
         on_spawn: Option<LuaFunction>,
+        modify_damage_dealt: Option<LuaFunction>,
+        modify_damage_taken: Option<LuaFunction>,
+        on_damage_dealt: Option<LuaFunction>,
+        on_damage_taken: Option<LuaFunction>,
+        abilities: Option<Vec<String>>,
+        resource: Option<ResourceProto>,
+        xp_reward: Option<f32>,
+        on_level_up: Option<LuaFunction>,
     }
 );
 
 /// Marker struct for units
 #[derive(Component, Default, Clone, PartialEq, Serialize, Deserialize)]
-#[require(VisibleBy, SightRange = SightRange(10.0), EffectList, AutoAttackTimer, StateList, CustomData, Facing)]
+#[require(VisibleBy, SightRange = SightRange(10.0), EffectList, AutoAttackTimer, StateList, CustomData, Facing, OrderQueue)]
 pub struct Unit;
 
 /// Where this unit currently wants to go
@@ -237,7 +300,7 @@ pub struct MovementTarget(pub Position);
 
 /// Message for clients to set their controlled unit's movement target.
 #[derive(Debug, Event, Clone, Serialize, Deserialize)]
-pub struct SetUnitMovementTarget(Position);
+pub struct SetUnitMovementTarget(pub Position);
 
 #[derive(Component, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ControlledByClient(pub ClientId);
@@ -278,6 +341,12 @@ pub struct UnitMap {
     map: HashMap<UnitId, Entity>,
 }
 
+impl UnitMap {
+    pub fn get(&self, id: &UnitId) -> Option<Entity> {
+        self.map.get(id).copied()
+    }
+}
+
 fn on_unit_id_inserted(
     trigger: Trigger<OnInsert, UnitId>,
     q: Query<&UnitId>,
@@ -310,6 +379,18 @@ pub struct UnitProxy {
     pub entity: Entity,
 }
 
+impl UnitProxy {
+    /// Adds `order` to the end of the unit's queue
+    fn queue_order(&self, lua: &Lua, order: QueuedOrder) -> LuaResult<bool> {
+        Ok(lua
+            .world()
+            .entity_mut(self.entity)
+            .get_mut::<OrderQueue>()
+            .unwrap()
+            .push(order))
+    }
+}
+
 impl LuaUserData for UnitProxy {
     fn add_fields<F: LuaUserDataFields<Self>>(_fields: &mut F) {}
 
@@ -341,6 +422,22 @@ impl LuaUserData for UnitProxy {
             Ok(())
         });
 
+        methods.add_method("queue_move", |lua, s, pos: W<Vec2>| {
+            s.queue_order(lua, QueuedOrder::Move(Position(pos.0)))
+        });
+
+        methods.add_method("queue_attack", |lua, s, target: UnitProxy| {
+            let target = lua.world().entity(target.entity).get::<UnitId>().copied();
+            let Some(target) = target else {
+                return Err(LuaError::external(anyhow!("Only units can be attacked")));
+            };
+            s.queue_order(lua, QueuedOrder::Attack(target))
+        });
+
+        methods.add_method("queue_attack_move", |lua, s, pos: W<Vec2>| {
+            s.queue_order(lua, QueuedOrder::AttackMove(Position(pos.0)))
+        });
+
         methods.add_method("get_team", |lua, s, ()| {
             Ok(W(*lua.world().entity(s.entity).get::<Team>().unwrap()))
         });
@@ -359,6 +456,22 @@ impl LuaUserData for UnitProxy {
 
         methods.add_method("apply_effect", effect::apply_effect);
 
+        methods.add_method("deal_damage", damage::deal_damage_from_lua);
+
+        methods.add_method("add_shield", damage::add_shield);
+
+        methods.add_method("add_stat_modifier", stats::add_stat_modifier);
+
+        methods.add_method("get_resource", resource::get_resource);
+
+        methods.add_method("spend_resource", resource::spend_resource);
+
+        methods.add_method("restore_resource", resource::restore_resource);
+
+        methods.add_method("get_level", experience::get_level);
+
+        methods.add_method("add_experience", experience::add_experience);
+
         methods.add_method("get_custom_data", |lua, s, ()| {
             Ok(lua
                 .world()
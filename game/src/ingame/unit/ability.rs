@@ -0,0 +1,692 @@
+//! Champion abilities, cast with Q, W, E and R.
+//!
+//! Abilities are registered from Lua with `game.register_ability` and handed to units through the
+//! `abilities` list of their proto. Abilities that aim at something are aimed with the mouse and
+//! cast with a left click, while a right click cancels aiming. The server checks every cast, puts
+//! the unit in the ability's cast state for its cast time and then calls its `on_cast`.
+
+use std::f32::consts::FRAC_PI_2;
+
+use anyhow::anyhow;
+use bevy::prelude::*;
+use bevy_enhanced_input::prelude::*;
+use lightyear::prelude::*;
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppExt, GameState, Players,
+    ingame::{
+        camera::MousePos,
+        lua::{AppLuaExt, LuaCtx, LuaExt, Protos, W},
+        map::MessageChannel,
+        replay::AppReplayExt,
+        targetable::{Health, Position},
+        unit::{
+            ControlledByClient, MovementTarget, UnitId, UnitMap, UnitProxy,
+            movement::{CurrentPath, MoveClick, UnitControlContext},
+            orders::cancel_current_order,
+            resource::UnitResource,
+            state::{State, StateList, StateProto},
+        },
+        validation::{CommandValidator, RejectedCommand, Rejection, reject},
+    },
+    main_ui::lobby_list::MyPlayerId,
+};
+
+/// How much further than its range a cast may be aimed, to make up for latency
+const RANGE_LEEWAY: f32 = 0.5;
+
+/// The keys abilities are cast with, in slot order
+const ABILITY_KEYS: [&str; 4] = ["Q", "W", "E", "R"];
+
+pub fn plugin(app: &mut App) {
+    app.register_component::<Abilities>(ChannelDirection::ServerToClient);
+    app.record_component::<Abilities>();
+    app.register_trigger::<CastAbility>(ChannelDirection::ClientToServer);
+
+    app.init_resource::<Protos<AbilityProto>>();
+
+    if app.is_client() {
+        app.add_observer(bind_input)
+            .add_observer(on_ability_key::<AbilityQ, 0>)
+            .add_observer(on_ability_key::<AbilityW, 1>)
+            .add_observer(on_ability_key::<AbilityE, 2>)
+            .add_observer(on_ability_key::<AbilityR, 3>)
+            .add_observer(on_cast_click)
+            .add_observer(cancel_aim)
+            .add_systems(OnEnter(GameState::InGame), spawn_ability_bar)
+            .add_systems(Update, tick_cooldowns)
+            .add_systems(
+                Update,
+                (draw_ability_indicators, update_ability_bar).run_if(
+                    in_state(GameState::InGame)
+                        .and(resource_exists::<Players>)
+                        .and(resource_exists::<MyPlayerId>),
+                ),
+            );
+    } else {
+        app.add_observer(on_cast_ability)
+            .add_systems(FixedUpdate, (tick_cooldowns, tick_casts));
+    }
+
+    app.setup_lua(setup_lua);
+}
+
+fn setup_lua(lua: &Lua) -> LuaResult<()> {
+    let game = lua.table("game")?;
+
+    game.set(
+        "register_ability",
+        lua.create_function(|lua, proto: LuaValue| {
+            let ability = AbilityProto::from_lua(proto.clone(), lua)?;
+            let cast_state = StateProto {
+                id: cast_state(&ability.id),
+                name: ability.name.clone(),
+                priority: 3.0,
+                move_cancellable: false,
+                on_move_cancel: None,
+                animation: ability.animation.unwrap_or_else(|| "Cast".into()),
+                loop_animation: false,
+            };
+
+            let mut world = lua.world();
+            world
+                .resource_mut::<Protos<StateProto>>()
+                .insert(cast_state.into_lua(lua)?, lua.current_path())?;
+            world
+                .resource_mut::<Protos<AbilityProto>>()
+                .insert(proto, lua.current_path())
+        })?,
+    )?;
+
+    Ok(())
+}
+
+/// What an ability is aimed at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Targeting {
+    /// Cast right away, on the caster
+    SelfCast,
+    Unit,
+    Point,
+    Direction,
+    /// A point, with the ability's radius drawn around it while aiming
+    Area,
+}
+
+impl FromLua for Targeting {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        match value.to_string()?.as_str() {
+            "self" => Ok(Self::SelfCast),
+            "unit" => Ok(Self::Unit),
+            "point" => Ok(Self::Point),
+            "direction" => Ok(Self::Direction),
+            "area" => Ok(Self::Area),
+            other => Err(LuaError::external(anyhow!(
+                "{other} is not a valid targeting mode"
+            ))),
+        }
+    }
+}
+
+impl IntoLua for Targeting {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        lua.create_string(match self {
+            Targeting::SelfCast => "self",
+            Targeting::Unit => "unit",
+            Targeting::Point => "point",
+            Targeting::Direction => "direction",
+            Targeting::Area => "area",
+        })?
+        .into_lua(lua)
+    }
+}
+
+#[derive(PartialEq)]
+pub struct AbilityProto {
+    pub id: String,
+    pub name: String,
+    /// In seconds
+    pub cooldown: f32,
+    pub cost: Option<f32>,
+    /// How long the unit is stuck in the cast state before `on_cast` is called, in seconds
+    pub cast_time: f32,
+    pub range: f32,
+    pub targeting: Targeting,
+    /// Size of the area drawn while aiming area abilities
+    pub radius: Option<f32>,
+    pub animation: Option<String>,
+    pub on_cast: Option<LuaFunction>,
+}
+
+proto!(
+    pub struct AbilityProto {
+        pub id: String,
+        pub name: String,
+        pub cooldown: f32,
+        pub cost: Option<f32>,
+        pub cast_time: f32,
+        pub range: f32,
+        pub targeting: Targeting,
+        pub radius: Option<f32>,
+        pub animation: Option<String>,
+        pub on_cast: Option<LuaFunction>,
+    }
+);
+
+/// The id of the state a unit is in while casting `ability`
+fn cast_state(ability: &str) -> String {
+    format!("cast.{ability}")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AbilitySlot {
+    pub proto: String,
+    /// Seconds until the ability can be cast again
+    pub cooldown: f32,
+}
+
+/// The abilities a unit can cast, one per slot.
+/// Cooldowns count down on both sides, so they only get replicated when an ability is cast.
+#[derive(Component, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Abilities(pub Vec<AbilitySlot>);
+
+impl Abilities {
+    pub fn new(protos: impl IntoIterator<Item = String>) -> Self {
+        Self(
+            protos
+                .into_iter()
+                .map(|proto| AbilitySlot {
+                    proto,
+                    cooldown: 0.0,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// What a cast is aimed at
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AbilityTarget {
+    None,
+    Unit(UnitId),
+    Point(Position),
+    Direction(Vec2),
+}
+
+/// Message for clients to cast one of their unit's abilities
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct CastAbility {
+    pub slot: u8,
+    pub target: AbilityTarget,
+}
+
+/// The unit is winding up an ability, which goes off once `remaining` runs out
+#[derive(Component, Clone, PartialEq)]
+pub struct Casting {
+    pub ability: String,
+    pub target: AbilityTarget,
+    pub remaining: f32,
+}
+
+/// The ability slot the local player is aiming with the mouse
+#[derive(Resource)]
+struct AbilityAim(usize);
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct AbilityQ;
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct AbilityW;
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct AbilityE;
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct AbilityR;
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct CastClick;
+
+fn bind_input(
+    trigger: Trigger<Binding<UnitControlContext>>,
+    mut actions: Query<&mut Actions<UnitControlContext>>,
+) {
+    let mut actions = actions.get_mut(trigger.target()).unwrap();
+    actions
+        .bind::<AbilityQ>()
+        .to(KeyCode::KeyQ)
+        .with_conditions(Press::default());
+    actions
+        .bind::<AbilityW>()
+        .to(KeyCode::KeyW)
+        .with_conditions(Press::default());
+    actions
+        .bind::<AbilityE>()
+        .to(KeyCode::KeyE)
+        .with_conditions(Press::default());
+    actions
+        .bind::<AbilityR>()
+        .to(KeyCode::KeyR)
+        .with_conditions(Press::default());
+    actions
+        .bind::<CastClick>()
+        .to(MouseButton::Left)
+        .with_conditions(Press::default());
+}
+
+/// The unit the local player controls
+fn my_unit<'a, T>(
+    units: impl IntoIterator<Item = (&'a ControlledByClient, T)>,
+    players: &Players,
+    my_id: &MyPlayerId,
+) -> Option<T> {
+    let me = players.players.get(&my_id.0)?;
+    units
+        .into_iter()
+        .find(|(controller, _)| controller.0 == me.client_id)
+        .map(|(_, item)| item)
+}
+
+/// Casts self abilities right away, and starts aiming the rest.
+/// Pressing the key of the ability being aimed stops aiming it.
+fn on_ability_key<A: InputAction, const SLOT: usize>(
+    _trigger: Trigger<Fired<A>>,
+    units: Query<(&ControlledByClient, &Abilities)>,
+    protos: Res<Protos<AbilityProto>>,
+    aim: Option<Res<AbilityAim>>,
+    players: Option<Res<Players>>,
+    my_id: Option<Res<MyPlayerId>>,
+    mut commands: Commands,
+) {
+    let (Some(players), Some(my_id)) = (players, my_id) else {
+        return;
+    };
+    let Some(slot) = my_unit(&units, &players, &my_id).and_then(|abilities| abilities.0.get(SLOT))
+    else {
+        return;
+    };
+    let Ok((proto, _)) = protos.get_cached(&slot.proto) else {
+        return;
+    };
+
+    if aim.is_some_and(|aim| aim.0 == SLOT) {
+        commands.remove_resource::<AbilityAim>();
+    } else if proto.targeting == Targeting::SelfCast {
+        commands.remove_resource::<AbilityAim>();
+        commands.client_trigger::<MessageChannel>(CastAbility {
+            slot: SLOT as u8,
+            target: AbilityTarget::None,
+        });
+    } else {
+        commands.insert_resource(AbilityAim(SLOT));
+    }
+}
+
+/// Casts the aimed ability at whatever is under the mouse
+fn on_cast_click(
+    _trigger: Trigger<Fired<CastClick>>,
+    aim: Option<Res<AbilityAim>>,
+    units: Query<(&ControlledByClient, (&Position, &Abilities))>,
+    targets: Query<(&UnitId, &Position), With<Health>>,
+    protos: Res<Protos<AbilityProto>>,
+    mouse_pos: Res<MousePos>,
+    players: Option<Res<Players>>,
+    my_id: Option<Res<MyPlayerId>>,
+    mut commands: Commands,
+) {
+    let (Some(aim), Some(players), Some(my_id)) = (aim, players, my_id) else {
+        return;
+    };
+    let Some((pos, abilities)) = my_unit(&units, &players, &my_id) else {
+        return;
+    };
+    let Some((proto, _)) = abilities
+        .0
+        .get(aim.0)
+        .and_then(|slot| protos.get_cached(&slot.proto).ok())
+    else {
+        return;
+    };
+
+    let click = mouse_pos.plane_pos;
+    let target = match proto.targeting {
+        Targeting::SelfCast => AbilityTarget::None,
+        Targeting::Unit => {
+            // Missing every unit keeps aiming
+            let Some((unit_id, _)) = targets
+                .iter()
+                .find(|(_, target_pos)| target_pos.distance(*click) <= 0.5)
+            else {
+                return;
+            };
+            AbilityTarget::Unit(*unit_id)
+        }
+        Targeting::Point | Targeting::Area => AbilityTarget::Point(click),
+        Targeting::Direction => AbilityTarget::Direction((*click - **pos).normalize_or(Vec2::X)),
+    };
+
+    commands.client_trigger::<MessageChannel>(CastAbility {
+        slot: aim.0 as u8,
+        target,
+    });
+    commands.remove_resource::<AbilityAim>();
+}
+
+fn cancel_aim(_trigger: Trigger<Fired<MoveClick>>, mut commands: Commands) {
+    commands.remove_resource::<AbilityAim>();
+}
+
+/// Draws the range of the aimed ability, and what it would hit
+fn draw_ability_indicators(
+    aim: Option<Res<AbilityAim>>,
+    units: Query<(&ControlledByClient, (&Transform, &Abilities))>,
+    protos: Res<Protos<AbilityProto>>,
+    mouse_pos: Res<MousePos>,
+    players: Res<Players>,
+    my_id: Res<MyPlayerId>,
+    mut gizmos: Gizmos,
+) {
+    let Some(aim) = aim else { return };
+    let Some((trans, abilities)) = my_unit(&units, &players, &my_id) else {
+        return;
+    };
+    let Some((proto, _)) = abilities
+        .0
+        .get(aim.0)
+        .and_then(|slot| protos.get_cached(&slot.proto).ok())
+    else {
+        return;
+    };
+
+    let on_ground = |pos: Vec3| Isometry3d::new(pos.with_y(0.06), Quat::from_rotation_x(FRAC_PI_2));
+    let unit_pos = trans.translation.with_y(0.06);
+    let mouse = Vec3::from(mouse_pos.plane_pos).with_y(0.06);
+    let color = Color::srgb(0.3, 0.7, 1.0);
+
+    gizmos.circle(on_ground(unit_pos), proto.range, color);
+    match proto.targeting {
+        Targeting::Area => {
+            gizmos.circle(on_ground(mouse), proto.radius.unwrap_or(1.0), color);
+        }
+        Targeting::Direction => {
+            let direction = (mouse - unit_pos).normalize_or(Vec3::X);
+            gizmos.line(unit_pos, unit_pos + direction * proto.range, color);
+        }
+        Targeting::SelfCast | Targeting::Unit | Targeting::Point => {}
+    }
+}
+
+fn tick_cooldowns(mut units: Query<&mut Abilities>, time: Res<Time>) {
+    for mut abilities in &mut units {
+        for slot in &mut abilities.bypass_change_detection().0 {
+            slot.cooldown = (slot.cooldown - time.delta_secs()).max(0.0);
+        }
+    }
+}
+
+fn on_cast_ability(
+    trigger: Trigger<FromClients<CastAbility>>,
+    mut units: Query<(
+        &Position,
+        &mut Abilities,
+        &mut StateList,
+        Has<Casting>,
+        Option<&mut UnitResource>,
+    )>,
+    positions: Query<&Position>,
+    validator: CommandValidator,
+    ability_protos: Res<Protos<AbilityProto>>,
+    state_protos: Res<Protos<StateProto>>,
+    mut commands: Commands,
+) {
+    let from = trigger.from;
+    let CastAbility { slot, target } = trigger.message.clone();
+    let checked = validator.controlled_unit(from).and_then(|(unit, _)| {
+        let (pos, abilities, state, casting, resource) =
+            units.get(unit).map_err(|_| Rejection::NoControlledUnit)?;
+        let ability = abilities
+            .0
+            .get(slot as usize)
+            .ok_or(Rejection::UnknownAbility)?;
+        let (proto, _) = ability_protos
+            .get_cached(&ability.proto)
+            .map_err(|_| Rejection::UnknownAbility)?;
+
+        if ability.cooldown > 0.0 {
+            return Err(Rejection::AbilityOnCooldown);
+        }
+        let (state_proto, _) = state_protos
+            .get_cached(&state.current_state().proto)
+            .map_err(|_| Rejection::Busy)?;
+        if casting || !state_proto.move_cancellable {
+            return Err(Rejection::Busy);
+        }
+        let cost = proto.cost.unwrap_or(0.0);
+        if cost > 0.0 && !resource.is_some_and(|resource| resource.current >= cost) {
+            return Err(Rejection::NotEnoughResource);
+        }
+
+        let in_range = |target: Position| {
+            if target.distance(**pos) <= proto.range + RANGE_LEEWAY {
+                Ok(())
+            } else {
+                Err(Rejection::OutOfRange)
+            }
+        };
+        let target = match (proto.targeting, target) {
+            (Targeting::SelfCast, AbilityTarget::None) => AbilityTarget::None,
+            (Targeting::Unit, AbilityTarget::Unit(target)) => {
+                let entity = validator.visible_unit(from, target)?;
+                in_range(
+                    *positions
+                        .get(entity)
+                        .map_err(|_| Rejection::UnknownTarget)?,
+                )?;
+                AbilityTarget::Unit(target)
+            }
+            (Targeting::Point | Targeting::Area, AbilityTarget::Point(target)) => {
+                let target = validator.position(from, target)?;
+                in_range(target)?;
+                AbilityTarget::Point(target)
+            }
+            (Targeting::Direction, AbilityTarget::Direction(direction)) => {
+                AbilityTarget::Direction(
+                    direction
+                        .try_normalize()
+                        .ok_or(Rejection::InvalidPosition)?,
+                )
+            }
+            _ => return Err(Rejection::WrongAbilityTarget),
+        };
+        Ok((unit, proto, target))
+    });
+    let (unit, proto, target) = match checked {
+        Ok(checked) => checked,
+        Err(rejection) => {
+            return reject(&mut commands, from, RejectedCommand::CastAbility, rejection);
+        }
+    };
+
+    let Ok((_, mut abilities, mut state, _, resource)) = units.get_mut(unit) else {
+        return;
+    };
+    abilities.0[slot as usize].cooldown = proto.cooldown;
+    if let Some(cost) = proto.cost
+        && cost > 0.0
+        && let Some(mut resource) = resource
+    {
+        resource.spend(cost);
+    }
+
+    let ability = proto.id.clone();
+    if proto.cast_time <= 0.0 {
+        commands.queue(FinishCast {
+            unit,
+            ability,
+            target,
+        });
+        return;
+    }
+
+    // Casting stops whatever the unit was doing, and keeps it standing still
+    cancel_current_order(&mut commands, unit, &state, &state_protos);
+    state.add_state(State::new(cast_state(&ability)), &state_protos);
+    commands
+        .entity(unit)
+        .remove::<(MovementTarget, CurrentPath)>()
+        .insert(Casting {
+            ability,
+            target,
+            remaining: proto.cast_time,
+        });
+}
+
+fn tick_casts(
+    mut casters: Query<(Entity, &mut Casting, &mut StateList)>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (unit, mut casting, mut state) in &mut casters {
+        casting.remaining -= time.delta_secs();
+        if casting.remaining > 0.0 {
+            continue;
+        }
+
+        state.remove_state(&cast_state(&casting.ability));
+        commands.entity(unit).remove::<Casting>();
+        commands.queue(FinishCast {
+            unit,
+            ability: casting.ability.clone(),
+            target: casting.target,
+        });
+    }
+}
+
+/// Calls the ability's `on_cast` with the caster and what it was aimed at
+struct FinishCast {
+    unit: Entity,
+    ability: String,
+    target: AbilityTarget,
+}
+
+impl Command for FinishCast {
+    fn apply(self, world: &mut World) {
+        let Ok((proto, _)) = world.resource::<Protos<AbilityProto>>().get(&self.ability) else {
+            return;
+        };
+        let Some(on_cast) = proto.on_cast else {
+            return;
+        };
+        if world.get_entity(self.unit).is_err() {
+            return;
+        }
+
+        let lua = world.resource::<LuaCtx>().0.clone();
+        let target = match self.target {
+            AbilityTarget::None => Ok(LuaValue::Nil),
+            // Units that died during the cast are missed
+            AbilityTarget::Unit(target) => match world.resource::<UnitMap>().get(&target) {
+                Some(entity) => UnitProxy { entity }.into_lua(&lua),
+                None => return,
+            },
+            AbilityTarget::Point(pos) => W(pos.0).into_lua(&lua),
+            AbilityTarget::Direction(direction) => direction_into_lua(&lua, direction),
+        };
+
+        lua.with_world(world, |_| {
+            let caster = UnitProxy { entity: self.unit };
+            if let Err(e) = target.and_then(|target| on_cast.call::<()>((caster, target))) {
+                error!("Lua error while casting {}: {e}", self.ability);
+            }
+        });
+    }
+}
+
+/// Directions go to Lua the way projectiles take them, as `{x, y, is_dir = true}`
+fn direction_into_lua(lua: &Lua, direction: Vec2) -> LuaResult<LuaValue> {
+    let table = lua.create_table()?;
+    table.set("x", direction.x)?;
+    table.set("y", direction.y)?;
+    table.set("is_dir", true)?;
+    Ok(LuaValue::Table(table))
+}
+
+/// One of the boxes along the bottom of the screen, showing an ability's key and cooldown
+#[derive(Component)]
+struct AbilityButton(usize);
+
+fn spawn_ability_bar(mut commands: Commands) {
+    commands
+        .spawn((
+            StateScoped(GameState::InGame),
+            Node {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(10.0),
+                width: Val::Percent(100.0),
+                justify_content: JustifyContent::Center,
+                column_gap: Val::Px(6.0),
+                ..default()
+            },
+            Pickable::IGNORE,
+        ))
+        .with_children(|bar| {
+            for slot in 0..ABILITY_KEYS.len() {
+                bar.spawn((
+                    AbilityButton(slot),
+                    Node {
+                        width: Val::Px(48.0),
+                        height: Val::Px(48.0),
+                        border: UiRect::all(Val::Px(1.0)),
+                        justify_content: JustifyContent::Center,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+                    BorderColor(Color::srgba(1.0, 1.0, 1.0, 0.3)),
+                    Pickable::IGNORE,
+                    children![Text::new(ABILITY_KEYS[slot])],
+                ));
+            }
+        });
+}
+
+fn update_ability_bar(
+    buttons: Query<(&AbilityButton, &mut BackgroundColor, &Children)>,
+    mut texts: Query<&mut Text>,
+    units: Query<(&ControlledByClient, &Abilities)>,
+    aim: Option<Res<AbilityAim>>,
+    players: Res<Players>,
+    my_id: Res<MyPlayerId>,
+) {
+    let abilities = my_unit(&units, &players, &my_id);
+
+    for (button, mut background, children) in buttons {
+        let slot = abilities.and_then(|abilities| abilities.0.get(button.0));
+        let key = ABILITY_KEYS[button.0];
+        let label = match slot {
+            Some(slot) if slot.cooldown > 0.0 => format!("{key}\n{:.1}", slot.cooldown),
+            Some(_) => key.to_string(),
+            None => String::new(),
+        };
+        for child in children {
+            if let Ok(mut text) = texts.get_mut(*child) {
+                text.0.clone_from(&label);
+            }
+        }
+
+        background.0 = if aim.as_ref().is_some_and(|aim| aim.0 == button.0) {
+            Color::srgba(0.3, 0.7, 1.0, 0.6)
+        } else if slot.is_some_and(|slot| slot.cooldown > 0.0) {
+            Color::srgba(0.3, 0.3, 0.3, 0.8)
+        } else {
+            Color::srgba(0.0, 0.0, 0.0, 0.6)
+        };
+    }
+}
@@ -8,9 +8,9 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     ingame::{
-        lua::{LuaCtx, LuaExt, Protos}, projectile::{SpawnProjectile, SpawnProjectileArgs}, structure::Model, targetable::{Health, Position}, unit::{
-            animation::{AnimationPlayerProxy, GltfAnimations}, movement::CurrentPath, state::{State, StateList, StateProto}, stats::StatBlock, ControlledByClient, MovementTarget, UnitId, UnitMap, UnitProxy
-        }, vision::VisibleBy
+        lua::{LuaCtx, LuaExt, Protos}, projectile::{SpawnProjectile, SpawnProjectileArgs}, replay::AppReplayExt, structure::Model, targetable::Position, unit::{
+            animation::{AnimationPlayerProxy, GltfAnimations}, damage::{DamageEvent, DamageKind, DealDamage}, movement::CurrentPath, orders::{AttackMoveTarget, HoldingPosition, OrderQueue}, state::{State, StateList, StateProto}, stats::StatBlock, ControlledByClient, MovementTarget, UnitId, UnitProxy
+        }, validation::{reject, CommandValidator, RejectedCommand}, vision::VisibleBy
     }, AppExt, Options
 };
 
@@ -22,6 +22,10 @@ pub fn plugin(app: &mut App) {
     app.register_component::<CurrentlyAutoAttacking>(ChannelDirection::ServerToClient)
         .add_map_entities();
     app.register_trigger::<SetAutoAttackTarget>(ChannelDirection::ClientToServer);
+    app.record_mapped_component::<AutoAttackTarget>()
+        .record_component::<AutoAttackTimer>()
+        .record_component::<AutoAttackType>()
+        .record_mapped_component::<CurrentlyAutoAttacking>();
 
     if app.is_server() {
         app.add_observer(on_set_auto_attack_target);
@@ -144,13 +148,14 @@ fn auto_attack(
         &Team,
         &mut AutoAttackTimer,
         Option<&CurrentlyAutoAttacking>,
+        Has<HoldingPosition>,
     )>,
     target: Query<(&Position, &VisibleBy)>,
     opt: Option<Res<Options>>,
     mut commands: Commands,
 ) {
     let is_client = opt.is_some();
-    for (e, pos, aa_target, stats, team, mut aa_timer, cur_target) in q {
+    for (e, pos, aa_target, stats, team, mut aa_timer, cur_target, holding) in q {
         let Ok((target_pos, vis)) = target.get(aa_target.0) else {
             // Target entity has been despawned
             let mut ec = commands.entity(e);
@@ -164,7 +169,7 @@ fn auto_attack(
             // is in range?
             if target_pos
                 .distance(**pos)
-                <= stats.range.base
+                <= stats.range.current()
             {
                 // let mut trans = trans_q.get_mut(e).unwrap();
                 // trans.look_at(target1, Vec3::Y);
@@ -176,13 +181,18 @@ fn auto_attack(
                     // we perform auto attack
                     commands.entity(e).insert(CurrentlyAutoAttacking {
                         target: aa_target.0,
-                        speed: stats.attack_speed.base,
+                        speed: stats.attack_speed.current(),
                     });
-                    aa_timer.set(stats.attack_speed.base.recip());
+                    aa_timer.set(stats.attack_speed.current().recip());
                 } else {
                     // we stand and wait
                 }
             } else if cur_target.is_none() || cur_target.unwrap().target != aa_target.0 {
+                if holding {
+                    // Out of reach, and we aren't allowed to go after it
+                    commands.entity(e).remove::<AutoAttackTarget>();
+                    continue;
+                }
                 // Move towards unit
                 // info!("MOVE TOWARDS UNIT");
                 commands
@@ -219,17 +229,22 @@ impl Command for PerformAutoAttack {
                     .get::<StatBlock>()
                     .unwrap()
                     .attack_a
-                    .base;
-                if let Ok(e) = world.get_entity_mut(self.target) {
-                    // We instantly apply damage
-                    ApplyDamage {
-                        source: self.source,
-                        // TODO: figure out auto attack damage scaling
-                        // For now, use base attack_a
-                        amount,
-                    }
-                    .apply(e);
-                }
+                    .current();
+                // We instantly apply damage
+                DealDamage(DamageEvent {
+                    source: Some(UnitProxy {
+                        entity: self.source,
+                    }),
+                    target: UnitProxy {
+                        entity: self.target,
+                    },
+                    // TODO: figure out auto attack damage scaling
+                    // For now, use attack_a
+                    amount,
+                    kind: DamageKind::A,
+                    tags: vec!["auto_attack".into()],
+                })
+                .apply(world);
             }
             AutoAttackType::Projectile(proto) => {
                 // We first create a projectile that then deals damage
@@ -249,22 +264,6 @@ impl Command for PerformAutoAttack {
     }
 }
 
-struct ApplyDamage {
-    source: Entity,
-    amount: f32,
-}
-
-impl EntityCommand for ApplyDamage {
-    fn apply(self, mut entity: EntityWorldMut) -> () {
-        // TODO: on-hit effects, resistances, lua callbacks
-        let Some(mut health) = entity.get_mut::<Health>() else {
-            return;
-        };
-
-        health.0 -= self.amount;
-    }
-}
-
 #[derive(Debug, Component, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AutoAttackType {
     Melee,
@@ -304,15 +303,19 @@ fn on_set_auto_attack_target(
         &mut StateList,
         Option<&CurrentlyAutoAttacking>,
     )>,
-    unit_map: Res<UnitMap>,
+    validator: CommandValidator,
     state_protos: Res<Protos<StateProto>>,
     mut commands: Commands,
 ) {
-    info!("Received set auto attack");
+    let new_target = match validator.attack_target(trigger.from, trigger.message.0) {
+        Ok(target) => target,
+        Err(rejection) => {
+            return reject(&mut commands, trigger.from, RejectedCommand::Attack, rejection);
+        }
+    };
+
     for (e, control, mut state_list, cur_target) in q {
         if control.0 == trigger.from {
-            info!("Setting auto attack target");
-            let new_target = *unit_map.map.get(&trigger.message.0).unwrap();
             if let Some(cur_target) = cur_target
                 && cur_target.target != new_target
             {
@@ -333,9 +336,10 @@ fn on_set_auto_attack_target(
                 }
                 // }
             }
-            commands.entity(e).insert(AutoAttackTarget(
-                *unit_map.map.get(&trigger.message.0).unwrap(),
-            ));
+            commands
+                .entity(e)
+                .remove::<(AttackMoveTarget, HoldingPosition)>()
+                .insert((AutoAttackTarget(new_target), OrderQueue::default()));
         }
     }
 }
@@ -0,0 +1,419 @@
+//! Dealing damage to units and structures.
+//!
+//! Damage goes through modifiers on both sides first, then the target's resistance to its kind,
+//! then the target's shield, before it comes off health. The source and target are told about
+//! the final damage afterwards, and so is every client that can see the target.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bevy::prelude::*;
+use lightyear::prelude::*;
+use lobby_common::Team;
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppExt, GameState, Players,
+    ingame::{
+        camera::{AnchorUiConfig, AnchoredUiNodes, HorizontalAnchor, VerticalAnchor},
+        lua::{LuaCtx, LuaExt, Protos},
+        map::MessageChannel,
+        targetable::Health,
+        unit::{
+            MyTeam, UnitId, UnitMap, UnitProto, UnitProtoId, UnitProxy,
+            effect::effect_damage_hooks, stats::StatBlock,
+        },
+        vision::VisibleBy,
+    },
+};
+
+/// How long damage numbers stay on screen, fading out over the whole time
+const DAMAGE_TEXT_TIME: Duration = Duration::from_millis(900);
+
+/// How far damage numbers rise while they are shown
+const DAMAGE_TEXT_RISE: f32 = 1.0;
+
+pub fn plugin(app: &mut App) {
+    app.register_trigger::<DamageDealt>(ChannelDirection::ServerToClient);
+
+    if app.is_client() {
+        app.add_observer(show_damage_text).add_systems(
+            Update,
+            float_damage_text.run_if(in_state(GameState::InGame)),
+        );
+    }
+}
+
+/// What a hit deals damage as, deciding which resistance stat reduces it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DamageKind {
+    A,
+    B,
+    C,
+    /// Not reduced by any resistance
+    Pure,
+}
+
+impl DamageKind {
+    fn resistance(self, stats: &StatBlock) -> f32 {
+        match self {
+            DamageKind::A => stats.resistance_a.current(),
+            DamageKind::B => stats.resistance_b.current(),
+            DamageKind::C => stats.resistance_c.current(),
+            DamageKind::Pure => 0.0,
+        }
+    }
+}
+
+impl FromLua for DamageKind {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        match value.to_string()?.as_str() {
+            "a" => Ok(Self::A),
+            "b" => Ok(Self::B),
+            "c" => Ok(Self::C),
+            "pure" => Ok(Self::Pure),
+            other => Err(LuaError::external(anyhow!(
+                "{other} is not a valid damage kind; must be one of a, b, c or pure"
+            ))),
+        }
+    }
+}
+
+impl IntoLua for DamageKind {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        lua.create_string(match self {
+            DamageKind::A => "a",
+            DamageKind::B => "b",
+            DamageKind::C => "c",
+            DamageKind::Pure => "pure",
+        })?
+        .into_lua(lua)
+    }
+}
+
+/// A single hit, as it makes its way through the pipeline
+#[derive(Clone)]
+pub struct DamageEvent {
+    pub source: Option<UnitProxy>,
+    pub target: UnitProxy,
+    pub amount: f32,
+    pub kind: DamageKind,
+    /// Free-form labels for Lua hooks to tell hits apart, like `auto_attack`
+    pub tags: Vec<String>,
+}
+
+from_into_lua_table!(
+    struct DamageEvent {
+        source: Option<UnitProxy>,
+        target: UnitProxy,
+        amount: f32,
+        kind: DamageKind,
+        tags: Vec<String>,
+    }
+);
+
+/// The callbacks on unit and effect protos that damage goes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DamageHook {
+    /// `modify_damage_dealt`, on the source before mitigation. Can return a new amount.
+    ModifyDealt,
+    /// `modify_damage_taken`, on the target before mitigation. Can return a new amount.
+    ModifyTaken,
+    /// `on_damage_dealt`, on the source with the final damage
+    Dealt,
+    /// `on_damage_taken`, on the target with the final damage
+    Taken,
+}
+
+/// Damage absorbed before health, only present on the server
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Shield(pub f32);
+
+/// Sent to every client that can see the target once damage has been dealt
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct DamageDealt {
+    pub source: Option<UnitId>,
+    pub target: UnitId,
+    /// What came off health
+    pub amount: f32,
+    /// What the target's shield took
+    pub absorbed: f32,
+    pub kind: DamageKind,
+    pub tags: Vec<String>,
+}
+
+/// Deals damage from outside of Lua
+pub struct DealDamage(pub DamageEvent);
+
+impl Command for DealDamage {
+    fn apply(self, world: &mut World) -> () {
+        let lua = world.resource::<LuaCtx>().0.clone();
+        lua.with_world(world, |lua| {
+            if let Err(e) = deal_damage(lua, self.0) {
+                error!("Lua error while dealing damage: {e}");
+            }
+        });
+    }
+}
+
+/// How much of a hit gets through `resistance`: half at 100, a third at 200 and so on.
+/// Negative resistance makes hits stronger instead, up to double.
+fn resistance_multiplier(resistance: f32) -> f32 {
+    if resistance >= 0.0 {
+        100.0 / (100.0 + resistance)
+    } else {
+        2.0 - 100.0 / (100.0 - resistance)
+    }
+}
+
+/// Runs `damage` through the whole pipeline
+pub fn deal_damage(lua: &Lua, mut damage: DamageEvent) -> LuaResult<()> {
+    let source = damage.source.as_ref().map(|source| source.entity);
+    let target = damage.target.entity;
+
+    if let Some(source) = source {
+        run_hooks(lua, source, DamageHook::ModifyDealt, &mut damage)?;
+    }
+    run_hooks(lua, target, DamageHook::ModifyTaken, &mut damage)?;
+
+    {
+        let mut world = lua.world();
+        let Ok(mut entity) = world.get_entity_mut(target) else {
+            return Ok(());
+        };
+        // Nothing left to hit
+        if !entity.get::<Health>().is_some_and(|health| health.0 > 0.0) {
+            return Ok(());
+        }
+
+        let resistance = entity
+            .get::<StatBlock>()
+            .map_or(0.0, |stats| damage.kind.resistance(stats));
+        damage.amount *= resistance_multiplier(resistance);
+
+        let absorbed = match entity.get_mut::<Shield>() {
+            Some(mut shield) => {
+                let absorbed = shield.0.min(damage.amount);
+                shield.0 -= absorbed;
+                absorbed
+            }
+            None => 0.0,
+        };
+        damage.amount -= absorbed;
+        entity.get_mut::<Health>().unwrap().0 -= damage.amount;
+
+        send_damage_dealt(&mut *world, &damage, absorbed);
+    }
+
+    if let Some(source) = source {
+        run_hooks(lua, source, DamageHook::Dealt, &mut damage)?;
+    }
+    run_hooks(lua, target, DamageHook::Taken, &mut damage)?;
+
+    Ok(())
+}
+
+/// Calls `hook` on the proto of `unit` and on each of its effects.
+/// Modifier hooks that return a number change the amount for the hooks after them.
+fn run_hooks(lua: &Lua, unit: Entity, hook: DamageHook, damage: &mut DamageEvent) -> LuaResult<()> {
+    let (proto_hook, effect_hooks) = {
+        let world = lua.world();
+        let proto_hook = world.get::<UnitProtoId>(unit).and_then(|id| {
+            let protos = world.resource::<Protos<UnitProto>>();
+            let (proto, _) = protos.get_cached(&id.0).ok()?;
+            proto.damage_hook(hook)
+        });
+        (proto_hook, effect_damage_hooks(&world, unit, hook))
+    };
+
+    let modifies = matches!(hook, DamageHook::ModifyDealt | DamageHook::ModifyTaken);
+    let proxy = UnitProxy { entity: unit };
+
+    if let Some(function) = proto_hook
+        && let Some(amount) = function.call::<Option<f32>>((proxy.clone(), damage.clone()))?
+        && modifies
+    {
+        damage.amount = amount.max(0.0);
+    }
+    for (function, effect) in effect_hooks {
+        if let Some(amount) =
+            function.call::<Option<f32>>((proxy.clone(), effect, damage.clone()))?
+            && modifies
+        {
+            damage.amount = amount.max(0.0);
+        }
+    }
+    Ok(())
+}
+
+fn send_damage_dealt(world: &mut World, damage: &DamageEvent, absorbed: f32) {
+    let Some(&target) = world.get::<UnitId>(damage.target.entity) else {
+        return;
+    };
+    let source = damage
+        .source
+        .as_ref()
+        .and_then(|source| world.get::<UnitId>(source.entity))
+        .copied();
+    let Some(visible_by) = world.get::<VisibleBy>(damage.target.entity) else {
+        return;
+    };
+    let clients = world
+        .resource::<Players>()
+        .players
+        .values()
+        .filter(|player| visible_by.0.contains(&player.team))
+        .map(|player| player.client_id)
+        .collect();
+
+    world.server_trigger::<MessageChannel>(
+        DamageDealt {
+            source,
+            target,
+            amount: damage.amount,
+            absorbed,
+            kind: damage.kind,
+            tags: damage.tags.clone(),
+        },
+        NetworkTarget::Only(clients),
+    );
+}
+
+pub struct DealDamageArgs {
+    target: UnitProxy,
+    amount: f32,
+    kind: DamageKind,
+    tags: Option<Vec<String>>,
+}
+
+from_into_lua_table!(
+    struct DealDamageArgs {
+        target: UnitProxy,
+        amount: f32,
+        kind: DamageKind,
+        tags: Option<Vec<String>>,
+    }
+);
+
+/// `unit:deal_damage{...}`, with the unit as the source
+pub fn deal_damage_from_lua(lua: &Lua, proxy: &UnitProxy, args: DealDamageArgs) -> LuaResult<()> {
+    if lua.is_client() {
+        return Ok(());
+    }
+
+    deal_damage(
+        lua,
+        DamageEvent {
+            source: Some(proxy.clone()),
+            target: args.target,
+            amount: args.amount.max(0.0),
+            kind: args.kind,
+            tags: args.tags.unwrap_or_default(),
+        },
+    )
+}
+
+/// `unit:add_shield(amount)`, adding to whatever shield the unit already has
+pub fn add_shield(lua: &Lua, proxy: &UnitProxy, amount: f32) -> LuaResult<()> {
+    if lua.is_client() {
+        return Ok(());
+    }
+
+    lua.world()
+        .entity_mut(proxy.entity)
+        .entry::<Shield>()
+        .or_default()
+        .get_mut()
+        .0 += amount.max(0.0);
+    Ok(())
+}
+
+/// A damage number floating up from where a hit landed
+#[derive(Component)]
+struct DamageText {
+    shown_at: Duration,
+}
+
+fn show_damage_text(
+    trigger: Trigger<FromServer<DamageDealt>>,
+    unit_map: Res<UnitMap>,
+    units: Query<(&Transform, &Team)>,
+    my_team: Option<Res<MyTeam>>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    let damage = &trigger.message;
+    let Some((trans, team)) = unit_map
+        .get(&damage.target)
+        .and_then(|target| units.get(target).ok())
+    else {
+        return;
+    };
+
+    let (text, color) = if damage.amount > 0.0 {
+        let color = match damage.kind {
+            DamageKind::A => Color::srgb(1.0, 0.55, 0.2),
+            DamageKind::B => Color::srgb(0.4, 0.6, 1.0),
+            DamageKind::C => Color::srgb(0.7, 0.4, 1.0),
+            DamageKind::Pure => Color::WHITE,
+        };
+        (format!("{:.0}", damage.amount), color)
+    } else if damage.absorbed > 0.0 {
+        (
+            format!("({:.0})", damage.absorbed),
+            Color::srgb(0.8, 0.8, 0.8),
+        )
+    } else {
+        return;
+    };
+    // Our own units getting hit stands out less than us hitting others
+    let size = if my_team.is_some_and(|my_team| my_team.0 == *team) {
+        14.0
+    } else {
+        18.0
+    };
+
+    commands.spawn((
+        StateScoped(GameState::InGame),
+        DamageText {
+            shown_at: time.elapsed(),
+        },
+        Transform::from_translation(trans.translation),
+        AnchoredUiNodes::spawn_one((
+            AnchorUiConfig::default()
+                .with_horizontal_anchoring(HorizontalAnchor::Mid)
+                .with_vertical_anchoring(VerticalAnchor::Bottom)
+                .with_offset(vec3(0.0, 1.5, 0.0)),
+            GlobalZIndex(2),
+            Pickable::IGNORE,
+            Text::new(text),
+            TextFont::from_font_size(size),
+            TextColor(color),
+        )),
+    ));
+}
+
+fn float_damage_text(
+    mut texts: Query<(Entity, &DamageText, &mut Transform, &AnchoredUiNodes)>,
+    mut colors: Query<&mut TextColor>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, text, mut trans, nodes) in &mut texts {
+        let shown_for = time.elapsed() - text.shown_at;
+        if shown_for >= DAMAGE_TEXT_TIME {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let t = shown_for.as_secs_f32() / DAMAGE_TEXT_TIME.as_secs_f32();
+        trans.translation.y +=
+            DAMAGE_TEXT_RISE * time.delta_secs() / DAMAGE_TEXT_TIME.as_secs_f32();
+        for node in nodes.iter() {
+            if let Ok(mut color) = colors.get_mut(node) {
+                color.0.set_alpha(1.0 - t);
+            }
+        }
+    }
+}
@@ -17,7 +17,8 @@ use crate::{
     ingame::{
         lua::{AppLuaExt, LuaCtx, LuaExt, Protos},
         map::MessageChannel,
-        unit::{UnitId, UnitMap, UnitProxy},
+        replay::AppReplayExt,
+        unit::{UnitId, UnitMap, UnitProxy, damage::DamageHook, stats::StatBlock},
     },
 };
 
@@ -34,6 +35,7 @@ pub fn plugin(app: &mut App) {
     app.register_trigger::<RemoveEffect>(ChannelDirection::ServerToClient);
 
     app.register_component::<CustomData>(ChannelDirection::ServerToClient);
+    app.record_component::<CustomData>();
 
     if app.is_client() {
         app.add_observer(on_effect_applied);
@@ -86,9 +88,9 @@ impl EffectList {
         self.effect_list.insert(id, effect);
     }
 
-    pub fn remove_effect(&mut self, effect_id: EffectId) {
-        self.effect_list.remove(&effect_id);
+    pub fn remove_effect(&mut self, effect_id: EffectId) -> Option<Effect> {
         self.updatable.remove(&effect_id);
+        self.effect_list.remove(&effect_id)
     }
 }
 
@@ -118,6 +120,21 @@ pub struct EffectProto {
     on_applied: Option<LuaFunction>,
     on_removed: Option<LuaFunction>,
     on_update: Option<LuaFunction>,
+    modify_damage_dealt: Option<LuaFunction>,
+    modify_damage_taken: Option<LuaFunction>,
+    on_damage_dealt: Option<LuaFunction>,
+    on_damage_taken: Option<LuaFunction>,
+}
+
+impl EffectProto {
+    fn damage_hook(&self, hook: DamageHook) -> Option<LuaFunction> {
+        match hook {
+            DamageHook::ModifyDealt => self.modify_damage_dealt.clone(),
+            DamageHook::ModifyTaken => self.modify_damage_taken.clone(),
+            DamageHook::Dealt => self.on_damage_dealt.clone(),
+            DamageHook::Taken => self.on_damage_taken.clone(),
+        }
+    }
 }
 
 proto!(
@@ -127,6 +144,10 @@ proto!(
         on_applied: Option<LuaFunction>,
         on_removed: Option<LuaFunction>,
         on_update: Option<LuaFunction>,
+        modify_damage_dealt: Option<LuaFunction>,
+        modify_damage_taken: Option<LuaFunction>,
+        on_damage_dealt: Option<LuaFunction>,
+        on_damage_taken: Option<LuaFunction>,
     }
 );
 
@@ -186,6 +207,10 @@ fn update_effects(
                         effect_id: effect.id,
                     };
                     commands.queue(move |world: &mut World| {
+                        // Something earlier this tick may have removed it already
+                        if effect.get(world).is_none() {
+                            return;
+                        }
                         let lua = world.resource::<LuaCtx>().0.clone();
                         lua.with_world(world, |_| match update.call::<()>((unit, effect)) {
                             Ok(()) => {}
@@ -270,43 +295,155 @@ impl Command for ApplyEffectCommand {
     }
 }
 
+pub struct RemoveEffectCommand {
+    pub entity: Entity,
+    pub effect_id: EffectId,
+}
+
+impl Command for RemoveEffectCommand {
+    fn apply(self, world: &mut World) {
+        let Ok(mut entity) = world.get_entity_mut(self.entity) else {
+            return;
+        };
+        let Some(mut effect_list) = entity.get_mut::<EffectList>() else {
+            return;
+        };
+        let Some(effect) = effect_list.remove_effect(self.effect_id) else {
+            return;
+        };
+
+        // Only touch the stats if the effect modified them, so they aren't replicated again
+        if entity
+            .get::<StatBlock>()
+            .is_some_and(|stats| stats.has_modifiers_from(self.effect_id))
+            && let Some(mut stats) = entity.get_mut::<StatBlock>()
+        {
+            stats.remove_modifiers_from(self.effect_id);
+        }
+
+        let unit_id = *entity.get::<UnitId>().unwrap();
+        world.server_trigger::<MessageChannel>(
+            RemoveEffect {
+                unit_id,
+                effect_id: self.effect_id,
+            },
+            NetworkTarget::All,
+        );
+
+        let on_removed = world
+            .resource::<Protos<EffectProto>>()
+            .get_cached(&effect.proto)
+            .ok()
+            .and_then(|(proto, _)| proto.on_removed.clone());
+        if let Some(on_removed) = on_removed {
+            let unit = UnitProxy {
+                entity: self.entity,
+            };
+            let proxy = EffectProxy {
+                entity: self.entity,
+                effect_id: self.effect_id,
+            };
+            // Queued, as this is usually applied from Lua, which holds the world until it returns
+            world.commands().queue(move |world: &mut World| {
+                let lua = world.resource::<LuaCtx>().0.clone();
+                lua.with_world(world, |_| match on_removed.call::<()>((unit, proxy)) {
+                    Ok(()) => {}
+                    Err(e) => {
+                        error!("Lua error during effect {} removal: {}", effect.proto, e);
+                    }
+                });
+            });
+        }
+    }
+}
+
+/// The `hook` of every effect on `entity` that has one, along with the effect
+pub fn effect_damage_hooks(
+    world: &World,
+    entity: Entity,
+    hook: DamageHook,
+) -> Vec<(LuaFunction, EffectProxy)> {
+    let Some(effect_list) = world.get::<EffectList>(entity) else {
+        return vec![];
+    };
+    let protos = world.resource::<Protos<EffectProto>>();
+
+    effect_list
+        .effect_list
+        .values()
+        .filter_map(|effect| {
+            let (proto, _) = protos.get_cached(&effect.proto).ok()?;
+            let proxy = EffectProxy {
+                entity,
+                effect_id: effect.id,
+            };
+            Some((proto.damage_hook(hook)?, proxy))
+        })
+        .collect()
+}
+
+#[derive(Clone, FromLua)]
 pub struct EffectProxy {
     entity: Entity,
     effect_id: EffectId,
 }
 
+impl EffectProxy {
+    pub fn effect_id(&self) -> EffectId {
+        self.effect_id
+    }
+
+    /// The effect, unless it or its unit is gone.
+    /// Hooks and updates queued before an effect was removed can still run after it.
+    fn get<'w>(&self, world: &'w World) -> Option<&'w Effect> {
+        world
+            .get::<EffectList>(self.entity)?
+            .effect_list
+            .get(&self.effect_id)
+    }
+}
+
 impl LuaUserData for EffectProxy {
     fn add_fields<F: LuaUserDataFields<Self>>(fields: &mut F) {}
 
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
         methods.add_method("get_custom_data", |lua, s, ()| {
-            let world = lua.world();
-
-            let custom_data = world
-                .entity(s.entity)
-                .get::<EffectList>()
-                .unwrap()
-                .effect_list
-                .get(&s.effect_id)
-                .unwrap()
-                .data
-                .clone()
-                .into_lua(lua);
+            // Removed effects have no data left
+            let custom_data = s
+                .get(&lua.world())
+                .map(|effect| effect.data.clone())
+                .unwrap_or_default();
 
             Ok(custom_data)
         });
 
+        methods.add_method("remove", |lua, s, ()| {
+            if lua.is_client() {
+                return Ok(());
+            }
+
+            let mut world = lua.world();
+            RemoveEffectCommand {
+                entity: s.entity,
+                effect_id: s.effect_id,
+            }
+            .apply(&mut world);
+
+            Ok(())
+        });
+
         methods.add_method("set_custom_data", |lua, s, data: CustomData| {
             let mut world = lua.world();
 
-            world
-                .entity_mut(s.entity)
-                .get_mut::<EffectList>()
-                .unwrap()
-                .effect_list
-                .get_mut(&s.effect_id)
-                .unwrap()
-                .data = data;
+            let effect = world
+                .get_mut::<EffectList>(s.entity)
+                .and_then(|list| list.into_inner().effect_list.get_mut(&s.effect_id));
+            let Some(effect) = effect else {
+                return Err(LuaError::external(anyhow::anyhow!(
+                    "Effect has been removed"
+                )));
+            };
+            effect.data = data;
 
             Ok(())
         });
@@ -0,0 +1,379 @@
+//! Champion experience and levels.
+//!
+//! When a unit dies, the enemy champions near it split the `xp_reward` of its proto. Champions
+//! that gain a level get the per-level growth of their base stats, and their proto's
+//! `on_level_up` is called. The player controlling the champion is told about it.
+
+use std::time::Duration;
+
+use anyhow::anyhow;
+use bevy::{color::palettes, prelude::*};
+use lightyear::prelude::*;
+use lobby_common::Team;
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppExt, GameState, Players,
+    ingame::{
+        lua::{LuaCtx, LuaExt, Protos},
+        map::MessageChannel,
+        replay::AppReplayExt,
+        targetable::{Health, Position},
+        unit::{
+            ControlledByClient, Unit, UnitId, UnitProto, UnitProtoId, UnitProxy, stats::StatBlock,
+        },
+    },
+    main_ui::lobby_list::MyPlayerId,
+};
+
+/// Champions can't level up past this
+pub const MAX_LEVEL: u32 = 18;
+
+/// How close a champion has to be to a dying enemy to get a share of its experience
+const XP_SHARE_RANGE: f32 = 8.0;
+
+/// How long the level up message stays on screen
+const LEVEL_UP_MESSAGE_TIME: Duration = Duration::from_secs(2);
+
+pub fn plugin(app: &mut App) {
+    app.register_component::<Experience>(ChannelDirection::ServerToClient);
+    app.record_component::<Experience>();
+    app.register_trigger::<LeveledUp>(ChannelDirection::ServerToClient);
+
+    if app.is_client() {
+        app.add_observer(show_level_up)
+            .add_systems(OnEnter(GameState::InGame), spawn_level_display)
+            .add_systems(
+                Update,
+                (
+                    fade_level_up_messages,
+                    update_level_display
+                        .run_if(resource_exists::<Players>.and(resource_exists::<MyPlayerId>)),
+                )
+                    .run_if(in_state(GameState::InGame)),
+            );
+    } else {
+        app.add_observer(share_experience);
+    }
+}
+
+/// A champion's level, and how far it is into it
+#[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Experience {
+    pub level: u32,
+    /// Experience gained since the last level up
+    pub xp: f32,
+}
+
+impl Default for Experience {
+    fn default() -> Self {
+        Self { level: 1, xp: 0.0 }
+    }
+}
+
+impl Experience {
+    /// How much experience it takes to get from `level` to the next one
+    pub fn needed_for_next(level: u32) -> f32 {
+        100.0 + 50.0 * (level - 1) as f32
+    }
+}
+
+/// Sent to the client controlling a champion when it gains a level
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct LeveledUp {
+    pub unit: UnitId,
+    pub level: u32,
+}
+
+/// Splits the experience of a unit that died between the enemy champions around it
+fn share_experience(
+    trigger: Trigger<OnRemove, Unit>,
+    units: Query<(&Position, &Team, &Health, &UnitProtoId)>,
+    champions: Query<(Entity, &Position, &Team, &Health), With<Experience>>,
+    protos: Res<Protos<UnitProto>>,
+    mut commands: Commands,
+) {
+    // Units also get despawned when the game ends
+    let Ok((pos, team, health, proto_id)) = units.get(trigger.target()) else {
+        return;
+    };
+    if health.0 > 0.0 {
+        return;
+    }
+    let Some(reward) = protos
+        .get_cached(&proto_id.0)
+        .ok()
+        .and_then(|(proto, _)| proto.xp_reward)
+    else {
+        return;
+    };
+
+    let nearby: Vec<Entity> = champions
+        .iter()
+        .filter(|(_, champion_pos, champion_team, champion_health)| {
+            *champion_team != team
+                && champion_health.0 > 0.0
+                && champion_pos.distance(**pos) <= XP_SHARE_RANGE
+        })
+        .map(|(champion, ..)| champion)
+        .collect();
+
+    let share = reward / nearby.len().max(1) as f32;
+    for champion in nearby {
+        commands.queue(GainExperience {
+            unit: champion,
+            amount: share,
+        });
+    }
+}
+
+/// Adds experience to a champion, levelling it up as many times as that takes it
+pub struct GainExperience {
+    pub unit: Entity,
+    pub amount: f32,
+}
+
+impl Command for GainExperience {
+    fn apply(self, world: &mut World) {
+        let levels = gain_experience(world, self.unit, self.amount);
+        if levels.is_empty() {
+            return;
+        }
+
+        let Some(proto_id) = world.get::<UnitProtoId>(self.unit) else {
+            return;
+        };
+        let Ok((proto, _)) = world.resource::<Protos<UnitProto>>().get(&proto_id.0) else {
+            return;
+        };
+
+        for &level in &levels {
+            level_up_stats(world, self.unit, &proto);
+            send_leveled_up(world, self.unit, level);
+        }
+
+        if let Some(on_level_up) = proto.on_level_up {
+            let lua = world.resource::<LuaCtx>().0.clone();
+            lua.with_world(world, |_| {
+                for &level in &levels {
+                    if let Err(e) = on_level_up.call::<()>((UnitProxy { entity: self.unit }, level))
+                    {
+                        error!("Lua error in on_level_up: {e}");
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// Adds `amount` to the unit's experience, returning the levels it reached
+fn gain_experience(world: &mut World, unit: Entity, amount: f32) -> Vec<u32> {
+    let Some(mut experience) = world.get_mut::<Experience>(unit) else {
+        return vec![];
+    };
+    if experience.level >= MAX_LEVEL {
+        return vec![];
+    }
+
+    let mut levels = vec![];
+    experience.xp += amount.max(0.0);
+    while experience.level < MAX_LEVEL
+        && experience.xp >= Experience::needed_for_next(experience.level)
+    {
+        experience.xp -= Experience::needed_for_next(experience.level);
+        experience.level += 1;
+        levels.push(experience.level);
+    }
+    if experience.level >= MAX_LEVEL {
+        experience.xp = 0.0;
+    }
+    levels
+}
+
+/// Grows the unit's stats by a level, healing it by however much its max health went up
+fn level_up_stats(world: &mut World, unit: Entity, proto: &UnitProto) {
+    let Some(mut stats) = world.get_mut::<StatBlock>(unit) else {
+        return;
+    };
+    let max_health = stats.max_health.current();
+    stats.level_up(&proto.base_stats);
+    let gained = stats.max_health.current() - max_health;
+
+    if let Some(mut health) = world.get_mut::<Health>(unit)
+        && gained > 0.0
+    {
+        health.0 += gained;
+    }
+}
+
+fn send_leveled_up(world: &mut World, unit: Entity, level: u32) {
+    let (Some(&unit), Some(controller)) = (
+        world.get::<UnitId>(unit),
+        world.get::<ControlledByClient>(unit),
+    ) else {
+        return;
+    };
+    let target = NetworkTarget::Single(controller.0);
+    world.server_trigger::<MessageChannel>(LeveledUp { unit, level }, target);
+}
+
+/// `unit:get_level()`, nil for units that don't level up
+pub fn get_level(lua: &Lua, proxy: &UnitProxy, _: ()) -> LuaResult<Option<u32>> {
+    Ok(lua
+        .world()
+        .get::<Experience>(proxy.entity)
+        .map(|experience| experience.level))
+}
+
+/// `unit:add_experience(amount)`, for experience that doesn't come from kills
+pub fn add_experience(lua: &Lua, proxy: &UnitProxy, amount: f32) -> LuaResult<()> {
+    if lua.is_client() {
+        return Ok(());
+    }
+    if lua.world().get::<Experience>(proxy.entity).is_none() {
+        return Err(LuaError::external(anyhow!(
+            "Only champions gain experience"
+        )));
+    }
+
+    // Queued, so on_level_up isn't called from inside whatever Lua is running now
+    lua.world().commands().queue(GainExperience {
+        unit: proxy.entity,
+        amount,
+    });
+    Ok(())
+}
+
+#[derive(Component)]
+struct LevelUpMessage {
+    shown_at: Duration,
+}
+
+fn show_level_up(
+    trigger: Trigger<FromServer<LeveledUp>>,
+    messages: Query<Entity, With<LevelUpMessage>>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for message in &messages {
+        commands.entity(message).despawn();
+    }
+
+    commands.spawn((
+        StateScoped(GameState::InGame),
+        LevelUpMessage {
+            shown_at: time.elapsed(),
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Percent(25.0),
+            width: Val::Percent(100.0),
+            justify_content: JustifyContent::Center,
+            ..default()
+        },
+        Pickable::IGNORE,
+        children![(
+            Text::new(format!(
+                "Level up! You are now level {}",
+                trigger.message.level
+            )),
+            TextFont::from_font_size(28.0),
+            TextColor(palettes::tailwind::AMBER_400.into()),
+        )],
+    ));
+}
+
+fn fade_level_up_messages(
+    messages: Query<(Entity, &LevelUpMessage, &Children)>,
+    mut colors: Query<&mut TextColor>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, message, children) in &messages {
+        let shown_for = time.elapsed() - message.shown_at;
+        if shown_for >= LEVEL_UP_MESSAGE_TIME {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let alpha = 1.0 - shown_for.as_secs_f32() / LEVEL_UP_MESSAGE_TIME.as_secs_f32();
+        for child in children {
+            if let Ok(mut color) = colors.get_mut(*child) {
+                color.0.set_alpha(alpha);
+            }
+        }
+    }
+}
+
+/// The local player's level, above the ability bar
+#[derive(Component)]
+struct LevelText;
+
+/// How far the local player is into their level
+#[derive(Component)]
+struct XpBarValue;
+
+fn spawn_level_display(mut commands: Commands) {
+    commands.spawn((
+        StateScoped(GameState::InGame),
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(64.0),
+            width: Val::Percent(100.0),
+            flex_direction: FlexDirection::Column,
+            align_items: AlignItems::Center,
+            row_gap: Val::Px(2.0),
+            ..default()
+        },
+        Pickable::IGNORE,
+        children![
+            (LevelText, Text::default(), TextFont::from_font_size(16.0)),
+            (
+                Node {
+                    width: Val::Px(210.0),
+                    height: Val::Px(6.0),
+                    border: UiRect::all(Val::Px(1.0)),
+                    ..default()
+                },
+                BorderColor(Color::srgba(1.0, 1.0, 1.0, 0.3)),
+                BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.6)),
+                children![(
+                    XpBarValue,
+                    Node {
+                        width: Val::Percent(0.0),
+                        height: Val::Percent(100.0),
+                        ..default()
+                    },
+                    BackgroundColor(palettes::tailwind::VIOLET_500.into()),
+                )],
+            ),
+        ],
+    ));
+}
+
+fn update_level_display(
+    units: Query<(&ControlledByClient, &Experience)>,
+    mut level_text: Single<&mut Text, With<LevelText>>,
+    mut xp_bar: Single<&mut Node, With<XpBarValue>>,
+    players: Res<Players>,
+    my_id: Res<MyPlayerId>,
+) {
+    let Some(me) = players.players.get(&my_id.0) else {
+        return;
+    };
+    let Some((_, experience)) = units
+        .iter()
+        .find(|(controller, _)| controller.0 == me.client_id)
+    else {
+        return;
+    };
+
+    level_text.0 = format!("Level {}", experience.level);
+    let progress = if experience.level >= MAX_LEVEL {
+        1.0
+    } else {
+        experience.xp / Experience::needed_for_next(experience.level)
+    };
+    xp_bar.width = Val::Percent(progress * 100.0);
+}
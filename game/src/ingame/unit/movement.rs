@@ -1,3 +1,5 @@
+use std::f32::consts::FRAC_PI_2;
+
 use bevy::prelude::*;
 use bevy_enhanced_input::prelude::*;
 use lightyear::prelude::*;
@@ -5,22 +7,33 @@ use lobby_common::Team;
 use vleue_navigator::prelude::*;
 
 use crate::AppExt;
-use crate::ingame::lua::LuaCtx;
-use crate::ingame::lua::LuaExt;
+use crate::GameState;
+use crate::Players;
+use crate::ingame::replay::AppReplayExt;
 use crate::ingame::lua::Protos;
 use crate::ingame::targetable::Facing;
 use crate::ingame::targetable::Health;
 use crate::ingame::targetable::Position;
 use crate::ingame::unit::MyTeam;
 use crate::ingame::unit::UnitId;
-use crate::ingame::unit::UnitProxy;
-use crate::ingame::unit::attack::AutoAttackTarget;
-use crate::ingame::unit::attack::CurrentlyAutoAttacking;
+use crate::ingame::unit::UnitMap;
 use crate::ingame::unit::attack::SetAutoAttackTarget;
+use crate::ingame::unit::orders::HoldUnitPosition;
+use crate::ingame::unit::orders::OrderQueue;
+use crate::ingame::unit::orders::QueueOrder;
+use crate::ingame::unit::orders::QueuedOrder;
+use crate::ingame::unit::orders::SetAttackMoveTarget;
+use crate::ingame::unit::orders::StopUnit;
+use crate::ingame::unit::orders::cancel_current_order;
+use crate::ingame::unit::orders::queueing;
 use crate::ingame::unit::state::State;
 use crate::ingame::unit::state::StateList;
 use crate::ingame::unit::state::StateProto;
 use crate::ingame::unit::stats::StatBlock;
+use crate::ingame::validation::CommandValidator;
+use crate::ingame::validation::RejectedCommand;
+use crate::ingame::validation::reject;
+use crate::main_ui::lobby_list::MyPlayerId;
 
 use super::MovementTarget;
 
@@ -37,6 +50,7 @@ use super::super::camera::MousePos;
 
 pub fn plugin(app: &mut App) {
     app.register_component::<CurrentPath>(ChannelDirection::ServerToClient);
+    app.record_component::<CurrentPath>();
 
     app.add_observer(on_movement_start);
     app.add_observer(on_movement_end);
@@ -48,8 +62,14 @@ pub fn plugin(app: &mut App) {
             .add_systems(Startup, |mut commands: Commands| {
                 commands.spawn(Actions::<UnitControlContext>::default());
             })
-            // .add_systems(Update, draw_current_path)
-            ;
+            .add_systems(
+                Update,
+                draw_current_path.run_if(
+                    in_state(GameState::InGame)
+                        .and(resource_exists::<Players>)
+                        .and(resource_exists::<MyPlayerId>),
+                ),
+            );
         // app.add_systems(Update, move_unit_along_path);
     } else {
         app.add_systems(FixedUpdate, unit_pathfinding);
@@ -66,6 +86,46 @@ pub struct UnitControlContext;
 #[input_action(output = bool)]
 pub struct MoveClick;
 
+/// Triggered on the client when the player orders their unit around,
+/// after the order has been sent to the server
+#[derive(Debug, Event, Clone)]
+pub enum OrderIssued {
+    Move(Position),
+    Attack(UnitId),
+    AttackMove(Position),
+    Stop,
+    HoldPosition,
+    /// Shift-clicked, to be carried out after everything the unit already has to do
+    Queued(QueuedOrder),
+}
+
+impl OrderIssued {
+    /// Sends the order to the server, then triggers it
+    pub fn send(self, commands: &mut Commands) {
+        match &self {
+            OrderIssued::Move(target) => {
+                commands.client_trigger::<MessageChannel>(SetUnitMovementTarget(*target));
+            }
+            OrderIssued::Attack(unit) => {
+                commands.client_trigger::<MessageChannel>(SetAutoAttackTarget(*unit));
+            }
+            OrderIssued::AttackMove(target) => {
+                commands.client_trigger::<MessageChannel>(SetAttackMoveTarget(*target));
+            }
+            OrderIssued::Stop => {
+                commands.client_trigger::<MessageChannel>(StopUnit);
+            }
+            OrderIssued::HoldPosition => {
+                commands.client_trigger::<MessageChannel>(HoldUnitPosition);
+            }
+            OrderIssued::Queued(order) => {
+                commands.client_trigger::<MessageChannel>(QueueOrder(*order));
+            }
+        }
+        commands.trigger(self);
+    }
+}
+
 pub(crate) fn bind_input(
     trigger: Trigger<Binding<UnitControlContext>>,
     mut actions: Query<&mut Actions<UnitControlContext>>,
@@ -82,47 +142,52 @@ pub(crate) fn on_move_click(
     mouse_pos: Res<MousePos>,
     q: Query<(&UnitId, &Position, &Team), With<Health>>,
     my_team: Option<Res<MyTeam>>,
+    keys: Res<ButtonInput<KeyCode>>,
     mut commands: Commands,
 ) {
     let Some(my_team) = my_team else { return };
-    // Check if we clicked on enemy
-    for (unit_id, pos, team) in q {
-        if *team != my_team.0 && pos.distance(*mouse_pos.plane_pos) <= 0.5 {
-            commands.client_trigger::<MessageChannel>(SetAutoAttackTarget(*unit_id));
-            return;
-        }
+    // Shift-clicks are queued up instead
+    if queueing(&keys) {
+        return;
+    }
+    match clicked_enemy(mouse_pos.plane_pos, q, my_team.0) {
+        Some(unit_id) => OrderIssued::Attack(unit_id).send(&mut commands),
+        None => OrderIssued::Move(mouse_pos.plane_pos).send(&mut commands),
     }
-    commands.client_trigger::<MessageChannel>(SetUnitMovementTarget(mouse_pos.plane_pos));
+}
+
+/// The enemy of `my_team` at `click`, if there is one
+pub(crate) fn clicked_enemy(
+    click: Position,
+    units: Query<(&UnitId, &Position, &Team), With<Health>>,
+    my_team: Team,
+) -> Option<UnitId> {
+    units
+        .iter()
+        .find(|(_, pos, team)| **team != my_team && pos.distance(*click) <= 0.5)
+        .map(|(unit_id, ..)| *unit_id)
 }
 
 pub(crate) fn on_set_unit_movement_target(
     event: Trigger<FromClients<SetUnitMovementTarget>>,
     // mut unit: Single<&mut MovementTarget>,
     unit: Query<(Entity, &ControlledByClient, &StateList), With<Unit>>,
+    validator: CommandValidator,
     state_protos: Res<Protos<StateProto>>,
     mut commands: Commands,
 ) {
-    // let client = event.from();
+    let target = match validator.position(event.from, event.message.0) {
+        Ok(target) => target,
+        Err(rejection) => {
+            return reject(&mut commands, event.from, RejectedCommand::Move, rejection);
+        }
+    };
 
     // We need some way to get the currently controlled unit for this player.
     for (unit, client, state) in unit {
-        if client.0 == event.from {
-            let (proto, _) = state_protos.get(&state.current_state().proto).unwrap();
-            if proto.move_cancellable {
-                commands
-                    .entity(unit)
-                    .remove::<(AutoAttackTarget, CurrentlyAutoAttacking)>()
-                    .insert(MovementTarget(event.message.0));
-
-                if let Some(on_cancel) = proto.on_move_cancel.clone() {
-                    commands.queue(move |world: &mut World| {
-                        let lua = world.resource::<LuaCtx>().0.clone();
-                        lua.with_world(world, |_| {
-                            on_cancel.call::<()>(UnitProxy { entity: unit }).unwrap();
-                        });
-                    });
-                }
-            }
+        if client.0 == event.from && cancel_current_order(&mut commands, unit, state, &state_protos)
+        {
+            commands.entity(unit).insert(MovementTarget(target));
         }
     }
 }
@@ -160,7 +225,7 @@ pub(crate) fn unit_pathfinding(
     }
 }
 
-fn get_path(
+pub(super) fn get_path(
     commands: &mut Commands<'_, '_>,
     navmesh: &NavMesh,
     e: Entity,
@@ -259,22 +324,65 @@ fn get_closest_point(navmesh: &NavMesh, end: Vec3) -> (Vec2, bool) {
 #[derive(Component, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct CurrentPath(Vec<Position>);
 
+/// Draws where the locally controlled unit is going, and the orders it has queued up after that
 pub(crate) fn draw_current_path(
-    units: Query<(&Position, &CurrentPath, Option<&Visibility>)>,
+    units: Query<(
+        &Transform,
+        &ControlledByClient,
+        Option<&CurrentPath>,
+        &OrderQueue,
+    )>,
+    targets: Query<&Position>,
+    unit_map: Res<UnitMap>,
+    players: Res<Players>,
+    my_id: Res<MyPlayerId>,
     mut gizmos: Gizmos,
 ) {
-    for (pos, path, visible) in &units {
-        if path.0.is_empty() || visible.copied() == Some(Visibility::Hidden) {
+    let Some(me) = players.players.get(&my_id.0) else {
+        return;
+    };
+
+    for (trans, controller, path, queue) in &units {
+        if controller.0 != me.client_id {
             continue;
         }
-        for [a, b] in
-            std::iter::once([*pos, *path.0.first().unwrap()]).chain(path.0.array_windows().copied())
-        {
+
+        let mut from = Position::from(trans.translation);
+        for step in path.map(|path| path.0.as_slice()).unwrap_or_default() {
             gizmos.line(
-                Vec3::from(a).with_y(0.06),
-                Vec3::from(b).with_y(0.06),
+                Vec3::from(from).with_y(0.06),
+                Vec3::from(*step).with_y(0.06),
                 Color::srgb(1.0, 0.0, 0.0),
             );
+            from = *step;
+        }
+
+        for order in &queue.0 {
+            let (to, color) = match order {
+                QueuedOrder::Move(target) => (*target, Color::srgb(0.2, 0.9, 0.3)),
+                QueuedOrder::AttackMove(target) => (*target, Color::srgb(1.0, 0.6, 0.1)),
+                QueuedOrder::Attack(target) => {
+                    // Targets we can't see can't be drawn
+                    let Some(pos) = unit_map.get(target).and_then(|e| targets.get(e).ok()) else {
+                        continue;
+                    };
+                    (*pos, Color::srgb(1.0, 0.0, 0.0))
+                }
+            };
+            gizmos.line(
+                Vec3::from(from).with_y(0.06),
+                Vec3::from(to).with_y(0.06),
+                color,
+            );
+            gizmos.circle(
+                Isometry3d::new(
+                    Vec3::from(to).with_y(0.06),
+                    Quat::from_rotation_x(FRAC_PI_2),
+                ),
+                0.2,
+                color,
+            );
+            from = to;
         }
     }
 }
@@ -291,30 +399,42 @@ pub(crate) fn move_unit_along_path(
     mut commands: Commands,
 ) {
     for (e, mut pos, mut facing, mut path, stats) in &mut units {
-        let speed = stats.move_speed.base;
-        let mut travel_dist = time.delta_secs() * speed;
-
-        while travel_dist > 0.0001 {
-            if let Some(next_step) = path.0.first() {
-                let dir = next_step.0 - pos.0;
-                facing.set_if_neq(Facing::from(dir));
-
-                // let pos = trans.translation;
-                let newpos = pos.move_towards(**next_step, travel_dist);
-                let travelled_dist = pos.distance(newpos);
+        if path.0.is_empty() {
+            commands.entity(e).remove::<CurrentPath>();
+            continue;
+        }
 
-                pos.0 = newpos;
-                if *pos == *next_step {
-                    path.0.remove(0);
-                }
+        let travel_dist = time.delta_secs() * stats.move_speed.current();
+        if let Some(new_facing) = step_along_path(&mut pos, &mut path.0, travel_dist) {
+            facing.set_if_neq(new_facing);
+        }
+    }
+}
 
-                travel_dist -= travelled_dist;
-            } else {
-                commands.entity(e).remove::<CurrentPath>();
-                break;
-            }
+/// Moves `pos` up to `travel_dist` along `path`, removing the steps it reaches.
+/// Returns the direction it last moved in, if it moved at all.
+pub(super) fn step_along_path(
+    pos: &mut Position,
+    path: &mut Vec<Position>,
+    mut travel_dist: f32,
+) -> Option<Facing> {
+    let mut facing = None;
+    while travel_dist > 0.0001
+        && let Some(next_step) = path.first()
+    {
+        facing = Some(Facing::from(next_step.0 - pos.0));
+
+        let newpos = pos.move_towards(**next_step, travel_dist);
+        let travelled_dist = pos.distance(newpos);
+
+        pos.0 = newpos;
+        if *pos == *next_step {
+            path.remove(0);
         }
+
+        travel_dist -= travelled_dist;
     }
+    facing
 }
 
 fn on_movement_start(
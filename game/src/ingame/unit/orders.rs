@@ -0,0 +1,496 @@
+//! Orders beyond moving and attacking a unit: attack-move, stop and hold position, and queueing
+//! orders up with shift to be carried out one after the other.
+
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy_enhanced_input::prelude::*;
+use lightyear::prelude::*;
+use lobby_common::Team;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppExt,
+    ingame::{
+        camera::MousePos,
+        lua::{LuaCtx, Protos},
+        map::MessageChannel,
+        targetable::{Health, Position},
+        unit::{
+            ControlledByClient, MovementTarget, MyTeam, UnitId, UnitMap, UnitProxy,
+            attack::{AutoAttackTarget, CurrentlyAutoAttacking},
+            movement::{
+                CurrentPath, OrderIssued, UnitControlContext, clicked_enemy, unit_pathfinding,
+            },
+            state::{StateList, StateProto},
+            stats::StatBlock,
+        },
+        validation::{CommandValidator, RejectedCommand, Rejection, reject},
+        vision::VisibleBy,
+    },
+};
+
+/// How close to its destination an attack-moving unit has to get to be done
+const ATTACK_MOVE_ARRIVE_DISTANCE: f32 = 0.1;
+
+/// How many orders a unit can have waiting in its queue
+const MAX_QUEUED_ORDERS: usize = 16;
+
+pub fn plugin(app: &mut App) {
+    app.register_trigger::<SetAttackMoveTarget>(ChannelDirection::ClientToServer);
+    app.register_trigger::<StopUnit>(ChannelDirection::ClientToServer);
+    app.register_trigger::<HoldUnitPosition>(ChannelDirection::ClientToServer);
+    app.register_trigger::<QueueOrder>(ChannelDirection::ClientToServer);
+    app.register_trigger::<OrderQueueChanged>(ChannelDirection::ServerToClient);
+
+    if app.is_client() {
+        app.add_observer(bind_input)
+            .add_observer(on_attack_move_key)
+            .add_observer(on_stop_key)
+            .add_observer(on_hold_key)
+            .add_observer(on_queue_click)
+            .add_observer(on_order_queue_changed);
+    } else {
+        app.add_observer(on_set_attack_move_target)
+            .add_observer(on_stop_unit)
+            .add_observer(on_hold_unit_position)
+            .add_observer(on_queue_order)
+            .add_systems(
+                FixedUpdate,
+                (
+                    acquire_targets,
+                    attack_move,
+                    start_queued_orders,
+                    send_order_queues,
+                )
+                    .chain()
+                    .after(unit_pathfinding),
+            );
+    }
+}
+
+/// Message for clients to walk their unit to a point, attacking enemies met along the way
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct SetAttackMoveTarget(pub Position);
+
+/// Message for clients to make their unit stop moving and attacking
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct StopUnit;
+
+/// Message for clients to make their unit stand still, attacking only what is in range
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct HoldUnitPosition;
+
+/// Where an attack-moving unit is headed once nothing is left to attack
+#[derive(Component, Clone, PartialEq)]
+pub struct AttackMoveTarget(pub Position);
+
+/// The unit attacks enemies in range but never moves to reach them
+#[derive(Component, Clone, PartialEq)]
+pub struct HoldingPosition;
+
+/// An order waiting in a unit's [`OrderQueue`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum QueuedOrder {
+    Move(Position),
+    Attack(UnitId),
+    AttackMove(Position),
+}
+
+/// Orders the unit carries out one after the other, once it is done with what it is doing.
+/// Giving the unit an order without queueing it empties the queue.
+#[derive(Component, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderQueue(pub VecDeque<QueuedOrder>);
+
+impl OrderQueue {
+    /// Adds `order` to the end of the queue, unless it is full
+    #[must_use]
+    pub fn push(&mut self, order: QueuedOrder) -> bool {
+        if self.0.len() >= MAX_QUEUED_ORDERS {
+            return false;
+        }
+        self.0.push_back(order);
+        true
+    }
+}
+
+/// Message for clients to add an order to the end of their unit's queue
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct QueueOrder(pub QueuedOrder);
+
+/// Sent to the client controlling a unit whenever the unit's queue changes
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct OrderQueueChanged {
+    pub unit: UnitId,
+    pub orders: VecDeque<QueuedOrder>,
+}
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct AttackMoveKey;
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct StopKey;
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct HoldKey;
+
+#[derive(Debug, InputAction)]
+#[input_action(output = bool)]
+pub struct QueueClick;
+
+fn bind_input(
+    trigger: Trigger<Binding<UnitControlContext>>,
+    mut actions: Query<&mut Actions<UnitControlContext>>,
+) {
+    let mut actions = actions.get_mut(trigger.target()).unwrap();
+    actions
+        .bind::<AttackMoveKey>()
+        .to(KeyCode::KeyA)
+        .with_conditions(Press::default());
+    actions
+        .bind::<StopKey>()
+        .to(KeyCode::KeyS)
+        .with_conditions(Press::default());
+    actions
+        .bind::<HoldKey>()
+        .to(KeyCode::KeyH)
+        .with_conditions(Press::default());
+    actions
+        .bind::<QueueClick>()
+        .to(MouseButton::Right.with_mod_keys(ModKeys::SHIFT))
+        .with_conditions(Press::default());
+}
+
+/// Whether orders should go to the end of the queue instead of replacing the current one
+pub(crate) fn queueing(keys: &ButtonInput<KeyCode>) -> bool {
+    keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
+}
+
+/// Attack-moves to wherever the mouse is
+fn on_attack_move_key(
+    _trigger: Trigger<Fired<AttackMoveKey>>,
+    mouse_pos: Res<MousePos>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut commands: Commands,
+) {
+    if queueing(&keys) {
+        OrderIssued::Queued(QueuedOrder::AttackMove(mouse_pos.plane_pos)).send(&mut commands);
+    } else {
+        OrderIssued::AttackMove(mouse_pos.plane_pos).send(&mut commands);
+    }
+}
+
+/// Queues an attack on the enemy under the mouse, or a move to wherever the mouse is
+fn on_queue_click(
+    _trigger: Trigger<Fired<QueueClick>>,
+    mouse_pos: Res<MousePos>,
+    units: Query<(&UnitId, &Position, &Team), With<Health>>,
+    my_team: Option<Res<MyTeam>>,
+    mut commands: Commands,
+) {
+    let Some(my_team) = my_team else { return };
+    let order = match clicked_enemy(mouse_pos.plane_pos, units, my_team.0) {
+        Some(unit_id) => QueuedOrder::Attack(unit_id),
+        None => QueuedOrder::Move(mouse_pos.plane_pos),
+    };
+    OrderIssued::Queued(order).send(&mut commands);
+}
+
+fn on_order_queue_changed(
+    trigger: Trigger<FromServer<OrderQueueChanged>>,
+    unit_map: Res<UnitMap>,
+    mut queues: Query<&mut OrderQueue>,
+) {
+    let event = &trigger.message;
+    if let Some(unit) = unit_map.get(&event.unit)
+        && let Ok(mut queue) = queues.get_mut(unit)
+    {
+        queue.0 = event.orders.clone();
+    }
+}
+
+fn on_stop_key(_trigger: Trigger<Fired<StopKey>>, mut commands: Commands) {
+    OrderIssued::Stop.send(&mut commands);
+}
+
+fn on_hold_key(_trigger: Trigger<Fired<HoldKey>>, mut commands: Commands) {
+    OrderIssued::HoldPosition.send(&mut commands);
+}
+
+/// Stops whatever `unit` is doing to make way for a new order, if its current state allows it.
+/// Returns whether it did.
+pub(crate) fn cancel_current_order(
+    commands: &mut Commands,
+    unit: Entity,
+    state: &StateList,
+    state_protos: &Protos<StateProto>,
+) -> bool {
+    let (proto, _) = state_protos.get(&state.current_state().proto).unwrap();
+    if !proto.move_cancellable {
+        return false;
+    }
+
+    commands
+        .entity(unit)
+        .remove::<(
+            AutoAttackTarget,
+            CurrentlyAutoAttacking,
+            AttackMoveTarget,
+            HoldingPosition,
+        )>()
+        .insert(OrderQueue::default());
+
+    if let Some(on_cancel) = proto.on_move_cancel.clone() {
+        commands.queue(move |world: &mut World| {
+            let lua = world.resource::<LuaCtx>().0.clone();
+            lua.with_world(world, |_| {
+                on_cancel.call::<()>(UnitProxy { entity: unit }).unwrap();
+            });
+        });
+    }
+    true
+}
+
+fn on_set_attack_move_target(
+    trigger: Trigger<FromClients<SetAttackMoveTarget>>,
+    states: Query<&StateList>,
+    validator: CommandValidator,
+    state_protos: Res<Protos<StateProto>>,
+    mut commands: Commands,
+) {
+    let checked = validator
+        .controlled_unit(trigger.from)
+        .and_then(|(unit, _)| {
+            let target = validator.position(trigger.from, trigger.message.0)?;
+            Ok((unit, target))
+        });
+    let (unit, target) = match checked {
+        Ok(checked) => checked,
+        Err(rejection) => {
+            return reject(&mut commands, trigger.from, RejectedCommand::AttackMove, rejection);
+        }
+    };
+
+    if let Ok(state) = states.get(unit)
+        && cancel_current_order(&mut commands, unit, state, &state_protos)
+    {
+        commands
+            .entity(unit)
+            .insert((AttackMoveTarget(target), MovementTarget(target)));
+    }
+}
+
+fn on_stop_unit(
+    trigger: Trigger<FromClients<StopUnit>>,
+    states: Query<&StateList>,
+    validator: CommandValidator,
+    state_protos: Res<Protos<StateProto>>,
+    mut commands: Commands,
+) {
+    let unit = match validator.controlled_unit(trigger.from) {
+        Ok((unit, _)) => unit,
+        Err(rejection) => {
+            return reject(&mut commands, trigger.from, RejectedCommand::Stop, rejection);
+        }
+    };
+
+    if let Ok(state) = states.get(unit)
+        && cancel_current_order(&mut commands, unit, state, &state_protos)
+    {
+        commands
+            .entity(unit)
+            .remove::<(MovementTarget, CurrentPath)>();
+    }
+}
+
+fn on_hold_unit_position(
+    trigger: Trigger<FromClients<HoldUnitPosition>>,
+    states: Query<&StateList>,
+    validator: CommandValidator,
+    state_protos: Res<Protos<StateProto>>,
+    mut commands: Commands,
+) {
+    let unit = match validator.controlled_unit(trigger.from) {
+        Ok((unit, _)) => unit,
+        Err(rejection) => {
+            return reject(&mut commands, trigger.from, RejectedCommand::HoldPosition, rejection);
+        }
+    };
+
+    if let Ok(state) = states.get(unit)
+        && cancel_current_order(&mut commands, unit, state, &state_protos)
+    {
+        commands
+            .entity(unit)
+            .remove::<(MovementTarget, CurrentPath)>()
+            .insert(HoldingPosition);
+    }
+}
+
+/// Attack-moving and holding units go for the closest enemy they can see in range
+fn acquire_targets(
+    units: Query<
+        (Entity, &Position, &Team, &StatBlock),
+        (
+            Or<(With<AttackMoveTarget>, With<HoldingPosition>)>,
+            Without<AutoAttackTarget>,
+        ),
+    >,
+    targets: Query<(Entity, &Position, &Team, &VisibleBy, &Health)>,
+    mut commands: Commands,
+) {
+    for (unit, pos, team, stats) in &units {
+        let closest = targets
+            .iter()
+            .filter(|(_, _, target_team, visible_by, health)| {
+                *target_team != team && visible_by.0.contains(team) && health.0 > 0.0
+            })
+            .map(|(target, target_pos, ..)| (target, target_pos.distance(**pos)))
+            .filter(|(_, distance)| *distance <= stats.range.current())
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        if let Some((target, _)) = closest {
+            commands.entity(unit).insert(AutoAttackTarget(target));
+        }
+    }
+}
+
+/// Heads back to the attack-move destination whenever there is nothing to attack,
+/// until it is reached or can't be gotten any closer to
+fn attack_move(
+    units: Query<
+        (
+            Entity,
+            &Position,
+            &AttackMoveTarget,
+            Option<&MovementTarget>,
+            Has<CurrentPath>,
+        ),
+        Without<AutoAttackTarget>,
+    >,
+    mut commands: Commands,
+) {
+    for (unit, pos, destination, movement_target, has_path) in &units {
+        let heading_there = movement_target.is_some_and(|target| target.0 == destination.0);
+        if pos.distance(*destination.0) <= ATTACK_MOVE_ARRIVE_DISTANCE
+            || (heading_there && !has_path)
+        {
+            commands.entity(unit).remove::<AttackMoveTarget>();
+        } else if !heading_there {
+            commands.entity(unit).insert(MovementTarget(destination.0));
+        }
+    }
+}
+
+fn on_queue_order(
+    trigger: Trigger<FromClients<QueueOrder>>,
+    mut queues: Query<&mut OrderQueue>,
+    validator: CommandValidator,
+    mut commands: Commands,
+) {
+    let from = trigger.from;
+    let checked = validator.controlled_unit(from).and_then(|(unit, _)| {
+        let order = match trigger.message.0 {
+            QueuedOrder::Move(target) => QueuedOrder::Move(validator.position(from, target)?),
+            QueuedOrder::Attack(target) => {
+                validator.attack_target(from, target)?;
+                QueuedOrder::Attack(target)
+            }
+            QueuedOrder::AttackMove(target) => {
+                QueuedOrder::AttackMove(validator.position(from, target)?)
+            }
+        };
+        Ok((unit, order))
+    });
+    let (unit, order) = match checked {
+        Ok(checked) => checked,
+        Err(rejection) => {
+            return reject(&mut commands, from, RejectedCommand::QueueOrder, rejection);
+        }
+    };
+
+    let Ok(mut queue) = queues.get_mut(unit) else {
+        return;
+    };
+    if !queue.push(order) {
+        reject(
+            &mut commands,
+            from,
+            RejectedCommand::QueueOrder,
+            Rejection::OrderQueueFull,
+        );
+    }
+}
+
+/// Starts the next queued order of every unit that has nothing left to do
+fn start_queued_orders(
+    mut units: Query<
+        (
+            Entity,
+            &mut OrderQueue,
+            &StateList,
+            Option<Ref<MovementTarget>>,
+        ),
+        (
+            Without<CurrentPath>,
+            Without<AutoAttackTarget>,
+            Without<AttackMoveTarget>,
+        ),
+    >,
+    unit_map: Res<UnitMap>,
+    state_protos: Res<Protos<StateProto>>,
+    mut commands: Commands,
+) {
+    for (unit, mut queue, state, movement_target) in &mut units {
+        // A movement target set since we last looked hasn't had its path found yet
+        if queue.0.is_empty() || movement_target.is_some_and(|target| target.is_changed()) {
+            continue;
+        }
+        if !state_protos
+            .get(&state.current_state().proto)
+            .is_some_and(|(proto, _)| proto.move_cancellable)
+        {
+            continue;
+        }
+
+        let Some(order) = queue.0.pop_front() else {
+            continue;
+        };
+        let mut unit = commands.entity(unit);
+        unit.remove::<HoldingPosition>();
+        match order {
+            QueuedOrder::Move(target) => {
+                unit.insert(MovementTarget(target));
+            }
+            // A target that is gone by now is skipped
+            QueuedOrder::Attack(target) => {
+                if let Some(target) = unit_map.get(&target) {
+                    unit.insert(AutoAttackTarget(target));
+                }
+            }
+            QueuedOrder::AttackMove(target) => {
+                unit.insert((AttackMoveTarget(target), MovementTarget(target)));
+            }
+        }
+    }
+}
+
+/// Lets the controlling client know what its unit has queued up, to draw it
+fn send_order_queues(
+    units: Query<(&UnitId, &OrderQueue, &ControlledByClient), Changed<OrderQueue>>,
+    mut commands: Commands,
+) {
+    for (unit_id, queue, controller) in &units {
+        let message = OrderQueueChanged {
+            unit: *unit_id,
+            orders: queue.0.clone(),
+        };
+        let target = NetworkTarget::Single(controller.0);
+        commands.queue(move |world: &mut World| {
+            world.server_trigger::<MessageChannel>(message, target);
+        });
+    }
+}
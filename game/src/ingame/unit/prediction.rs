@@ -0,0 +1,252 @@
+//! Client-side prediction of the locally controlled unit's movement.
+//!
+//! Movement orders are carried out locally straight away, with the same pathfinding and movement
+//! as the server. Positions from the server are compared to where we predicted the unit to be a
+//! round trip earlier, and any difference is corrected smoothly.
+//! Every other unit is left to interpolation in [`crate::ingame::targetable`].
+
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::prelude::*;
+use lightyear::prelude::*;
+use vleue_navigator::prelude::*;
+
+use crate::{
+    AppExt, GameState, Options, Players,
+    ingame::{
+        lua::Protos,
+        replay::watching_replay,
+        targetable::{Facing, Position},
+        unit::{
+            ControlledByClient,
+            movement::{OrderIssued, get_path, step_along_path},
+            state::{StateList, StateProto},
+            stats::StatBlock,
+        },
+        validation::{CommandRejected, RejectedCommand},
+    },
+    main_ui::lobby_list::MyPlayerId,
+};
+
+/// How quickly the drawn position catches up after a correction, per second
+const CORRECTION_RATE: f32 = 10.0;
+
+/// Differences smaller than this are not worth correcting
+const CORRECTION_THRESHOLD: f32 = 0.05;
+
+pub fn plugin(app: &mut App) {
+    if app.is_server() || app.world().resource::<Options>().no_prediction {
+        return;
+    }
+
+    app.add_observer(predict_order)
+        .add_observer(cancel_rejected_order);
+    app.add_systems(
+        Update,
+        (
+            mark_predicted_unit.run_if(
+                resource_exists::<Players>
+                    .and(resource_exists::<MyPlayerId>)
+                    .and(not(watching_replay)),
+            ),
+            reconcile_prediction,
+            predict_movement,
+        )
+            .chain()
+            .run_if(in_state(GameState::InGame)),
+    );
+}
+
+/// The locally controlled unit, when its movement is predicted
+#[derive(Component)]
+pub struct Predicted {
+    /// Where we think the unit is right now
+    position: Position,
+    /// The path we are predicting the unit to follow
+    path: Vec<Position>,
+    /// Predicted positions the server has not yet confirmed, oldest first
+    history: VecDeque<(Duration, Position)>,
+    /// How far the drawn unit is from `position`, shrinking every frame
+    error: Vec2,
+}
+
+impl Predicted {
+    fn new(position: Position) -> Self {
+        Self {
+            position,
+            path: vec![],
+            history: VecDeque::new(),
+            error: Vec2::ZERO,
+        }
+    }
+
+    /// Moves the prediction by `offset` without moving the unit on screen
+    fn correct(&mut self, offset: Vec2) {
+        self.position.0 += offset;
+        self.error -= offset;
+        for (_, position) in &mut self.history {
+            position.0 += offset;
+        }
+    }
+}
+
+fn mark_predicted_unit(
+    units: Query<(Entity, &ControlledByClient, &Position), Without<Predicted>>,
+    players: Res<Players>,
+    my_id: Res<MyPlayerId>,
+    mut commands: Commands,
+) {
+    let Some(me) = players.players.get(&my_id.0) else {
+        return;
+    };
+
+    for (entity, controller, position) in &units {
+        if controller.0 == me.client_id {
+            commands.entity(entity).insert(Predicted::new(*position));
+        }
+    }
+}
+
+fn predict_order(
+    trigger: Trigger<OrderIssued>,
+    mut units: Query<(Entity, &mut Predicted, &StateList)>,
+    navmesh: Query<&ManagedNavMesh>,
+    assets: Res<Assets<NavMesh>>,
+    state_protos: Res<Protos<StateProto>>,
+    mut commands: Commands,
+) {
+    let Ok((entity, mut predicted, state)) = units.single_mut() else {
+        return;
+    };
+
+    match trigger.event() {
+        // Attack-moving walks like a move order until the server finds something to attack
+        OrderIssued::Move(target) | OrderIssued::AttackMove(target) => {
+            // Mirrors the server, which ignores the order unless the current state allows it
+            let Some((proto, _)) = state_protos.get(&state.current_state().proto) else {
+                return;
+            };
+            if !proto.move_cancellable {
+                return;
+            }
+
+            let Some(navmesh) = navmesh
+                .single()
+                .ok()
+                .and_then(|navmesh| assets.get(navmesh))
+            else {
+                return;
+            };
+            if let Some(path) = get_path(
+                &mut commands,
+                navmesh,
+                entity,
+                predicted.position,
+                None,
+                *target,
+            ) {
+                predicted.path = path;
+            }
+        }
+        // Chasing the target is up to the server
+        OrderIssued::Attack(_) => predicted.path.clear(),
+        OrderIssued::Stop | OrderIssued::HoldPosition => {
+            if state_protos
+                .get(&state.current_state().proto)
+                .is_some_and(|(proto, _)| proto.move_cancellable)
+            {
+                predicted.path.clear();
+            }
+        }
+        // The server starts queued orders when it gets to them
+        OrderIssued::Queued(_) => {}
+    }
+}
+
+/// The server won't carry out what we predicted, so stop and let it correct us
+fn cancel_rejected_order(
+    trigger: Trigger<FromServer<CommandRejected>>,
+    mut units: Query<&mut Predicted>,
+) {
+    // Only movement and attack orders are predicted
+    if !matches!(
+        trigger.message.command,
+        RejectedCommand::Move | RejectedCommand::Attack | RejectedCommand::AttackMove
+    ) {
+        return;
+    }
+    for mut predicted in &mut units {
+        predicted.path.clear();
+    }
+}
+
+fn reconcile_prediction(
+    mut units: Query<(&Position, &mut Predicted), Changed<Position>>,
+    connection: Res<ClientConnectionManager>,
+    time: Res<Time>,
+) {
+    // Positions we get now are the server acting on what we did about a round trip ago
+    let confirmed_until = time.elapsed().saturating_sub(connection.rtt());
+
+    for (server_position, mut predicted) in &mut units {
+        let predicted = &mut *predicted;
+
+        let offset = match predicted.history.front() {
+            // Not predicting anything, so just follow the server
+            None => server_position.0 - predicted.position.0,
+            // The server hasn't seen our order yet
+            Some(&(at, _)) if at > confirmed_until => continue,
+            Some(_) => {
+                while predicted
+                    .history
+                    .get(1)
+                    .is_some_and(|&(at, _)| at <= confirmed_until)
+                {
+                    predicted.history.pop_front();
+                }
+                server_position.0 - predicted.history[0].1.0
+            }
+        };
+
+        if offset.length() > CORRECTION_THRESHOLD {
+            predicted.correct(offset);
+        }
+
+        // Once the server has caught up with the end of the path, go back to following it
+        if predicted.path.is_empty()
+            && predicted
+                .history
+                .back()
+                .is_some_and(|&(at, _)| at <= confirmed_until)
+        {
+            predicted.history.clear();
+        }
+    }
+}
+
+fn predict_movement(
+    mut units: Query<(&mut Predicted, &StatBlock, &Facing, &mut Transform)>,
+    time: Res<Time>,
+) {
+    for (mut predicted, stats, facing, mut trans) in &mut units {
+        let predicted = &mut *predicted;
+
+        if !predicted.path.is_empty() {
+            let travel_dist = time.delta_secs() * stats.move_speed.current();
+            if let Some(new_facing) =
+                step_along_path(&mut predicted.position, &mut predicted.path, travel_dist)
+            {
+                trans.look_to(Vec3::from(new_facing), Vec3::Y);
+            }
+            predicted
+                .history
+                .push_back((time.elapsed(), predicted.position));
+        } else if predicted.history.is_empty() {
+            trans.look_to(Vec3::from(*facing), Vec3::Y);
+        }
+
+        predicted.error *= (-CORRECTION_RATE * time.delta_secs()).exp();
+        trans.translation = Vec3::from(Position(predicted.position.0 + predicted.error))
+            .with_y(trans.translation.y);
+    }
+}
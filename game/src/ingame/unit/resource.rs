@@ -0,0 +1,188 @@
+//! What units spend to cast abilities, like mana or energy.
+//!
+//! A unit gets a resource from the `resource` of its proto, starting out full and regenerating
+//! over time. Ability costs come out of it, and Lua can spend and restore it directly.
+
+use anyhow::anyhow;
+use bevy::{color::palettes, prelude::*};
+use lightyear::prelude::*;
+use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppExt,
+    ingame::{lua::LuaExt, replay::AppReplayExt, unit::UnitProxy},
+};
+
+pub fn plugin(app: &mut App) {
+    app.register_component::<UnitResource>(ChannelDirection::ServerToClient);
+    app.record_component::<UnitResource>();
+
+    if app.is_server() {
+        app.add_systems(FixedUpdate, regen_resources);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResourceKind {
+    Mana,
+    Energy,
+    /// Anything else a unit proto comes up with, like rage or heat
+    Custom(String),
+}
+
+impl ResourceKind {
+    fn default_color(&self) -> Srgba {
+        match self {
+            ResourceKind::Mana => palettes::tailwind::BLUE_500,
+            ResourceKind::Energy => palettes::tailwind::YELLOW_400,
+            ResourceKind::Custom(_) => palettes::tailwind::PURPLE_500,
+        }
+    }
+}
+
+impl FromLua for ResourceKind {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        Ok(match value.to_string()?.as_str() {
+            "mana" => Self::Mana,
+            "energy" => Self::Energy,
+            other => Self::Custom(other.into()),
+        })
+    }
+}
+
+impl IntoLua for ResourceKind {
+    fn into_lua(self, lua: &Lua) -> LuaResult<LuaValue> {
+        lua.create_string(match &self {
+            ResourceKind::Mana => "mana",
+            ResourceKind::Energy => "energy",
+            ResourceKind::Custom(name) => name,
+        })?
+        .into_lua(lua)
+    }
+}
+
+/// The `resource` of a unit proto
+#[derive(PartialEq)]
+pub struct ResourceProto {
+    pub kind: ResourceKind,
+    pub max: f32,
+    /// Per second
+    pub regen: f32,
+    /// Hex colour of the bar under the healthbar, picked from the kind if left out
+    pub color: Option<String>,
+}
+
+from_into_lua_table!(
+    struct ResourceProto {
+        kind: ResourceKind,
+        max: f32,
+        regen: f32,
+        color: Option<String>,
+    }
+);
+
+#[derive(Component, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnitResource {
+    pub kind: ResourceKind,
+    pub current: f32,
+    pub max: f32,
+    pub regen: f32,
+    pub color: [f32; 4],
+}
+
+impl UnitResource {
+    pub fn from_proto(proto: &ResourceProto) -> Self {
+        let color = match proto.color.as_deref().map(Srgba::hex) {
+            Some(Ok(color)) => color,
+            Some(Err(e)) => {
+                warn!("Invalid resource colour: {e}");
+                proto.kind.default_color()
+            }
+            None => proto.kind.default_color(),
+        };
+
+        Self {
+            kind: proto.kind.clone(),
+            current: proto.max,
+            max: proto.max,
+            regen: proto.regen,
+            color: color.to_f32_array(),
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        Srgba::from_f32_array(self.color).into()
+    }
+
+    /// Takes `amount` if there is that much left, returning whether it did
+    pub fn spend(&mut self, amount: f32) -> bool {
+        if self.current < amount {
+            return false;
+        }
+        self.current -= amount;
+        true
+    }
+
+    pub fn restore(&mut self, amount: f32) {
+        self.current = (self.current + amount).min(self.max);
+    }
+}
+
+fn regen_resources(resources: Query<&mut UnitResource>, time: Res<Time>) {
+    for mut resource in resources {
+        // Full resources are left alone so they don't get replicated every tick
+        if resource.current < resource.max {
+            let regen = resource.regen * time.delta_secs();
+            resource.restore(regen);
+        }
+    }
+}
+
+/// `unit:get_resource()`, nil for units without one
+pub fn get_resource(lua: &Lua, proxy: &UnitProxy, _: ()) -> LuaResult<LuaValue> {
+    let resource = lua
+        .world()
+        .entity(proxy.entity)
+        .get::<UnitResource>()
+        .cloned();
+    let Some(resource) = resource else {
+        return Ok(LuaValue::Nil);
+    };
+
+    let table = lua.create_table()?;
+    table.set("kind", resource.kind)?;
+    table.set("current", resource.current)?;
+    table.set("max", resource.max)?;
+    table.set("regen", resource.regen)?;
+    Ok(LuaValue::Table(table))
+}
+
+/// `unit:spend_resource(amount)`, spending nothing and returning false if there isn't enough
+pub fn spend_resource(lua: &Lua, proxy: &UnitProxy, amount: f32) -> LuaResult<bool> {
+    if lua.is_client() {
+        return Ok(false);
+    }
+
+    let mut world = lua.world();
+    let mut entity = world.entity_mut(proxy.entity);
+    let Some(mut resource) = entity.get_mut::<UnitResource>() else {
+        return Err(LuaError::external(anyhow!("Unit has no resource")));
+    };
+    Ok(resource.spend(amount.max(0.0)))
+}
+
+/// `unit:restore_resource(amount)`, up to the unit's max
+pub fn restore_resource(lua: &Lua, proxy: &UnitProxy, amount: f32) -> LuaResult<()> {
+    if lua.is_client() {
+        return Ok(());
+    }
+
+    let mut world = lua.world();
+    let mut entity = world.entity_mut(proxy.entity);
+    let Some(mut resource) = entity.get_mut::<UnitResource>() else {
+        return Err(LuaError::external(anyhow!("Unit has no resource")));
+    };
+    resource.restore(amount.max(0.0));
+    Ok(())
+}
@@ -6,6 +6,7 @@ use crate::{
     AppExt,
     ingame::{
         lua::{LuaCtx, LuaExt, Protos},
+        replay::AppReplayExt,
         structure::Model,
         unit::{
             UnitProxy,
@@ -17,6 +18,7 @@ use crate::{
 
 pub fn plugin(app: &mut App) {
     app.register_component::<StateList>(ChannelDirection::ServerToClient);
+    app.record_component::<StateList>();
 
     let mut protos: Protos<StateProto> = Protos::from_world(app.world_mut());
     let lua = app.world().resource::<LuaCtx>().0.clone();
@@ -1,12 +1,33 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
 use bevy::prelude::*;
 use lightyear::prelude::{AppComponentExt, ChannelDirection};
+use mlua::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    AppExt,
+    ingame::{
+        lua::LuaExt,
+        replay::AppReplayExt,
+        unit::{
+            UnitProxy,
+            effect::{EffectId, EffectProxy},
+        },
+    },
+};
+
 pub fn plugin(app: &mut App) {
     app.register_component::<StatBlock>(ChannelDirection::ServerToClient);
+    app.record_component::<StatBlock>();
+
+    if app.is_server() {
+        app.add_systems(FixedUpdate, expire_stat_modifiers);
+    }
 }
 
-#[derive(Component, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[derive(Component, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StatBlock {
     pub max_health: Stat,
     pub move_speed: Stat,
@@ -37,17 +58,280 @@ impl From<BaseStats> for StatBlock {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+impl StatBlock {
+    pub fn stat_mut(&mut self, name: StatName) -> &mut Stat {
+        match name {
+            StatName::MaxHealth => &mut self.max_health,
+            StatName::MoveSpeed => &mut self.move_speed,
+            StatName::AttackSpeed => &mut self.attack_speed,
+            StatName::AttackA => &mut self.attack_a,
+            StatName::AttackB => &mut self.attack_b,
+            StatName::AttackC => &mut self.attack_c,
+            StatName::ResistanceA => &mut self.resistance_a,
+            StatName::ResistanceB => &mut self.resistance_b,
+            StatName::ResistanceC => &mut self.resistance_c,
+            StatName::Range => &mut self.range,
+        }
+    }
+
+    /// Raises the base of every stat by its growth in `base_stats`, for a unit gaining a level
+    pub fn level_up(&mut self, base_stats: &BaseStats) {
+        for (name, growth) in base_stats.growth() {
+            let stat = self.stat_mut(name);
+            stat.base += growth;
+            stat.recalculate();
+        }
+    }
+
+    /// Drops every modifier that came from `effect`, for when the effect is removed
+    pub fn remove_modifiers_from(&mut self, effect: EffectId) {
+        for stat in self.iter_mut() {
+            stat.remove_modifiers_from(effect);
+        }
+    }
+
+    pub fn has_modifiers_from(&self, effect: EffectId) -> bool {
+        self.iter().any(|stat| {
+            stat.modifiers
+                .iter()
+                .any(|modifier| modifier.source == Some(effect))
+        })
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Stat> {
+        [
+            &self.max_health,
+            &self.move_speed,
+            &self.attack_speed,
+            &self.attack_a,
+            &self.attack_b,
+            &self.attack_c,
+            &self.resistance_a,
+            &self.resistance_b,
+            &self.resistance_c,
+            &self.range,
+        ]
+        .into_iter()
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Stat> {
+        [
+            &mut self.max_health,
+            &mut self.move_speed,
+            &mut self.attack_speed,
+            &mut self.attack_a,
+            &mut self.attack_b,
+            &mut self.attack_c,
+            &mut self.resistance_a,
+            &mut self.resistance_b,
+            &mut self.resistance_c,
+            &mut self.range,
+        ]
+        .into_iter()
+    }
+}
+
+/// A single stat: its base value and whatever is buffing or debuffing it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Stat {
     pub base: f32,
+    modifiers: Vec<StatModifier>,
+    /// `base` with every modifier applied
+    current: f32,
 }
 
 impl Stat {
     pub fn new(base: f32) -> Self {
-        Self { base }
+        Self {
+            base,
+            modifiers: vec![],
+            current: base,
+        }
+    }
+
+    /// The value to use for the stat, with every modifier applied
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    /// Adds `modifier`, replacing the one of the same kind from the same source if there is one,
+    /// so effects can refresh their modifiers without stacking them
+    pub fn add_modifier(&mut self, modifier: StatModifier) {
+        if let Some(source) = modifier.source
+            && let Some(existing) = self
+                .modifiers
+                .iter_mut()
+                .find(|existing| existing.source == Some(source) && existing.kind == modifier.kind)
+        {
+            *existing = modifier;
+        } else {
+            self.modifiers.push(modifier);
+        }
+        self.recalculate();
+    }
+
+    fn remove_modifiers_from(&mut self, effect: EffectId) {
+        self.modifiers
+            .retain(|modifier| modifier.source != Some(effect));
+        self.recalculate();
+    }
+
+    fn has_expired(&self, now: Duration) -> bool {
+        self.modifiers
+            .iter()
+            .any(|modifier| modifier.expires_at.is_some_and(|at| at <= now))
+    }
+
+    fn remove_expired(&mut self, now: Duration) {
+        self.modifiers
+            .retain(|modifier| modifier.expires_at.is_none_or(|at| at > now));
+        self.recalculate();
+    }
+
+    /// Flat modifiers are added to the base first, then scaled by the sum of the percent-additive
+    /// ones, then by each percent-multiplicative one in turn
+    fn recalculate(&mut self) {
+        let mut flat = 0.0;
+        let mut additive = 1.0;
+        let mut multiplicative = 1.0;
+        for modifier in &self.modifiers {
+            match modifier.kind {
+                StatModifierKind::Flat => flat += modifier.value,
+                StatModifierKind::PercentAdditive => additive += modifier.value,
+                StatModifierKind::PercentMultiplicative => multiplicative *= 1.0 + modifier.value,
+            }
+        }
+        self.current = ((self.base + flat) * additive.max(0.0) * multiplicative).max(0.0);
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatModifier {
+    pub kind: StatModifierKind,
+    /// Added as is for flat modifiers, and as a fraction of the stat, so 0.2 for +20%, otherwise
+    pub value: f32,
+    /// The effect the modifier came from
+    pub source: Option<EffectId>,
+    /// When the modifier runs out, in server time
+    pub expires_at: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StatModifierKind {
+    Flat,
+    PercentAdditive,
+    PercentMultiplicative,
+}
+
+impl FromLua for StatModifierKind {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        match value.to_string()?.as_str() {
+            "flat" => Ok(Self::Flat),
+            "percent_additive" => Ok(Self::PercentAdditive),
+            "percent_multiplicative" => Ok(Self::PercentMultiplicative),
+            other => Err(LuaError::external(anyhow!(
+                "{other} is not a valid stat modifier kind; \
+                 must be one of flat, percent_additive or percent_multiplicative"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatName {
+    MaxHealth,
+    MoveSpeed,
+    AttackSpeed,
+    AttackA,
+    AttackB,
+    AttackC,
+    ResistanceA,
+    ResistanceB,
+    ResistanceC,
+    Range,
+}
+
+impl FromLua for StatName {
+    fn from_lua(value: LuaValue, _lua: &Lua) -> LuaResult<Self> {
+        match value.to_string()?.as_str() {
+            "max_health" => Ok(Self::MaxHealth),
+            "move_speed" => Ok(Self::MoveSpeed),
+            "attack_speed" => Ok(Self::AttackSpeed),
+            "attack_a" => Ok(Self::AttackA),
+            "attack_b" => Ok(Self::AttackB),
+            "attack_c" => Ok(Self::AttackC),
+            "resistance_a" => Ok(Self::ResistanceA),
+            "resistance_b" => Ok(Self::ResistanceB),
+            "resistance_c" => Ok(Self::ResistanceC),
+            "range" => Ok(Self::Range),
+            other => Err(LuaError::external(anyhow!("{other} is not a valid stat"))),
+        }
+    }
+}
+
+fn expire_stat_modifiers(mut units: Query<&mut StatBlock>, time: Res<Time>) {
+    let now = time.elapsed();
+    for mut stats in &mut units {
+        // Only touch the ones that changed, so the rest aren't replicated again
+        if stats.iter().any(|stat| stat.has_expired(now)) {
+            for stat in stats.iter_mut() {
+                stat.remove_expired(now);
+            }
+        }
+    }
+}
+
+pub struct AddStatModifierArgs {
+    stat: StatName,
+    kind: StatModifierKind,
+    value: f32,
+    /// In seconds, or forever if left out or `math.huge`
+    duration: Option<f32>,
+    source: Option<EffectProxy>,
+}
+
+from_lua_table!(
+    struct AddStatModifierArgs {
+        stat: StatName,
+        kind: StatModifierKind,
+        value: f32,
+        duration: Option<f32>,
+        source: Option<EffectProxy>,
+    }
+);
+
+/// `unit:add_stat_modifier{...}`
+pub fn add_stat_modifier(lua: &Lua, proxy: &UnitProxy, args: AddStatModifierArgs) -> LuaResult<()> {
+    if lua.is_client() {
+        return Ok(());
+    }
+
+    // math.huge lasts forever, like leaving the duration out
+    let duration = match args.duration {
+        Some(duration) if duration != f32::INFINITY => Some(
+            Duration::try_from_secs_f32(duration.clamp(0.0, f32::MAX)).map_err(|e| {
+                LuaError::external(anyhow!("Invalid stat modifier duration {duration}: {e}"))
+            })?,
+        ),
+        _ => None,
+    };
+
+    let mut world = lua.world();
+    let now = world.resource::<Time>().elapsed();
+    let modifier = StatModifier {
+        kind: args.kind,
+        value: args.value,
+        source: args.source.map(|source| source.effect_id()),
+        // Too far out to count in server time is as good as forever
+        expires_at: duration.and_then(|duration| now.checked_add(duration)),
+    };
+    let Some(mut stats) = world.get_mut::<StatBlock>(proxy.entity) else {
+        return Err(LuaError::external(anyhow!("Only units have stats")));
+    };
+    stats.stat_mut(args.stat).add_modifier(modifier);
+    Ok(())
+}
+
 #[derive(Clone, PartialEq)]
 pub struct BaseStats {
     pub max_health: f32,
@@ -60,6 +344,32 @@ pub struct BaseStats {
     pub resistance_b: f32,
     pub resistance_c: f32,
     pub range: f32,
+    pub max_health_per_level: Option<f32>,
+    pub attack_speed_per_level: Option<f32>,
+    pub attack_a_per_level: Option<f32>,
+    pub attack_b_per_level: Option<f32>,
+    pub attack_c_per_level: Option<f32>,
+    pub resistance_a_per_level: Option<f32>,
+    pub resistance_b_per_level: Option<f32>,
+    pub resistance_c_per_level: Option<f32>,
+}
+
+impl BaseStats {
+    /// How much each stat goes up per level, leaving out the ones that don't
+    fn growth(&self) -> impl Iterator<Item = (StatName, f32)> {
+        [
+            (StatName::MaxHealth, self.max_health_per_level),
+            (StatName::AttackSpeed, self.attack_speed_per_level),
+            (StatName::AttackA, self.attack_a_per_level),
+            (StatName::AttackB, self.attack_b_per_level),
+            (StatName::AttackC, self.attack_c_per_level),
+            (StatName::ResistanceA, self.resistance_a_per_level),
+            (StatName::ResistanceB, self.resistance_b_per_level),
+            (StatName::ResistanceC, self.resistance_c_per_level),
+        ]
+        .into_iter()
+        .filter_map(|(name, growth)| Some((name, growth?)))
+    }
 }
 
 from_into_lua_table!(
@@ -74,5 +384,13 @@ from_into_lua_table!(
         resistance_b: f32,
         resistance_c: f32,
         range: f32,
+        max_health_per_level: Option<f32>,
+        attack_speed_per_level: Option<f32>,
+        attack_a_per_level: Option<f32>,
+        attack_b_per_level: Option<f32>,
+        attack_c_per_level: Option<f32>,
+        resistance_a_per_level: Option<f32>,
+        resistance_b_per_level: Option<f32>,
+        resistance_c_per_level: Option<f32>,
     }
 );
@@ -0,0 +1,268 @@
+//! Checks on the commands clients send, before the server acts on them.
+//!
+//! Commands that fail a check are dropped, and the client is told why with [`CommandRejected`].
+
+use std::{fmt, time::Duration};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use lightyear::prelude::*;
+use lobby_common::Team;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppExt, GameState, InGamePlayerInfo, Players,
+    ingame::{
+        map::MessageChannel,
+        navmesh::map_bounds,
+        targetable::{Health, Position},
+        unit::{ControlledByClient, Unit, UnitId, UnitMap},
+        vision::VisibleBy,
+    },
+};
+
+/// How long the reason for a rejected command stays on screen
+const REJECTION_MESSAGE_TIME: Duration = Duration::from_secs(2);
+
+pub fn plugin(app: &mut App) {
+    app.register_trigger::<CommandRejected>(ChannelDirection::ServerToClient);
+
+    if app.is_client() {
+        app.add_observer(show_rejection).add_systems(
+            Update,
+            fade_rejection_messages.run_if(in_state(GameState::InGame)),
+        );
+    }
+}
+
+/// Why a command was not carried out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rejection {
+    /// The client isn't one of the players in this game
+    NotAPlayer,
+    /// The client has no unit to command
+    NoControlledUnit,
+    /// The target doesn't exist, or is already gone
+    UnknownTarget,
+    AlliedTarget,
+    /// The target is hidden from the client's team
+    TargetNotVisible,
+    /// The target can't be attacked, or has no health left
+    TargetNotTargetable,
+    /// Not a real number
+    InvalidPosition,
+    OutsideMap,
+    /// Too many chat messages in a short time
+    TooManyMessages,
+    /// Pinged again too soon after the last ping
+    PingOnCooldown,
+    /// The unit already has as many orders queued up as it can take
+    OrderQueueFull,
+    /// The unit has no ability in that slot
+    UnknownAbility,
+    AbilityOnCooldown,
+    /// The unit is in the middle of something it can't be interrupted in
+    Busy,
+    OutOfRange,
+    /// The unit can't pay for the ability
+    NotEnoughResource,
+    /// The target is not the kind the ability aims at
+    WrongAbilityTarget,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::NotAPlayer => "You are not playing in this game",
+            Rejection::NoControlledUnit => "You have no unit to command",
+            Rejection::UnknownTarget => "That target is gone",
+            Rejection::AlliedTarget => "You can't attack allies",
+            Rejection::TargetNotVisible => "You can't see that target",
+            Rejection::TargetNotTargetable => "That target can't be attacked",
+            Rejection::InvalidPosition => "That isn't a valid position",
+            Rejection::OutsideMap => "That is outside the map",
+            Rejection::TooManyMessages => "You are sending messages too quickly",
+            Rejection::PingOnCooldown => "You are pinging too quickly",
+            Rejection::OrderQueueFull => "Your unit can't queue up any more orders",
+            Rejection::UnknownAbility => "Your unit has no such ability",
+            Rejection::AbilityOnCooldown => "That ability isn't ready yet",
+            Rejection::Busy => "Your unit is busy",
+            Rejection::OutOfRange => "That is out of range",
+            Rejection::NotEnoughResource => "Your unit can't afford that",
+            Rejection::WrongAbilityTarget => "That ability can't be aimed like that",
+        })
+    }
+}
+
+/// Which of a client's commands was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RejectedCommand {
+    Move,
+    Attack,
+    AttackMove,
+    Stop,
+    HoldPosition,
+    QueueOrder,
+    CastAbility,
+    Ping,
+    ChatMessage,
+    LoadState,
+}
+
+/// Sent to a client when the server drops one of its commands
+#[derive(Debug, Event, Clone, Serialize, Deserialize)]
+pub struct CommandRejected {
+    pub command: RejectedCommand,
+    pub reason: Rejection,
+}
+
+/// Everything needed to check commands from clients
+#[derive(SystemParam)]
+pub struct CommandValidator<'w, 's> {
+    players: Res<'w, Players>,
+    unit_map: Res<'w, UnitMap>,
+    controlled: Query<'w, 's, (Entity, &'static ControlledByClient, &'static Team), With<Unit>>,
+    targets: Query<'w, 's, (&'static Team, &'static VisibleBy, Option<&'static Health>)>,
+}
+
+impl CommandValidator<'_, '_> {
+    pub fn player(&self, client: ClientId) -> Result<&InGamePlayerInfo, Rejection> {
+        self.players
+            .players
+            .values()
+            .find(|player| player.client_id == client)
+            .ok_or(Rejection::NotAPlayer)
+    }
+
+    /// The unit `client` commands, and its team
+    pub fn controlled_unit(&self, client: ClientId) -> Result<(Entity, Team), Rejection> {
+        self.player(client)?;
+        self.controlled
+            .iter()
+            .find(|(_, controller, _)| controller.0 == client)
+            .map(|(entity, _, team)| (entity, *team))
+            .ok_or(Rejection::NoControlledUnit)
+    }
+
+    /// The unit `client` wants to attack, if it is allowed to
+    pub fn attack_target(&self, client: ClientId, target: UnitId) -> Result<Entity, Rejection> {
+        let (_, team) = self.controlled_unit(client)?;
+        let entity = self.unit_map.get(&target).ok_or(Rejection::UnknownTarget)?;
+        let (target_team, visible_by, health) = self
+            .targets
+            .get(entity)
+            .map_err(|_| Rejection::UnknownTarget)?;
+
+        if *target_team == team {
+            Err(Rejection::AlliedTarget)
+        } else if !visible_by.0.contains(&team) {
+            Err(Rejection::TargetNotVisible)
+        } else if !health.is_some_and(|health| health.0 > 0.0) {
+            Err(Rejection::TargetNotTargetable)
+        } else {
+            Ok(entity)
+        }
+    }
+
+    /// A unit of either team that `client` can see and that is still alive
+    pub fn visible_unit(&self, client: ClientId, target: UnitId) -> Result<Entity, Rejection> {
+        let (_, team) = self.controlled_unit(client)?;
+        let entity = self.unit_map.get(&target).ok_or(Rejection::UnknownTarget)?;
+        let (_, visible_by, health) = self
+            .targets
+            .get(entity)
+            .map_err(|_| Rejection::UnknownTarget)?;
+
+        if !visible_by.0.contains(&team) {
+            Err(Rejection::TargetNotVisible)
+        } else if !health.is_some_and(|health| health.0 > 0.0) {
+            Err(Rejection::TargetNotTargetable)
+        } else {
+            Ok(entity)
+        }
+    }
+
+    /// A position for `client` to move to, if it is on the map
+    pub fn position(&self, client: ClientId, position: Position) -> Result<Position, Rejection> {
+        self.controlled_unit(client)?;
+        if !position.is_finite() {
+            Err(Rejection::InvalidPosition)
+        } else if !map_bounds().contains(position.0) {
+            Err(Rejection::OutsideMap)
+        } else {
+            Ok(position)
+        }
+    }
+}
+
+/// Tells `client` which of its commands was dropped, and why
+pub fn reject(
+    commands: &mut Commands,
+    client: ClientId,
+    command: RejectedCommand,
+    reason: Rejection,
+) {
+    debug!("Rejected {command:?} from {client:?}: {reason:?}");
+    commands.queue(move |world: &mut World| {
+        world.server_trigger::<MessageChannel>(
+            CommandRejected { command, reason },
+            NetworkTarget::Single(client),
+        );
+    });
+}
+
+#[derive(Component)]
+struct RejectionMessage {
+    shown_at: Duration,
+}
+
+fn show_rejection(
+    trigger: Trigger<FromServer<CommandRejected>>,
+    messages: Query<Entity, With<RejectionMessage>>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for message in &messages {
+        commands.entity(message).despawn();
+    }
+
+    commands.spawn((
+        StateScoped(GameState::InGame),
+        RejectionMessage {
+            shown_at: time.elapsed(),
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Percent(20.0),
+            width: Val::Percent(100.0),
+            justify_content: JustifyContent::Center,
+            ..default()
+        },
+        Pickable::IGNORE,
+        children![(
+            Text::new(trigger.message.reason.to_string()),
+            TextColor(Color::srgb(1.0, 0.4, 0.3)),
+        )],
+    ));
+}
+
+fn fade_rejection_messages(
+    messages: Query<(Entity, &RejectionMessage, &Children)>,
+    mut colors: Query<&mut TextColor>,
+    time: Res<Time>,
+    mut commands: Commands,
+) {
+    for (entity, message, children) in &messages {
+        let shown_for = time.elapsed() - message.shown_at;
+        if shown_for >= REJECTION_MESSAGE_TIME {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        let alpha = 1.0 - shown_for.as_secs_f32() / REJECTION_MESSAGE_TIME.as_secs_f32();
+        for child in children {
+            if let Ok(mut color) = colors.get_mut(*child) {
+                color.0.set_alpha(alpha);
+            }
+        }
+    }
+}
@@ -12,13 +12,17 @@ use bevy::{
         view::RenderLayers,
     }
 };
-use lightyear::prelude::*;
+use lightyear::prelude::{
+    server::{RoomId, RoomManager},
+    *,
+};
 use lobby_common::Team;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    AppExt, GameState,
+    AppExt, GameState, Players,
     ingame::{
+        replay::AppReplayExt,
         terrain::{C, Terrain, TerrainObject},
         unit::MyTeam,
     },
@@ -29,14 +33,17 @@ use super::targetable::Position;
 pub fn plugin(app: &mut App) {
     app.register_component::<VisibleBy>(ChannelDirection::ServerToClient);
     app.register_component::<SightRange>(ChannelDirection::ServerToClient);
+    app.record_component::<VisibleBy>()
+        .record_component::<SightRange>();
 
     if app.is_server() {
         app.add_systems(
             FixedUpdate,
-            (update_visibility, update_team_visibility)
+            (update_visibility, update_team_visibility, update_relevance)
                 .chain()
                 .run_if(in_state(GameState::InGame)),
         );
+        app.add_systems(Update, join_team_room);
 
         app.add_observer(on_sight_removed);
     } else {
@@ -68,7 +75,9 @@ pub fn plugin(app: &mut App) {
 
         app.add_systems(
             Update,
-            (render_fog_of_war, move_fow_mesh).chain().run_if(in_state(GameState::InGame)),
+            (render_fog_of_war, move_fow_mesh, show_fow_overlay)
+                .chain()
+                .run_if(in_state(GameState::InGame)),
         );
     }
 }
@@ -211,6 +220,69 @@ fn update_team_visibility(
     }
 }
 
+/// Replication for entities that only the teams that can see them should know about.
+/// Which teams those are is kept up to date by [`update_relevance`].
+pub fn replicate_to_viewers() -> ServerReplicate {
+    ServerReplicate {
+        relevance_mode: NetworkRelevanceMode::InterestManagement,
+        ..default()
+    }
+}
+
+/// Each client is in the room of its team, and each entity in the rooms of the teams that see it
+fn team_room(team: Team) -> RoomId {
+    RoomId(team.0 as u64)
+}
+
+/// The teams an entity is currently replicated to
+#[derive(Component)]
+struct ReplicatedToTeams(HashSet<Team>);
+
+fn join_team_room(
+    mut events: EventReader<ServerConnectEvent>,
+    players: Res<Players>,
+    mut rooms: ResMut<RoomManager>,
+) {
+    for event in events.read() {
+        let Some(player) = players
+            .players
+            .values()
+            .find(|player| player.client_id == event.client_id)
+        else {
+            warn!("Unknown client {:?} connected", event.client_id);
+            continue;
+        };
+        rooms.add_client(event.client_id, team_room(player.team));
+    }
+}
+
+fn update_relevance(
+    q: Query<(Entity, &Team, &VisibleBy, Option<&mut ReplicatedToTeams>), Changed<VisibleBy>>,
+    mut rooms: ResMut<RoomManager>,
+    mut commands: Commands,
+) {
+    for (entity, team, visible_by, replicated_to) in q {
+        let mut teams = visible_by.0.clone();
+        // A team always knows about its own entities
+        teams.insert(*team);
+
+        let previous = match replicated_to {
+            Some(mut replicated_to) => std::mem::replace(&mut replicated_to.0, teams.clone()),
+            None => {
+                commands.entity(entity).insert(ReplicatedToTeams(teams.clone()));
+                HashSet::new()
+            }
+        };
+
+        for &team in teams.difference(&previous) {
+            rooms.add_entity(entity, team_room(team));
+        }
+        for &team in previous.difference(&teams) {
+            rooms.remove_entity(entity, team_room(team));
+        }
+    }
+}
+
 fn aabb_collide(aabb: Aabb2d, segment: Segment2d) -> bool {
     if segment.aabb_2d(Vec2::ZERO).intersects(&aabb) {
         true
@@ -270,9 +342,17 @@ fn segments_intersection(a: Segment2d, b: Segment2d) -> (f32, f32) {
 //     ccw(a, c, d) != ccw(b, c, d) && ccw(a, b, c) != ccw(a, b, d)
 // }
 
-pub fn change_visibility(q: Query<(&Team, &VisibleBy, &mut Visibility)>, me: Res<MyTeam>) {
+/// Lets us see everything regardless of team, like when watching a replay with a free camera
+#[derive(Resource)]
+pub struct FullVision;
+
+pub fn change_visibility(
+    q: Query<(&Team, &VisibleBy, &mut Visibility)>,
+    me: Res<MyTeam>,
+    full_vision: Option<Res<FullVision>>,
+) {
     for (team, visible, mut visibility) in q {
-        if *team == me.0 || visible.0.contains(&me.0) {
+        if full_vision.is_some() || *team == me.0 || visible.0.contains(&me.0) {
             *visibility = Visibility::Inherited;
         } else {
             *visibility = Visibility::Hidden;
@@ -479,6 +559,7 @@ fn setup_fow(asset_server: Res<AssetServer>, mut commands: Commands) {
     };
     commands.spawn((
         StateScoped(GameState::InGame),
+        FogOfWarOverlay,
         Transform::from_xyz(0.0, 0.01, 0.0),
         Mesh3d(asset_server.add(square.into())),
         MeshMaterial3d(asset_server.add(mat)),
@@ -487,6 +568,20 @@ fn setup_fow(asset_server: Res<AssetServer>, mut commands: Commands) {
     ));
 }
 
+#[derive(Component)]
+struct FogOfWarOverlay;
+
+fn show_fow_overlay(
+    mut overlay: Single<&mut Visibility, With<FogOfWarOverlay>>,
+    full_vision: Option<Res<FullVision>>,
+) {
+    **overlay = if full_vision.is_some() {
+        Visibility::Hidden
+    } else {
+        Visibility::Inherited
+    };
+}
+
 #[derive(Component)]
 #[relationship(relationship_target = FoWMeshes)]
 struct FoWMeshOf(Entity);
@@ -22,6 +22,7 @@ use bevy::{
 };
 
 mod r#async;
+mod bot;
 mod ingame;
 mod main_ui;
 mod network;
@@ -31,17 +32,24 @@ mod ui;
 pub use ingame::{
     InGamePlayerInfo, Players,
     camera::PrimaryCamera,
-    network::{PROTOCOL_ID, PrivateKey, ServerOptions},
+    loading::MatchMap,
+    lobby_control::{LobbyControl, client_id, connect_candidates, direct_player_id},
+    network::{PROTOCOL_ID, PrivateKey, ServerCertificate, ServerOptions, to_hex},
     unit::Unit,
 };
+pub use bot::{BotBehaviour, BotOrder, BotRng, plugin as bot};
 pub use network::Sess;
 
 use engine_common::{ChampList, ChampionDef, ChampionId};
-use lightyear::prelude::*;
+use lightyear::{connection::netcode::PRIVATE_KEY_BYTES, prelude::*};
 pub use network::LobbySender;
 use serde::{Deserialize, Serialize};
 
-use crate::ingame::{map::MessageChannel, unit::champion::ChampionDefAsset};
+use crate::ingame::{
+    map::MessageChannel,
+    network::{LinkConditionerOptions, parse_private_key},
+    unit::champion::ChampionDefAsset,
+};
 
 pub fn client(app: &mut App) {
     app.add_plugins((
@@ -134,8 +142,20 @@ fn client_setup(mut commands: Commands) {
 pub struct Options {
     #[arg(long)]
     direct_connect: Option<SocketAddr>,
+    /// Client id to join a `--direct-connect` server with
+    #[arg(long, default_value_t = 0)]
+    client_id: u64,
+    /// Hex encoded connect token printed by a `--direct-connect` server
+    #[arg(long, requires = "direct_connect")]
+    connect_token: Option<String>,
+    /// Hex encoded key of a `--direct-connect` server, to make our own token with
+    #[arg(long, requires = "direct_connect", value_parser = parse_private_key)]
+    private_key: Option<[u8; PRIVATE_KEY_BYTES]>,
     #[arg(long)]
     connect: bool,
+    /// Lobby server to connect to with `--connect`
+    #[arg(long, default_value = "localhost")]
+    lobby_address: String,
     #[arg(long, default_value_t = LobbyMode::None)]
     lobby_mode: LobbyMode,
     #[arg(long)]
@@ -148,6 +168,32 @@ pub struct Options {
     auto_pick_first_champ: bool,
     #[arg(long)]
     auto_lock: bool,
+    /// Watches a replay file instead of connecting to a server
+    #[arg(long)]
+    replay: Option<PathBuf>,
+    /// Waits for the server before moving our unit, instead of predicting its movement
+    #[arg(long)]
+    no_prediction: bool,
+    /// How many server ticks behind other units are drawn, to smooth over late or lost updates
+    #[arg(long, default_value_t = 2.0)]
+    interpolation_ticks: f32,
+    #[command(flatten)]
+    link_conditioner: LinkConditionerOptions,
+}
+
+impl Options {
+    /// Options for a bot that connects to `lobby_address` and makes its way into a game on its own
+    pub fn bot(lobby_address: String, lobby_mode: LobbyMode, auto_start: Option<usize>) -> Self {
+        Self {
+            connect: true,
+            lobby_address,
+            lobby_mode,
+            auto_start,
+            auto_pick_first_champ: true,
+            auto_lock: true,
+            ..<Self as clap::Parser>::parse_from(["bot"])
+        }
+    }
 }
 
 #[derive(Clone, Default, clap::ValueEnum)]
@@ -1,8 +1,8 @@
 use bevy::prelude::*;
-use lobby_common::{ClientToLobby, PlayerId, Team};
+use lobby_common::{ClientToLobby, LobbyState, PlayerId, Team};
 
 use crate::{
-    ChampDefs, LobbySender, Options,
+    ChampDefs, GameState, LobbySender, Options,
     main_ui::lobby_list::MyPlayerId,
     new_ui::{
         View, ViewExt, button::ButtonView, image::ImageView, list::ListView, subtree::SubtreeView,
@@ -19,6 +19,11 @@ use super::{
 
 pub fn client(app: &mut App) {
     app.add_systems(OnEnter(LobbyMenuState::InChampSelect), setup_ui)
+        // Our game might still be running after we lost connection to it
+        .add_systems(
+            OnEnter(GameState::NotInGame),
+            setup_ui.run_if(in_state(LobbyMenuState::InChampSelect)),
+        )
         .add_observer(on_goto_champ_select)
         .add_observer(on_return_from_champ_select);
 }
@@ -57,6 +62,7 @@ fn champ_select3(
     }
 
     let lobby = &lobby.0;
+    let in_game = lobby.lobby_state == LobbyState::InGame;
 
     let team_pairs = lobby.teams.chunks(2).enumerate().map(|(i, slice)| {
         slice
@@ -78,11 +84,20 @@ fn champ_select3(
                 .width(Val::Percent(100.0))
                 .scrollable(),
         )
-        .with(ButtonView::new(
-            "Lock",
-            "lock_selection",
-            send_msg(ClientToLobby::LockSelection),
-        ))
+        .with((!in_game).then(|| {
+            ButtonView::new(
+                "Lock",
+                "lock_selection",
+                send_msg(ClientToLobby::LockSelection),
+            )
+        }))
+        .with(in_game.then(|| {
+            ButtonView::new(
+                "Rejoin game",
+                "rejoin_game",
+                send_msg(ClientToLobby::RejoinGame),
+            )
+        }))
         .styled()
         .width(Val::Percent(34.0))
         .position_type(PositionType::Absolute)
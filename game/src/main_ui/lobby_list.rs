@@ -1,6 +1,5 @@
 use bevy::prelude::*;
 use engine_common::ChampionId;
-use lightyear::prelude::ConnectToken;
 use lobby_common::{
     ClientToLobby, LobbyId, LobbyInfo, LobbyShortInfo, LobbyToClient, PlayerId, PlayerInfo, Team,
 };
@@ -71,6 +70,11 @@ pub struct PlayerChangedPositions(pub PlayerId, pub PlayerId);
 #[derive(Resource)]
 pub struct MyPlayerId(pub PlayerId);
 
+/// Set when the lobby server has told us it is draining.
+/// Holds the time it will shut down, in seconds since the unix epoch.
+#[derive(Resource)]
+pub struct LobbyServerShuttingDown(pub u64);
+
 fn listen_to_lobby_server(
     options: Res<Options>,
     receiver: Option<ResMut<LobbyReceiver>>,
@@ -107,15 +111,18 @@ fn listen_to_lobby_server(
             LobbyMessage::Message(lobby_to_client) => match lobby_to_client {
                 LobbyToClient::Handshake { .. } => unreachable!(),
                 LobbyToClient::LobbyList(lobby_short_infos) => {
+                    let in_lobby_list = state
+                        .as_ref()
+                        .is_some_and(|state| *state.get() == LobbyMenuState::LobbyList);
+                    let joinable = lobby_short_infos.iter().find(|lobby| {
+                        lobby.player_count < lobby.max_player_count && lobby.game_time.is_none()
+                    });
                     if matches!(options.lobby_mode, LobbyMode::AutoJoinFirst)
-                        && lobby_short_infos.len() > 0
-                    {
-                        _ = sender
-                            .0
-                            .send(ClientToLobby::JoinLobby(lobby_short_infos[0].id));
-                    } else if let Some(ref state) = state
-                        && *state.get() == LobbyMenuState::LobbyList
+                        && in_lobby_list
+                        && let Some(lobby) = joinable
                     {
+                        _ = sender.0.send(ClientToLobby::JoinLobby(lobby.id));
+                    } else if in_lobby_list {
                         commands.run_system_cached_with(populate_lobby_list, lobby_short_infos);
                     }
                 }
@@ -155,9 +162,12 @@ fn listen_to_lobby_server(
                 LobbyToClient::PlayerLockedSelection(player_id) => {
                     commands.trigger(PlayerLockedSelection(player_id));
                 }
-                LobbyToClient::GameStarted(items) => {
-                    let token = ConnectToken::try_from_bytes(&items).unwrap();
-                    commands.queue(ConnectToGameServer(token));
+                LobbyToClient::GameStarted(candidates) => {
+                    commands.queue(ConnectToGameServer(candidates));
+                }
+                LobbyToClient::ServerShuttingDown { at } => {
+                    warn!("Lobby server is shutting down");
+                    commands.insert_resource(LobbyServerShuttingDown(at));
                 }
             },
         }
@@ -179,6 +189,7 @@ fn populate_lobby_list(lobbies: In<Vec<LobbyShortInfo>>, mut commands: Commands)
 }
 
 fn on_lobby_disconnect(_trigger: Trigger<LobbyConnectionLost>, mut commands: Commands) {
+    commands.remove_resource::<LobbyServerShuttingDown>();
     commands.set_state(ConnectionState::NotConnected);
 }
 
@@ -201,6 +212,12 @@ pub fn connected_to_lobby_server(
 
 pub fn lobby_list() -> impl View {
     ListView::new()
+        .with(SubtreeView::new(
+            "shutdown_notice",
+            IfRunner::new(shutdown_notice, |world| {
+                world.contains_resource::<LobbyServerShuttingDown>()
+            }),
+        ))
         .with(
             ListView::new()
                 .with(ButtonView::new(
@@ -231,6 +248,19 @@ pub fn lobby_list() -> impl View {
         .flex_grow(1.0)
 }
 
+fn shutdown_notice(notice: Res<LobbyServerShuttingDown>) -> Option<impl View + use<>> {
+    if !notice.is_changed() {
+        return None;
+    }
+
+    let time_of_day = notice.0 % (24 * 60 * 60);
+    Some(TextView::new(format!(
+        "The lobby server is shutting down at {:02}:{:02} UTC; no new games can be started",
+        time_of_day / (60 * 60),
+        time_of_day / 60 % 60
+    )))
+}
+
 fn lobby_list_subtree(list: Res<LobbyList>) -> Option<impl View + use<>> {
     if !list.is_changed() {
         return None;
@@ -253,6 +283,10 @@ fn lobby_list_subtree(list: Res<LobbyList>) -> Option<impl View + use<>> {
 fn lobby_list_entry(info: &LobbyShortInfo) -> impl View + use<> {
     ListView::new()
         .with(TextView::new(&info.name).styled().flex_grow(1.0))
+        .with(
+            info.game_time
+                .map(|time| TextView::new(format!("In game {}:{:02}", time / 60, time % 60))),
+        )
         .with(TextView::new(format!(
             "{}/{}",
             info.player_count, info.max_player_count
@@ -1,5 +1,8 @@
 use crate::PROTOCOL_ID;
 use crate::ingame::ConnectToGameServer;
+use crate::ingame::lobby_control::direct_player_id;
+use crate::ingame::network::from_hex;
+use crate::ingame::replay::StartReplay;
 use crate::new_ui::Widget;
 use crate::{
     GameState, LobbySender, Options,
@@ -17,10 +20,9 @@ use crate::{
         tree::{OnceRunner, UiTree},
     },
 };
-use bevy::asset::uuid::Uuid;
 use bevy::{input_focus::InputFocus, prelude::*, state::state::FreelyMutableState};
-use lightyear::prelude::ConnectToken;
-use lobby_common::{ClientToLobby, PlayerId};
+use lightyear::{connection::netcode::PRIVATE_KEY_BYTES, prelude::ConnectToken};
+use lobby_common::{ClientToLobby, ConnectCandidate};
 use lobby_list::{connected_to_lobby_server, MyPlayerId};
 
 pub mod in_champ_select;
@@ -69,16 +71,32 @@ pub fn create_ui(mut options: ResMut<Options>, mut commands: Commands) {
         UiTree::once(ui_root2),
     ));
     if let Some(addr) = options.direct_connect {
-        commands.insert_resource(MyPlayerId(PlayerId(Uuid::nil())));
-        commands.queue(ConnectToGameServer(
-            ConnectToken::build(addr, PROTOCOL_ID, 0, [0; 32])
-                .generate()
-                .unwrap(),
-        ));
+        let token = match &options.connect_token {
+            // An invalid token is reported when connecting
+            Some(token) => from_hex(token).unwrap_or_default(),
+            None => {
+                let key = options.private_key.unwrap_or([0; PRIVATE_KEY_BYTES]);
+                ConnectToken::build(addr, PROTOCOL_ID, options.client_id, key)
+                    .generate()
+                    .unwrap()
+                    .try_into_bytes()
+                    .unwrap()
+                    .to_vec()
+            }
+        };
+        commands.insert_resource(MyPlayerId(direct_player_id(options.client_id)));
+        commands.queue(ConnectToGameServer(vec![ConnectCandidate {
+            address: addr,
+            token,
+            certificate_digest: None,
+        }]));
+    }
+    if let Some(path) = options.replay.take() {
+        commands.queue(StartReplay(path));
     }
     if options.connect {
         options.connect = false;
-        commands.insert_resource(LobbyUrl("localhost".into()));
+        commands.insert_resource(LobbyUrl(options.lobby_address.clone()));
         commands.run_system_cached(connect);
     }
 }
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::SocketAddr};
 
 use engine_common::ChampionId;
 use serde::{Deserialize, Serialize};
@@ -20,7 +20,11 @@ pub enum LobbyToClient {
     ReturnFromChampSelect,
     PlayerSelectedChamp(PlayerId, ChampionId),
     PlayerLockedSelection(PlayerId),
-    GameStarted(Vec<u8>),
+    /// Every address the game server can be reached on, in the order they should be tried
+    GameStarted(Vec<ConnectCandidate>),
+    /// The lobby server is draining and will shut down at `at` (seconds since the unix epoch).
+    /// No new lobbies or games can be started after this is received.
+    ServerShuttingDown { at: u64 },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -39,6 +43,8 @@ pub enum ClientToLobby {
     GoToChampSelect,
     SelectChamp(ChampionId),
     LockSelection,
+    /// Asks for new connect candidates for the game our lobby is playing, after losing connection to it
+    RejoinGame,
     Disconnect,
 }
 
@@ -48,6 +54,8 @@ pub struct LobbyShortInfo {
     pub name: String,
     pub player_count: usize,
     pub max_player_count: usize,
+    /// How long the lobby's game has been running in seconds, if it is in game
+    pub game_time: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -121,11 +129,50 @@ pub enum LobbyToServer {
         settings: LobbySettings,
         players: Vec<PlayerGameInfo>,
     },
+    /// Ends the match and shuts down the game server
+    EndMatch,
+    /// Disconnects a player from the match, they will not be let back in
+    KickPlayer(PlayerId),
+    /// Asks for new connect candidates for a player, answered with [`ServerToLobby::PlayerTokens`]
+    IssueToken {
+        player: PlayerId,
+        is_ipv4: bool,
+        is_local: bool,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ServerToLobby {
-    PlayerTokens { tokens: HashMap<PlayerId, Vec<u8>> },
+    PlayerTokens {
+        tokens: HashMap<PlayerId, Vec<ConnectCandidate>>,
+    },
+    /// Sent periodically while the match is running
+    Status {
+        phase: MatchPhase,
+        /// Seconds since the match left the loading phase
+        game_time: u64,
+        connected_players: Vec<PlayerId>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MatchPhase {
+    Loading,
+    InProgress,
+    /// A team has won, the game server shuts down shortly
+    Ended,
+}
+
+/// One address a game server can be reached on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectCandidate {
+    pub address: SocketAddr,
+    /// Connect token for `address`, as bytes
+    pub token: Vec<u8>,
+    /// Set when `address` is the game server's WebTransport transport, which browsers use.
+    /// Hex SHA-256 hash of its self-signed certificate, for the browser to pin.
+    #[serde(default)]
+    pub certificate_digest: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -134,6 +181,8 @@ pub struct PlayerGameInfo {
     pub name: String,
     pub team: Team,
     pub champ: ChampionId,
+    /// Whether the player reached the lobby server over ipv4, used to order their candidates
     pub is_ipv4: bool,
+    /// Whether the player reached the lobby server from a local address, used to order their candidates
     pub is_local: bool,
 }
@@ -0,0 +1,258 @@
+//! Starting game servers for lobbies that finished champ select.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    process::Command,
+    time::Instant,
+};
+
+use anyhow::Result;
+use lobby_common::{LobbyId, LobbySettings, LobbyToServer, PlayerGameInfo, ServerToLobby};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use tracing::{Instrument as _, Span, error, info, warn};
+use wtransport::{ClientConfig, Endpoint};
+
+use crate::{
+    InternalMessage,
+    metrics::METRICS,
+    transport::{RecvMessage as _, SendMessage as _},
+};
+
+/// Everything a game server needs to know to host a lobby's game
+pub struct GameLaunch {
+    pub lobby_id: LobbyId,
+    pub internal_port: u16,
+    pub external_port: u16,
+    /// Where browsers reach the game server over WebTransport, from the same range as
+    /// `external_port`
+    pub webtransport_port: u16,
+    pub settings: LobbySettings,
+    pub players: Vec<PlayerGameInfo>,
+    /// When the lobby asked for the game to start
+    pub started: Instant,
+}
+
+pub trait GameLauncher {
+    /// Starts a game server.
+    ///
+    /// Once started, the launcher reports back on `sender`:
+    /// [`InternalMessage::GameTokenCreated`] for each player and [`InternalMessage::InternalPortReleased`]
+    /// once players can connect, then [`InternalMessage::GameServerClosed`] and
+    /// [`InternalMessage::ExternalPortReleased`] for both external ports when the game server
+    /// exits.
+    /// While the game runs, messages on `control` are passed on to the game server, and what it
+    /// reports back is sent as [`InternalMessage::GameStatus`] and [`InternalMessage::GameTokenCreated`].
+    /// The game server should be killed when `kill` resolves, including when its sender is dropped.
+    fn launch(
+        &mut self,
+        launch: GameLaunch,
+        sender: UnboundedSender<InternalMessage>,
+        kill: oneshot::Receiver<()>,
+        control: UnboundedReceiver<LobbyToServer>,
+    ) -> Result<()>;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum LaunchMode {
+    Cargo,
+    #[default]
+    Executable,
+}
+
+/// A worse connection than the real one, simulated by game servers for testing
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LinkConditioner {
+    /// Added to every incoming packet
+    pub latency_ms: u64,
+    /// Random variation in the added latency
+    pub jitter_ms: u64,
+    /// Share of incoming packets dropped, from 0 to 1
+    pub packet_loss: f32,
+}
+
+/// Runs each game server as a child process
+#[derive(Debug)]
+pub struct ProcessLauncher {
+    pub launch_mode: LaunchMode,
+    pub release: bool,
+    pub public_ipv4_address: Ipv4Addr,
+    pub local_ipv4_address: Ipv4Addr,
+    pub ipv6_address: Ipv6Addr,
+    pub link_conditioner: Option<LinkConditioner>,
+}
+
+impl GameLauncher for ProcessLauncher {
+    fn launch(
+        &mut self,
+        launch: GameLaunch,
+        sender: UnboundedSender<InternalMessage>,
+        kill_receiver: oneshot::Receiver<()>,
+        mut control: UnboundedReceiver<LobbyToServer>,
+    ) -> Result<()> {
+        let GameLaunch {
+            lobby_id,
+            internal_port,
+            external_port,
+            webtransport_port,
+            settings,
+            players,
+            started,
+        } = launch;
+
+        let mut cmd = match self.launch_mode {
+            LaunchMode::Cargo => Command::new("cargo"),
+            LaunchMode::Executable => Command::new("./server"),
+        };
+
+        if self.launch_mode == LaunchMode::Cargo {
+            cmd.args(["run", "--bin=server"]);
+            if self.release {
+                cmd.arg("--release");
+            }
+            cmd.arg("--");
+        }
+
+        cmd.arg(format!("--webtransport-port={webtransport_port}"));
+
+        if let Some(conditioner) = self.link_conditioner {
+            cmd.args([
+                format!("--latency-ms={}", conditioner.latency_ms),
+                format!("--jitter-ms={}", conditioner.jitter_ms),
+                format!("--packet-loss={}", conditioner.packet_loss),
+            ]);
+        }
+
+        let mut child = tokio::process::Command::from(cmd)
+            .kill_on_drop(true)
+            .args([
+                &self.public_ipv4_address.to_string(),
+                &self.local_ipv4_address.to_string(),
+                &self.ipv6_address.to_string(),
+                &internal_port.to_string(),
+                &external_port.to_string(),
+            ])
+            .spawn()?;
+
+        let s = sender.clone();
+        tokio::spawn(
+            async move {
+                let killed = tokio::select! {
+                    _ = child.wait() => false,
+                    _ = kill_receiver => true,
+                };
+
+                if killed {
+                    warn!("Killing game server");
+                    _ = child.kill().await;
+                } else {
+                    info!("Game server exited");
+                }
+
+                _ = s.send(InternalMessage::GameServerClosed(lobby_id));
+                _ = s.send(InternalMessage::InternalPortReleased(internal_port));
+                _ = s.send(InternalMessage::ExternalPortReleased(external_port));
+                _ = s.send(InternalMessage::ExternalPortReleased(webtransport_port));
+            }
+            .instrument(Span::current()),
+        );
+
+        tokio::spawn(
+            async move {
+                // Connect to server
+                let conn = match Endpoint::client(
+                    ClientConfig::builder()
+                        .with_bind_default()
+                        .with_no_cert_validation()
+                        .build(),
+                )
+                .unwrap()
+                .connect(format!("https://localhost:{internal_port}"))
+                .await
+                {
+                    Ok(v) => v,
+                    Err(e) => {
+                        METRICS.game_start_failures.inc();
+                        error!("Error connecting to game server: {e}");
+                        return;
+                    }
+                };
+                conn.send(LobbyToServer::Handshake { settings, players })
+                    .await
+                    .unwrap();
+                let tokens = match conn.recv().await {
+                    Ok(ServerToLobby::PlayerTokens { tokens }) => tokens,
+                    Ok(message) => {
+                        METRICS.game_start_failures.inc();
+                        error!("Expected tokens from game server, got {message:?}");
+                        return;
+                    }
+                    Err(e) => {
+                        METRICS.game_start_failures.inc();
+                        error!("Error receiving tokens from game server: {e}");
+                        return;
+                    }
+                };
+                METRICS.game_start_duration.observe(started.elapsed());
+                info!("Game tokens created");
+                for (player, candidates) in tokens {
+                    sender
+                        .send(InternalMessage::GameTokenCreated(player, candidates))
+                        .unwrap();
+                }
+                _ = sender.send(InternalMessage::InternalPortReleased(internal_port));
+
+                // Keep the connection as a control channel until either side goes away
+                let forward = async {
+                    while let Some(message) = control.recv().await {
+                        if let Err(e) = conn.send(message).await {
+                            warn!("Error sending to game server: {e}");
+                            break;
+                        }
+                    }
+                };
+                let receive = async {
+                    loop {
+                        match conn.recv().await {
+                            Ok(ServerToLobby::PlayerTokens { tokens }) => {
+                                for (player, candidates) in tokens {
+                                    _ = sender.send(InternalMessage::GameTokenCreated(
+                                        player, candidates,
+                                    ));
+                                }
+                            }
+                            Ok(ServerToLobby::Status {
+                                phase,
+                                game_time,
+                                connected_players,
+                            }) => {
+                                _ = sender.send(InternalMessage::GameStatus {
+                                    lobby: lobby_id,
+                                    phase,
+                                    game_time,
+                                    connected_players,
+                                });
+                            }
+                            Err(e) => {
+                                info!("Control connection to game server closed: {e}");
+                                break;
+                            }
+                        }
+                    }
+                };
+                tokio::select! {
+                    _ = forward => {}
+                    _ = receive => {}
+                }
+                conn.close(0u8.into(), &[]);
+            }
+            .instrument(Span::current()),
+        );
+
+        Ok(())
+    }
+}
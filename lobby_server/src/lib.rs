@@ -0,0 +1,48 @@
+#![feature(ip)]
+#![feature(impl_trait_in_assoc_type)]
+
+use std::str::FromStr;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+pub mod launcher;
+pub mod metrics;
+pub mod transport;
+pub mod validation;
+mod wee;
+
+pub use wee::*;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl IntoIterator for PortRange {
+    type Item = u16;
+
+    type IntoIter = impl Iterator<Item = u16>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.first..=self.last
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.parse::<u16>() {
+            Ok(x) => Ok(Self { first: x, last: x }),
+            Err(_) => match s.split_once('-') {
+                Some((a, b)) => match (a.parse(), b.parse()) {
+                    (Ok(a), Ok(b)) => Ok(Self { first: a, last: b }),
+                    _ => bail!("expected port (e.g. 4545) or port range (e.g. 4545-4555)"),
+                },
+                _ => bail!("expected port (e.g. 4545) or port range (e.g. 4545-4555)"),
+            },
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Counters and histograms for the lobby server, exposed in the Prometheus text format.
+
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::{
+        Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::TcpListener,
+};
+use tracing::{debug, info, warn};
+
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    pub connections: Counter,
+    pub connected_players: Gauge,
+    pub lobbies: Gauge,
+    pub lobbies_created: Counter,
+    pub game_starts: Counter,
+    pub game_start_failures: Counter,
+    pub running_games: Gauge,
+    pub messages_received: Counter,
+    pub messages_sent: Counter,
+    pub message_send_failures: Counter,
+    pub violations: Counter,
+    pub violation_disconnects: Counter,
+    pub message_handle_duration: Histogram,
+    pub game_start_duration: Histogram,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections: Counter::new(),
+            connected_players: Gauge::new(),
+            lobbies: Gauge::new(),
+            lobbies_created: Counter::new(),
+            game_starts: Counter::new(),
+            game_start_failures: Counter::new(),
+            running_games: Gauge::new(),
+            messages_received: Counter::new(),
+            messages_sent: Counter::new(),
+            message_send_failures: Counter::new(),
+            violations: Counter::new(),
+            violation_disconnects: Counter::new(),
+            message_handle_duration: Histogram::new(&[
+                0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+            ]),
+            game_start_duration: Histogram::new(&[0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0]),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.connections.render(
+            &mut out,
+            "lobby_connections_total",
+            "Connections that completed the handshake",
+        );
+        self.connected_players.render(
+            &mut out,
+            "lobby_connected_players",
+            "Players currently connected",
+        );
+        self.lobbies
+            .render(&mut out, "lobby_lobbies", "Lobbies that currently exist");
+        self.lobbies_created
+            .render(&mut out, "lobby_lobbies_created_total", "Lobbies created");
+        self.game_starts
+            .render(&mut out, "lobby_game_starts_total", "Game servers started");
+        self.game_start_failures.render(
+            &mut out,
+            "lobby_game_start_failures_total",
+            "Games that failed to start",
+        );
+        self.running_games.render(
+            &mut out,
+            "lobby_running_games",
+            "Game servers currently running",
+        );
+        self.messages_received.render(
+            &mut out,
+            "lobby_messages_received_total",
+            "Messages received from players",
+        );
+        self.messages_sent.render(
+            &mut out,
+            "lobby_messages_sent_total",
+            "Messages sent to players",
+        );
+        self.message_send_failures.render(
+            &mut out,
+            "lobby_message_send_failures_total",
+            "Messages that could not be sent to players",
+        );
+        self.violations.render(
+            &mut out,
+            "lobby_violations_total",
+            "Messages rejected for being invalid, too large or rate limited",
+        );
+        self.violation_disconnects.render(
+            &mut out,
+            "lobby_violation_disconnects_total",
+            "Players disconnected after too many violations",
+        );
+        self.message_handle_duration.render(
+            &mut out,
+            "lobby_message_handle_seconds",
+            "Time spent handling a player message",
+        );
+        self.game_start_duration.render(
+            &mut out,
+            "lobby_game_start_seconds",
+            "Time from all players locking in until their game tokens were created",
+        );
+        out
+    }
+}
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        let _ = writeln!(out, "{name} {}", self.0.load(Ordering::Relaxed));
+    }
+}
+
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub fn set(&self, value: usize) {
+        self.0.store(value as i64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} gauge");
+        let _ = writeln!(out, "{name} {}", self.0.load(Ordering::Relaxed));
+    }
+}
+
+pub struct Histogram {
+    /// Upper bounds of the buckets, in seconds
+    bounds: &'static [f64],
+    inner: Mutex<HistogramInner>,
+}
+
+struct HistogramInner {
+    /// Non-cumulative counts, one per bound plus one for `+Inf`
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            inner: Mutex::new(HistogramInner {
+                buckets: Vec::new(),
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let value = duration.as_secs_f64();
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.buckets.resize(self.bounds.len() + 1, 0);
+        inner.buckets[bucket] += 1;
+        inner.sum += value;
+        inner.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let inner = self.inner.lock().unwrap();
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (i, bound) in self.bounds.iter().enumerate() {
+            cumulative += inner.buckets.get(i).copied().unwrap_or(0);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", inner.count);
+        let _ = writeln!(out, "{name}_sum {}", inner.sum);
+        let _ = writeln!(out, "{name}_count {}", inner.count);
+    }
+}
+
+/// Serves [`METRICS`] to anything that connects to `address`, e.g. a Prometheus scraper.
+///
+/// Every request gets the same response regardless of path, so there is no need for
+/// a full http server.
+pub async fn serve(address: SocketAddr) {
+    let listener = match TcpListener::bind(address).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!(%address, "Failed binding metrics listener: {err}");
+            return;
+        }
+    };
+    info!(%address, "Serving metrics");
+
+    loop {
+        let Ok((mut stream, remote)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(async move {
+            // We don't care about the request, but read it so the client doesn't get a reset
+            let mut buf = [0; 1024];
+            _ = stream.read(&mut buf).await;
+
+            let body = METRICS.render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(err) = stream.write_all(response.as_bytes()).await {
+                debug!(%remote, "Failed writing metrics: {err}");
+            }
+        });
+    }
+}
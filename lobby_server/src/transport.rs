@@ -0,0 +1,201 @@
+//! Connections to players over WebTransport.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{Result, bail};
+use lobby_common::{ClientToLobby, LobbyToClient, PlayerId};
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncReadExt as _, sync::mpsc::UnboundedSender};
+use tracing::{Instrument as _, Span, debug, info, info_span, warn};
+use uuid::Uuid;
+use wtransport::{
+    Connection, Endpoint,
+    endpoint::{IncomingSession, endpoint_side::Server},
+};
+
+use crate::{
+    InternalMessage, Player,
+    metrics::METRICS,
+    validation::{self, Limits, MessageTooLarge, Validator},
+};
+
+/// How the lobby state talks to a connected player.
+pub trait PlayerConnection: Send + Sync {
+    /// Queues a message to be sent to the player. Failures are only logged,
+    /// a broken connection is noticed by whatever is reading from it.
+    fn send(&self, message: LobbyToClient);
+
+    fn remote_address(&self) -> SocketAddr;
+}
+
+impl PlayerConnection for Connection {
+    fn send(&self, message: LobbyToClient) {
+        let connection = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = SendMessage::send(&connection, message).await {
+                METRICS.message_send_failures.inc();
+                debug!("Failed sending message: {err}");
+            }
+        });
+    }
+
+    fn remote_address(&self) -> SocketAddr {
+        Connection::remote_address(self)
+    }
+}
+
+pub async fn server_loop(
+    endpoint: Endpoint<Server>,
+    limits: Limits,
+    s: UnboundedSender<InternalMessage>,
+) -> Result<()> {
+    loop {
+        let incoming = endpoint.accept().await;
+        tokio::spawn(handle_connection(incoming, limits, s.clone()));
+    }
+}
+
+#[tracing::instrument(skip_all, fields(remote = %incoming.remote_address(), player))]
+pub async fn handle_connection(
+    incoming: IncomingSession,
+    limits: Limits,
+    s: UnboundedSender<InternalMessage>,
+) -> Result<()> {
+    debug!("Incoming connection");
+    let connection = incoming.await?;
+    let connection = connection.accept().await?;
+    debug!("Connection accepted, waiting for application handshake");
+    match connection
+        .recv_limited::<ClientToLobby>(limits.max_message_size)
+        .await
+    {
+        Ok(ClientToLobby::Handshake { name }) => {
+            let name = match validation::validate_player_name(&name) {
+                Ok(name) => name,
+                Err(err) => {
+                    METRICS.violations.inc();
+                    warn!("Rejecting handshake: {err}");
+                    connection.close(0u8.into(), b"invalid name");
+                    return Ok(());
+                }
+            };
+            let player_id = PlayerId(Uuid::new_v4());
+            Span::current().record("player", tracing::field::display(player_id.0));
+            SendMessage::send(&connection, LobbyToClient::Handshake { id: player_id }).await?;
+            info!(name, "Handshake completed");
+            METRICS.connections.inc();
+            let player = Player {
+                id: player_id,
+                name,
+                current_lobby: None,
+                connection: Arc::new(connection.clone()),
+            };
+
+            s.send(InternalMessage::NewPlayer(player))?;
+            tokio::spawn(
+                listen_to_connection(player_id, connection, limits, s.clone())
+                    .instrument(info_span!("player", id = %player_id.0)),
+            );
+        }
+        Ok(other) => {
+            warn!("Error receiving handshake: unexpected message {other:?}");
+        }
+        Err(err) => {
+            warn!("Error receiving handshake: {err}");
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn listen_to_connection(
+    player: PlayerId,
+    connection: Connection,
+    limits: Limits,
+    s: UnboundedSender<InternalMessage>,
+) -> Result<()> {
+    let mut validator = Validator::new(limits, connection.remote_address());
+
+    loop {
+        let message = match connection
+            .recv_limited::<ClientToLobby>(limits.max_message_size)
+            .await
+        {
+            Ok(message) => validator.check(&message).map(|()| message),
+            Err(e) if e.is::<MessageTooLarge>() || e.is::<serde_json::Error>() => Err(e),
+            Err(e) => {
+                info!("Read failed: {e}");
+                s.send(InternalMessage::PlayerDisconnected(player))?;
+                break;
+            }
+        };
+
+        match message {
+            Ok(message) => {
+                METRICS.messages_received.inc();
+                s.send(InternalMessage::PlayerMessage { player, message })?
+            }
+            Err(violation) => {
+                if validator.record_violation(&violation) {
+                    METRICS.violation_disconnects.inc();
+                    warn!(
+                        remote = %connection.remote_address(),
+                        "Disconnecting player after too many violations"
+                    );
+                    connection.close(0u8.into(), b"too many invalid messages");
+                    s.send(InternalMessage::PlayerDisconnected(player))?;
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) trait SendMessage {
+    async fn send<T: Serialize>(&self, msg: T) -> anyhow::Result<()>;
+}
+
+impl SendMessage for Connection {
+    async fn send<T: Serialize>(&self, msg: T) -> anyhow::Result<()> {
+        let msg = serde_json::to_vec_pretty(&msg)?;
+        self.open_uni().await?.await?.write_all(&msg).await?;
+        Ok(())
+    }
+}
+
+pub(crate) trait RecvMessage {
+    async fn recv<T: for<'de> Deserialize<'de>>(&self) -> anyhow::Result<T>;
+
+    /// Like [`RecvMessage::recv`], but fails with [`MessageTooLarge`] instead of reading
+    /// more than `max_size` bytes.
+    async fn recv_limited<T: for<'de> Deserialize<'de>>(
+        &self,
+        max_size: usize,
+    ) -> anyhow::Result<T>;
+}
+
+impl RecvMessage for Connection {
+    async fn recv<T: for<'de> Deserialize<'de>>(&self) -> anyhow::Result<T> {
+        let mut buf = vec![];
+        self.accept_uni().await?.read_to_end(&mut buf).await?;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    async fn recv_limited<T: for<'de> Deserialize<'de>>(
+        &self,
+        max_size: usize,
+    ) -> anyhow::Result<T> {
+        let mut buf = vec![];
+        self.accept_uni()
+            .await?
+            .take(max_size as u64 + 1)
+            .read_to_end(&mut buf)
+            .await?;
+        if buf.len() > max_size {
+            bail!(MessageTooLarge);
+        }
+        Ok(serde_json::from_slice(&buf)?)
+    }
+}
@@ -0,0 +1,154 @@
+//! Checks on everything players send us, before it reaches the lobby state.
+
+use std::{fmt, net::SocketAddr, time::Instant};
+
+use anyhow::{Result, bail};
+use lobby_common::{ClientToLobby, LobbySettings};
+use tracing::warn;
+
+use crate::metrics::METRICS;
+
+pub const MAX_PLAYER_NAME_LENGTH: usize = 32;
+pub const MAX_LOBBY_NAME_LENGTH: usize = 64;
+pub const MAX_CHAMPION_ID_LENGTH: usize = 64;
+pub const MAX_TEAM_COUNT: usize = 8;
+pub const MAX_PLAYERS_PER_TEAM: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Largest message in bytes we are willing to read from a player
+    pub max_message_size: usize,
+    /// Messages per second a player can send on average
+    pub message_rate: f64,
+    /// Messages a player can send in quick succession before being limited
+    pub message_burst: f64,
+    /// Players are disconnected after this many invalid or rate limited messages
+    pub max_violations: u32,
+}
+
+/// Returned when a message from a player is larger than [`Limits::max_message_size`].
+#[derive(Debug)]
+pub struct MessageTooLarge;
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Message too large")
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    rate: f64,
+    burst: f64,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            tokens: burst,
+            last_refill: Instant::now(),
+            rate,
+            burst,
+        }
+    }
+
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Validation state of a single connection.
+pub struct Validator {
+    limits: Limits,
+    remote: SocketAddr,
+    bucket: TokenBucket,
+    violations: u32,
+}
+
+impl Validator {
+    pub fn new(limits: Limits, remote: SocketAddr) -> Self {
+        Self {
+            limits,
+            remote,
+            bucket: TokenBucket::new(limits.message_rate, limits.message_burst),
+            violations: 0,
+        }
+    }
+
+    /// Checks that a message may be passed on to the lobby state.
+    pub fn check(&mut self, message: &ClientToLobby) -> Result<()> {
+        if !self.bucket.try_take() {
+            bail!("Rate limited");
+        }
+
+        match message {
+            ClientToLobby::Handshake { .. } => bail!("Repeated handshake"),
+            ClientToLobby::SetLobbySettings(settings) => validate_lobby_settings(settings)?,
+            ClientToLobby::SelectChamp(champ) => {
+                if champ.0.len() > MAX_CHAMPION_ID_LENGTH {
+                    bail!("Champion id too long");
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Logs a violation, and returns whether the player should be disconnected because of it.
+    pub fn record_violation(&mut self, violation: &anyhow::Error) -> bool {
+        self.violations += 1;
+        METRICS.violations.inc();
+        warn!(
+            remote = %self.remote,
+            violations = self.violations,
+            "Invalid message from player: {violation}"
+        );
+
+        self.violations >= self.limits.max_violations
+    }
+}
+
+/// Checks the name a player picked, returning it with surrounding whitespace removed.
+pub fn validate_player_name(name: &str) -> Result<String> {
+    let name = name.trim();
+    validate_name(name, MAX_PLAYER_NAME_LENGTH)?;
+    Ok(name.to_string())
+}
+
+fn validate_lobby_settings(settings: &LobbySettings) -> Result<()> {
+    validate_name(&settings.name, MAX_LOBBY_NAME_LENGTH)?;
+    if settings.team_count > MAX_TEAM_COUNT {
+        bail!("Too many teams");
+    }
+    if settings.max_players_per_team > MAX_PLAYERS_PER_TEAM {
+        bail!("Too many players per team");
+    }
+    Ok(())
+}
+
+fn validate_name(name: &str, max_length: usize) -> Result<()> {
+    if name.trim().is_empty() {
+        bail!("Name is empty");
+    }
+    if name.chars().count() > max_length {
+        bail!("Name is longer than {max_length} characters");
+    }
+    if name.chars().any(char::is_control) {
+        bail!("Name contains control characters");
+    }
+    Ok(())
+}
@@ -0,0 +1,1151 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{Result, anyhow, bail};
+use engine_common::ChampionId;
+use lobby_common::{
+    ChampionSelection, ClientToLobby, ConnectCandidate, LobbyId, LobbyInfo, LobbySettings,
+    LobbyShortInfo, LobbyState, LobbyToClient, LobbyToServer, MatchPhase, PlayerGameInfo, PlayerId,
+    PlayerInfo, Team,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    oneshot,
+};
+use tracing::{Instrument as _, Span, debug, info, info_span, trace, warn};
+use uuid::Uuid;
+
+use crate::{
+    PortRange,
+    launcher::{GameLaunch, GameLauncher},
+    metrics::METRICS,
+    transport::PlayerConnection,
+};
+
+/// Lobbies restored after a restart that nobody has rejoined by then are removed
+const RESTORED_LOBBY_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+pub enum InternalMessage {
+    NewPlayer(Player),
+    PlayerMessage {
+        player: PlayerId,
+        message: ClientToLobby,
+    },
+    PlayerDisconnected(PlayerId),
+    InternalPortReleased(u16),
+    ExternalPortReleased(u16),
+    GameServerClosed(LobbyId),
+    GameTokenCreated(PlayerId, Vec<ConnectCandidate>),
+    /// Periodic report from the game server of a lobby
+    GameStatus {
+        lobby: LobbyId,
+        phase: MatchPhase,
+        game_time: u64,
+        connected_players: Vec<PlayerId>,
+    },
+    /// Operator asked for the game of a lobby to be ended
+    EndGame(LobbyId),
+    /// Operator asked for a player to be removed from their game and lobby
+    KickFromGame(PlayerId),
+    /// Stop accepting new lobbies and games, and shut down once all running games are done
+    BeginDrain,
+    /// The drain timeout has passed; any game servers still running are killed
+    DrainDeadlineReached,
+    /// Restored lobbies have had time to be rejoined, the ones still empty are removed
+    RestoredLobbiesExpired,
+}
+
+pub struct Player {
+    pub id: PlayerId,
+    pub name: String,
+    pub current_lobby: Option<LobbyId>,
+    pub connection: Arc<dyn PlayerConnection>,
+}
+
+impl Player {
+    fn get_info(&self) -> PlayerInfo {
+        PlayerInfo {
+            id: self.id,
+            name: self.name.clone(),
+        }
+    }
+}
+
+pub struct Lobby {
+    pub id: LobbyId,
+    pub settings: LobbySettings,
+    pub teams: Vec<Vec<PlayerId>>,
+    pub leader: PlayerId,
+    pub lobby_state: LobbyState,
+    pub selected_champs: HashMap<PlayerId, ChampionSelection>,
+    /// Seconds the lobby's game has been in progress, as last reported by the game server
+    pub game_time: Option<u64>,
+}
+
+impl Lobby {
+    fn player_count(&self) -> usize {
+        self.teams.iter().map(Vec::len).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.teams.iter().all(Vec::is_empty)
+    }
+
+    fn get_short_info(&self) -> LobbyShortInfo {
+        LobbyShortInfo {
+            id: self.id,
+            name: self.settings.name.clone(),
+            player_count: self.player_count(),
+            max_player_count: self.settings.max_players_per_team * self.teams.len(),
+            game_time: self.game_time,
+        }
+    }
+
+    fn get_info(&self) -> LobbyInfo {
+        LobbyInfo {
+            short: self.get_short_info(),
+            settings: self.settings.clone(),
+            teams: self.teams.clone(),
+            leader: self.leader,
+            lobby_state: self.lobby_state,
+            selected_champs: self.selected_champs.clone(),
+        }
+    }
+
+    fn add_player(&mut self, player: PlayerId) {
+        // Find team with lowest amount of players
+        let lowest_team = self.teams.iter_mut().min_by_key(|x| x.len()).unwrap();
+        lowest_team.push(player);
+    }
+
+    /// Readjusts players so that no team has more players than they are allowed to,
+    /// if possible.
+    fn readjust_players(&mut self) {
+        let teams = (0..self.settings.team_count).map(Team).collect::<Vec<_>>();
+
+        if self.teams.len() > self.settings.team_count {
+            for from_team in (self.settings.team_count..self.teams.len()).rev().map(Team) {
+                let players = &self.teams[from_team.0];
+                // Players need to be moved from this team
+                'outer: for _ in 0..players.len() {
+                    for &to_team in &teams {
+                        if self.teams[to_team.0].len() < self.settings.max_players_per_team {
+                            // We can move them here
+                            let player = self.teams[from_team.0].pop().unwrap();
+                            self.teams[to_team.0].push(player);
+                            continue 'outer;
+                        }
+                    }
+                    // We could not find a team with space, just find the one with
+                    // the least amount of players
+                    if let Some(team_to_move_to) =
+                        teams.iter().min_by_key(|t| self.teams[t.0].len())
+                    {
+                        let player = self.teams[from_team.0].pop().unwrap();
+                        self.teams[team_to_move_to.0].push(player);
+                    } else {
+                        // There are no teams :(
+                        panic!("Lobby without teams is invalid");
+                    }
+                }
+            }
+            self.teams.pop();
+        }
+        if self.teams.len() < self.settings.team_count {
+            for _team in (self.teams.len()..self.settings.team_count).map(Team) {
+                self.teams.push(vec![]);
+            }
+        }
+        let teams = (0..self.settings.team_count).map(Team).collect::<Vec<_>>();
+
+        for &from_team in &teams {
+            let players = &self.teams[from_team.0];
+            if players.len() > self.settings.max_players_per_team {
+                // Players need to be moved from this team
+                let amount_to_move = players.len() - self.settings.max_players_per_team;
+                for _ in 0..amount_to_move {
+                    for &to_team in &teams {
+                        if self.teams[to_team.0].len() < self.settings.max_players_per_team {
+                            // We can move them here
+                            let player = self.teams[from_team.0].pop().unwrap();
+                            self.teams[to_team.0].push(player);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn needs_readjustment(&self) -> bool {
+        self.teams.len() != self.settings.team_count
+            || self
+                .teams
+                .iter()
+                .any(|t| t.len() > self.settings.max_players_per_team)
+                && !self
+                    .teams
+                    .iter()
+                    .all(|t| t.len() > self.settings.max_players_per_team)
+    }
+
+    fn readjust_if_needed(&mut self) {
+        if self.needs_readjustment() {
+            self.readjust_players();
+        }
+    }
+
+    fn remove_player(&mut self, player_id: PlayerId, only_temporarily: bool) {
+        for team in self.teams.iter_mut() {
+            if let Some(pos) = team.iter().position(|p| *p == player_id) {
+                team.remove(pos);
+                break;
+            }
+        }
+        if !only_temporarily {
+            if self.leader == player_id
+                && let Some(player) = self.teams.iter().flatten().next()
+            {
+                self.leader = *player;
+            }
+            self.readjust_if_needed();
+        }
+    }
+}
+
+/// The parts of a lobby that survive a restart of the lobby server.
+///
+/// Players have to reconnect after a restart, so only the lobby itself is kept;
+/// the first player to join a restored lobby becomes its leader.
+#[derive(Serialize, Deserialize)]
+struct PersistedLobby {
+    id: LobbyId,
+    settings: LobbySettings,
+}
+
+/// A game server that was started for a lobby
+struct RunningGame {
+    /// Dropping or sending on this kills the game server
+    kill: oneshot::Sender<()>,
+    control: UnboundedSender<LobbyToServer>,
+    /// Players connected to the game server, as of its last status report
+    connected_players: Vec<PlayerId>,
+    /// Players that were kicked from the game, and can't rejoin it
+    kicked_players: HashSet<PlayerId>,
+}
+
+impl RunningGame {
+    fn kick(&mut self, player: PlayerId) {
+        self.kicked_players.insert(player);
+        _ = self.control.send(LobbyToServer::KickPlayer(player));
+    }
+}
+
+/// What the lobby state needs to know about the server's configuration
+#[derive(Debug, Clone)]
+pub struct StateOptions {
+    pub internal_ports: PortRange,
+    pub external_ports: PortRange,
+    pub state_file: PathBuf,
+    pub drain_timeout: Duration,
+}
+
+pub struct State {
+    options: StateOptions,
+    launcher: Box<dyn GameLauncher>,
+    players: HashMap<PlayerId, Player>,
+    used_player_names: HashSet<String>,
+    lobbies: HashMap<LobbyId, Lobby>,
+    sender: UnboundedSender<InternalMessage>,
+    used_internal_ports: HashSet<u16>,
+    used_external_ports: HashSet<u16>,
+    running_games: HashMap<LobbyId, RunningGame>,
+    /// Set when the server is draining, holds the time we will shut down at the latest
+    shutting_down_at: Option<SystemTime>,
+    finished: bool,
+}
+
+impl State {
+    pub fn new(
+        sender: UnboundedSender<InternalMessage>,
+        options: StateOptions,
+        launcher: Box<dyn GameLauncher>,
+    ) -> Self {
+        Self {
+            options,
+            launcher,
+            players: HashMap::new(),
+            used_player_names: HashSet::new(),
+            lobbies: HashMap::new(),
+            sender,
+            used_internal_ports: HashSet::new(),
+            used_external_ports: HashSet::new(),
+            running_games: HashMap::new(),
+            shutting_down_at: None,
+            finished: false,
+        }
+    }
+
+    /// Whether the server has finished draining and should exit
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    fn is_draining(&self) -> bool {
+        self.shutting_down_at.is_some()
+    }
+
+    /// Restores lobbies saved by [`State::persist_lobbies`], removing the state file afterwards.
+    pub fn restore_lobbies(&mut self) -> Result<usize> {
+        let data = match std::fs::read(&self.options.state_file) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let lobbies: Vec<PersistedLobby> = serde_json::from_slice(&data)?;
+        let count = lobbies.len();
+
+        for persisted in lobbies {
+            let mut lobby = Lobby {
+                id: persisted.id,
+                settings: persisted.settings,
+                teams: vec![],
+                // Nobody leads a restored lobby until the first player joins it, who becomes its
+                // leader
+                leader: PlayerId(Uuid::nil()),
+                lobby_state: LobbyState::InLobby,
+                selected_champs: HashMap::new(),
+                game_time: None,
+            };
+            lobby.readjust_if_needed();
+            self.lobbies.insert(lobby.id, lobby);
+        }
+
+        std::fs::remove_file(&self.options.state_file)?;
+
+        if count > 0 {
+            let sender = self.sender.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(RESTORED_LOBBY_TIMEOUT).await;
+                _ = sender.send(InternalMessage::RestoredLobbiesExpired);
+            });
+        }
+
+        Ok(count)
+    }
+
+    /// Saves all lobbies that are waiting in [`LobbyState::InLobby`] to the state file.
+    pub fn persist_lobbies(&self) -> Result<usize> {
+        let lobbies = self
+            .lobbies
+            .values()
+            .filter(|lobby| lobby.lobby_state == LobbyState::InLobby)
+            .map(|lobby| PersistedLobby {
+                id: lobby.id,
+                settings: lobby.settings.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        if lobbies.is_empty() {
+            return Ok(0);
+        }
+
+        std::fs::write(
+            &self.options.state_file,
+            serde_json::to_vec_pretty(&lobbies)?,
+        )?;
+
+        Ok(lobbies.len())
+    }
+
+    fn begin_drain(&mut self) {
+        if self.is_draining() {
+            return;
+        }
+
+        let at = SystemTime::now() + self.options.drain_timeout;
+        self.shutting_down_at = Some(at);
+        info!(
+            running_games = self.running_games.len(),
+            "Draining; waiting for running games to finish",
+        );
+
+        // Lobbies in champ select can no longer start their game
+        let in_champ_select = self
+            .lobbies
+            .values_mut()
+            .filter(|lobby| lobby.lobby_state == LobbyState::InChampSelect)
+            .map(|lobby| {
+                lobby.lobby_state = LobbyState::InLobby;
+                lobby.selected_champs.clear();
+                lobby.id
+            })
+            .collect::<Vec<_>>();
+        for lobby_id in in_champ_select {
+            _ = self.broadcast_message(lobby_id, None, LobbyToClient::ReturnFromChampSelect);
+        }
+
+        let at_secs = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        for &player in self.players.keys() {
+            _ = self.send_message(player, LobbyToClient::ServerShuttingDown { at: at_secs });
+        }
+
+        let sender = self.sender.clone();
+        let timeout = self.options.drain_timeout;
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            _ = sender.send(InternalMessage::DrainDeadlineReached);
+        });
+
+        self.finish_drain_if_done();
+    }
+
+    fn finish_drain_if_done(&mut self) {
+        if self.is_draining() && self.running_games.is_empty() {
+            self.finished = true;
+        }
+    }
+
+    /// A span for everything that happens on behalf of `player_id`
+    fn player_span(&self, player_id: PlayerId) -> Span {
+        let lobby = self
+            .players
+            .get(&player_id)
+            .and_then(|player| player.current_lobby);
+        info_span!(
+            "player",
+            id = %player_id.0,
+            lobby = lobby.map(|lobby| tracing::field::display(lobby.0)),
+        )
+    }
+
+    pub async fn handle(&mut self, r: &mut UnboundedReceiver<InternalMessage>) -> Result<()> {
+        let result = self.handle_message(r).await;
+
+        METRICS.connected_players.set(self.players.len());
+        METRICS.lobbies.set(self.lobbies.len());
+        METRICS.running_games.set(self.running_games.len());
+
+        result
+    }
+
+    async fn handle_message(&mut self, r: &mut UnboundedReceiver<InternalMessage>) -> Result<()> {
+        match r.recv().await.ok_or(anyhow!("Reading failed"))? {
+            InternalMessage::NewPlayer(mut player) => {
+                let _span = self.player_span(player.id).entered();
+                info!("Player connected");
+                if let Some(at) = self.shutting_down_at {
+                    let at = at
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    player
+                        .connection
+                        .send(LobbyToClient::ServerShuttingDown { at });
+                }
+                // Find unused username
+                let mut i = 1;
+                let mut name = player.name.clone();
+                while self.used_player_names.contains(&name) {
+                    i += 1;
+                    name = format!("{} {i}", player.name);
+                    debug!("Incrementing name to {name}");
+                }
+                player.name = name.clone();
+                self.players.insert(player.id, player);
+                self.used_player_names.insert(name);
+            }
+            InternalMessage::PlayerDisconnected(player_id)
+            | InternalMessage::PlayerMessage {
+                player: player_id,
+                message: ClientToLobby::Disconnect,
+            } => {
+                let _span = self.player_span(player_id).entered();
+                info!("Player disconnected");
+                let _ = self.handle_player_left(player_id);
+                if let Some(player) = self.players.remove(&player_id) {
+                    self.used_player_names.remove(&player.name);
+                }
+            }
+            InternalMessage::PlayerMessage { player, message } => {
+                let span = self.player_span(player);
+                let start = Instant::now();
+                let result = self
+                    .handle_player_message(player, message)
+                    .instrument(span.clone())
+                    .await;
+                METRICS.message_handle_duration.observe(start.elapsed());
+                if let Err(err) = result {
+                    span.in_scope(|| warn!("Rejected message: {err:?}"));
+                }
+            }
+            InternalMessage::GameServerClosed(lobby_id) => {
+                self.running_games.remove(&lobby_id);
+                if let Some(lobby) = self.lobbies.get_mut(&lobby_id) {
+                    lobby.lobby_state = LobbyState::InLobby;
+                    lobby.selected_champs.clear();
+                    lobby.game_time = None;
+                    _ = self.broadcast_message(
+                        lobby_id,
+                        None,
+                        LobbyToClient::ReturnFromChampSelect,
+                    );
+                }
+                self.finish_drain_if_done();
+            }
+            InternalMessage::GameTokenCreated(player_id, candidates) => {
+                _ = self.send_message(player_id, LobbyToClient::GameStarted(candidates));
+            }
+            InternalMessage::GameStatus {
+                lobby: lobby_id,
+                phase,
+                game_time,
+                connected_players,
+            } => {
+                trace!(lobby = %lobby_id.0, ?phase, game_time, "Game status");
+                if let Some(game) = self.running_games.get_mut(&lobby_id) {
+                    game.connected_players = connected_players;
+                }
+                if let Some(lobby) = self.lobbies.get_mut(&lobby_id) {
+                    lobby.game_time = (phase == MatchPhase::InProgress).then_some(game_time);
+                }
+            }
+            InternalMessage::EndGame(lobby_id) => {
+                let Some(game) = self.running_games.get(&lobby_id) else {
+                    bail!("Lobby has no running game");
+                };
+                info!(lobby = %lobby_id.0, "Ending game");
+                if game.control.send(LobbyToServer::EndMatch).is_err() {
+                    warn!(lobby = %lobby_id.0, "Game server not reachable, killing it");
+                    if let Some(game) = self.running_games.remove(&lobby_id) {
+                        _ = game.kill.send(());
+                    }
+                }
+            }
+            InternalMessage::KickFromGame(player_id) => {
+                let _span = self.player_span(player_id).entered();
+                let lobby_id = self
+                    .players
+                    .get(&player_id)
+                    .and_then(|player| player.current_lobby)
+                    .filter(|lobby| self.running_games.contains_key(lobby))
+                    .or_else(|| {
+                        self.running_games
+                            .iter()
+                            .find(|(_, game)| game.connected_players.contains(&player_id))
+                            .map(|(lobby, _)| *lobby)
+                    });
+                let Some(game) = lobby_id.and_then(|lobby| self.running_games.get_mut(&lobby))
+                else {
+                    bail!("Player is not in a running game");
+                };
+                info!("Kicking player from game");
+                game.kick(player_id);
+                if self.players.contains_key(&player_id) {
+                    self.handle_player_left(player_id)?;
+                }
+            }
+            InternalMessage::InternalPortReleased(port) => {
+                self.used_internal_ports.remove(&port);
+            }
+            InternalMessage::ExternalPortReleased(port) => {
+                self.used_external_ports.remove(&port);
+            }
+            InternalMessage::BeginDrain => {
+                self.begin_drain();
+            }
+            InternalMessage::DrainDeadlineReached => {
+                self.begin_drain();
+                if !self.running_games.is_empty() {
+                    warn!(
+                        running_games = self.running_games.len(),
+                        "Drain timeout reached, killing running games"
+                    );
+                }
+                // Dropping the kill senders kills the game servers
+                self.running_games.clear();
+                self.finish_drain_if_done();
+            }
+            InternalMessage::RestoredLobbiesExpired => {
+                // Lobbies are removed once their last player leaves, so only restored lobbies
+                // nobody joined can be empty
+                let before = self.lobbies.len();
+                self.lobbies.retain(|_, lobby| !lobby.is_empty());
+                let expired = before - self.lobbies.len();
+                if expired > 0 {
+                    info!(expired, "Removed restored lobbies nobody rejoined");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_player_message(
+        &mut self,
+        player_id: PlayerId,
+        message: ClientToLobby,
+    ) -> Result<()> {
+        match message {
+            ClientToLobby::Handshake { .. } => unreachable!(),
+            ClientToLobby::FetchLobbyList => {
+                let _ = self.send_message(
+                    player_id,
+                    LobbyToClient::LobbyList(
+                        self.lobbies.values().map(Lobby::get_short_info).collect(),
+                    ),
+                );
+            }
+            ClientToLobby::CreateAndJoinLobby => {
+                let player = self
+                    .players
+                    .get_mut(&player_id)
+                    .ok_or(anyhow!("Invalid player"))?;
+
+                if player.current_lobby.is_some() {
+                    bail!("Player is already in lobby");
+                }
+
+                if self.shutting_down_at.is_some() {
+                    bail!("Server is shutting down");
+                }
+
+                let lobby_id = LobbyId(Uuid::new_v4());
+                let lobby = Lobby {
+                    id: lobby_id,
+                    settings: LobbySettings {
+                        name: format!("{}'s lobby", player.name),
+                        locked: false,
+                        team_count: 2,
+                        max_players_per_team: 5,
+                    },
+                    teams: [vec![player_id], vec![]].into_iter().collect(),
+                    leader: player_id,
+                    lobby_state: LobbyState::InLobby,
+                    selected_champs: HashMap::new(),
+                    game_time: None,
+                };
+
+                self.lobbies.insert(lobby_id, lobby);
+                METRICS.lobbies_created.inc();
+                info!(lobby = %lobby_id.0, "Lobby created");
+
+                player.current_lobby = Some(lobby_id);
+
+                let _ = self.send_message(player_id, LobbyToClient::YouJoinedLobby(lobby_id));
+            }
+            ClientToLobby::JoinLobby(lobby_id) => {
+                let player = self
+                    .players
+                    .get_mut(&player_id)
+                    .ok_or(anyhow!("Invalid player"))?;
+
+                if player.current_lobby.is_some() {
+                    bail!("Player is already in lobby");
+                }
+
+                let lobby = self
+                    .lobbies
+                    .get_mut(&lobby_id)
+                    .ok_or(anyhow!("Lobby doesn't exist"))?;
+
+                if lobby.settings.locked {
+                    bail!("Lobby is locked");
+                }
+
+                if lobby.player_count() >= lobby.settings.max_players() {
+                    bail!("Lobby is full");
+                }
+
+                if lobby.lobby_state != LobbyState::InLobby {
+                    bail!("Lobby is in champ select or in game");
+                }
+
+                // Restored lobbies start out empty, whoever joins first leads them
+                if lobby.is_empty() {
+                    lobby.leader = player_id;
+                }
+
+                // Add player to lobby
+                lobby.add_player(player_id);
+                player.current_lobby = Some(lobby_id);
+
+                let _ = self.send_message(player_id, LobbyToClient::YouJoinedLobby(lobby_id));
+                let _ = self.broadcast_message(
+                    lobby_id,
+                    player_id,
+                    LobbyToClient::PlayerJoinedLobby(player_id),
+                );
+            }
+            ClientToLobby::LeaveCurrentLobby => {
+                self.handle_player_left(player_id)?;
+            }
+            ClientToLobby::GetLobbyInfo(lobby_id) => {
+                let Some(lobby) = self.lobbies.get(&lobby_id) else {
+                    bail!("Lobby doesn't exist")
+                };
+
+                let _ = self.send_message(player_id, LobbyToClient::LobbyInfo(lobby.get_info()));
+            }
+            ClientToLobby::GetPlayerInfo(req_player_id) => {
+                let Some(player) = self.players.get(&req_player_id) else {
+                    bail!("Player doesn't exist")
+                };
+
+                let _ = self.send_message(player_id, LobbyToClient::PlayerInfo(player.get_info()));
+            }
+            ClientToLobby::SetLobbySettings(mut lobby_settings) => {
+                let Some(player) = self.players.get(&player_id) else {
+                    bail!("Player doesn't exist");
+                };
+                let Some(lobby_id) = player.current_lobby else {
+                    bail!("Player is not in a lobby");
+                };
+                let Some(lobby) = self.lobbies.get_mut(&lobby_id) else {
+                    bail!("Lobby doesn't exist");
+                };
+                if lobby.leader != player_id {
+                    bail!("Player is not lobby leader");
+                }
+                lobby_settings.team_count = lobby_settings.team_count.max(1);
+                lobby_settings.max_players_per_team = lobby_settings.max_players_per_team.max(1);
+                lobby.settings = lobby_settings;
+                lobby.readjust_if_needed();
+                let info = lobby.get_info();
+                let _ = self.broadcast_message(lobby_id, None, LobbyToClient::LobbyInfo(info));
+            }
+            ClientToLobby::Disconnect => unreachable!(),
+            ClientToLobby::ChangePlayerTeam(player_to_move, team) => {
+                let Some(player) = self.players.get(&player_id) else {
+                    bail!("Player doesn't exist");
+                };
+                let Some(lobby_id) = player.current_lobby else {
+                    bail!("Player is not in a lobby");
+                };
+                let Some(lobby) = self.lobbies.get_mut(&lobby_id) else {
+                    bail!("Lobby doesn't exist");
+                };
+                if player_to_move != player_id && lobby.leader != player_id {
+                    bail!("Player is not lobby leader");
+                }
+                if lobby.teams.len() <= team.0 {
+                    bail!("Invalid team");
+                }
+                if lobby.teams[team.0].len() >= lobby.settings.max_players_per_team {
+                    bail!("Team is full");
+                }
+
+                lobby.remove_player(player_to_move, true);
+                lobby.teams[team.0].push(player_to_move);
+
+                _ = self.broadcast_message(
+                    lobby_id,
+                    None,
+                    LobbyToClient::PlayerChangedTeam(player_to_move, team),
+                );
+            }
+            ClientToLobby::SwitchPlayerPositions(player_a_id, player_b_id) => {
+                let Some(player) = self.players.get(&player_id) else {
+                    bail!("Player doesn't exist");
+                };
+                let Some(lobby_id) = player.current_lobby else {
+                    bail!("Player is not in a lobby");
+                };
+                let Some(lobby) = self.lobbies.get_mut(&lobby_id) else {
+                    bail!("Lobby doesn't exist");
+                };
+                if lobby.leader != player_id {
+                    bail!("Player is not lobby leader");
+                }
+                if player_a_id == player_b_id {
+                    bail!("Cannot switch player to themselves");
+                }
+                let a = lobby
+                    .teams
+                    .iter()
+                    .enumerate()
+                    .find_map(|(t, p)| {
+                        p.iter()
+                            .position(|p| *p == player_a_id)
+                            .map(|i| (Team(t), i))
+                    })
+                    .ok_or(anyhow!("No player a in lobby"))?;
+                let b = lobby
+                    .teams
+                    .iter()
+                    .enumerate()
+                    .find_map(|(t, p)| {
+                        p.iter()
+                            .position(|p| *p == player_b_id)
+                            .map(|i| (Team(t), i))
+                    })
+                    .ok_or(anyhow!("No player b in lobby"))?;
+
+                if a.0 == b.0 {
+                    lobby.teams[a.0.0].swap(a.1, b.1);
+                } else {
+                    let [at, bt] = lobby.teams.get_disjoint_mut([a.0.0, b.0.0]).unwrap();
+                    std::mem::swap(&mut at[a.1], &mut bt[b.1]);
+                }
+
+                _ = self.broadcast_message(
+                    lobby_id,
+                    None,
+                    LobbyToClient::PlayerChangedPositions(player_a_id, player_b_id),
+                );
+            }
+            ClientToLobby::KickPlayer(player_to_kick) => {
+                let Some(player) = self.players.get(&player_id) else {
+                    bail!("Player doesn't exist");
+                };
+                let Some(lobby_id) = player.current_lobby else {
+                    bail!("Player is not in a lobby");
+                };
+                let Some(lobby) = self.lobbies.get_mut(&lobby_id) else {
+                    bail!("Lobby doesn't exist");
+                };
+                if lobby.leader != player_id {
+                    bail!("Player is not lobby leader");
+                }
+                if lobby.teams.iter().all(|t| !t.contains(&player_to_kick)) {
+                    bail!("Player to kick not in this lobby");
+                }
+                if let Some(game) = self.running_games.get_mut(&lobby_id) {
+                    game.kick(player_to_kick);
+                }
+
+                self.handle_player_left(player_to_kick)?;
+            }
+            ClientToLobby::GoToChampSelect => {
+                let Some(player) = self.players.get(&player_id) else {
+                    bail!("Player doesn't exist");
+                };
+                let Some(lobby_id) = player.current_lobby else {
+                    bail!("Player is not in a lobby");
+                };
+                let Some(lobby) = self.lobbies.get_mut(&lobby_id) else {
+                    bail!("Lobby doesn't exist");
+                };
+                if lobby.leader != player_id {
+                    bail!("Player is not lobby leader");
+                }
+                if self.shutting_down_at.is_some() {
+                    bail!("Server is shutting down");
+                }
+
+                lobby.lobby_state = LobbyState::InChampSelect;
+
+                _ = self.broadcast_message(lobby_id, None, LobbyToClient::GoToChampSelect);
+            }
+            ClientToLobby::SelectChamp(champ) => {
+                let Some(player) = self.players.get(&player_id) else {
+                    bail!("Player doesn't exist");
+                };
+                let Some(lobby_id) = player.current_lobby else {
+                    bail!("Player is not in a lobby");
+                };
+                let Some(lobby) = self.lobbies.get_mut(&lobby_id) else {
+                    bail!("Lobby doesn't exist");
+                };
+                let entry = lobby
+                    .selected_champs
+                    .entry(player_id)
+                    .or_insert(ChampionSelection {
+                        id: ChampionId(String::new()),
+                        locked: false,
+                    });
+                if entry.locked {
+                    bail!("Cannot change locked selection");
+                }
+
+                entry.id = champ.clone();
+
+                _ = self.broadcast_message(
+                    lobby_id,
+                    None,
+                    LobbyToClient::PlayerSelectedChamp(player_id, champ),
+                );
+            }
+            ClientToLobby::LockSelection => {
+                let Some(player) = self.players.get(&player_id) else {
+                    bail!("Player doesn't exist");
+                };
+                let Some(lobby_id) = player.current_lobby else {
+                    bail!("Player is not in a lobby");
+                };
+                let Some(lobby) = self.lobbies.get_mut(&lobby_id) else {
+                    bail!("Lobby doesn't exist");
+                };
+                let Some(selection) = lobby.selected_champs.get_mut(&player_id) else {
+                    bail!("Cannot lock non-existant selection");
+                };
+                if selection.locked {
+                    bail!("Cannot lock locked selection");
+                }
+
+                selection.locked = true;
+
+                if lobby.selected_champs.len() == lobby.player_count()
+                    && lobby.selected_champs.values().all(|s| s.locked)
+                    && let Err(err) = self.start_game(lobby_id)
+                {
+                    METRICS.game_start_failures.inc();
+                    return Err(err.context("Failed starting game"));
+                }
+                _ = self.broadcast_message(
+                    lobby_id,
+                    None,
+                    LobbyToClient::PlayerLockedSelection(player_id),
+                );
+            }
+            ClientToLobby::RejoinGame => {
+                let Some(player) = self.players.get(&player_id) else {
+                    bail!("Player doesn't exist");
+                };
+                let Some(lobby_id) = player.current_lobby else {
+                    bail!("Player is not in a lobby");
+                };
+                let Some(game) = self.running_games.get(&lobby_id) else {
+                    bail!("Lobby is not in game");
+                };
+                if game.kicked_players.contains(&player_id) {
+                    bail!("Player was kicked from the game");
+                }
+
+                let (is_ipv4, is_local) = address_hints(player.connection.remote_address());
+                game.control
+                    .send(LobbyToServer::IssueToken {
+                        player: player_id,
+                        is_ipv4,
+                        is_local,
+                    })
+                    .map_err(|_| anyhow!("Game server is not reachable"))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_player_left(&mut self, player_id: PlayerId) -> Result<()> {
+        let player = self
+            .players
+            .get_mut(&player_id)
+            .ok_or(anyhow!("Player doesn't exist"))?;
+        let Some(lobby_id) = player.current_lobby else {
+            return Ok(());
+        };
+        let lobby = self
+            .lobbies
+            .get_mut(&lobby_id)
+            .ok_or(anyhow!("Lobby doesn't exist"))?;
+        lobby.remove_player(player_id, false);
+        player.current_lobby = None;
+
+        let in_champ_select = lobby.lobby_state == LobbyState::InChampSelect;
+        if in_champ_select {
+            lobby.lobby_state = LobbyState::InLobby;
+            lobby.selected_champs.clear();
+        }
+
+        if lobby.is_empty() {
+            self.lobbies.remove(&lobby_id);
+        } else {
+            if in_champ_select {
+                _ = self.broadcast_message(lobby_id, None, LobbyToClient::ReturnFromChampSelect);
+            }
+            let _ =
+                self.broadcast_message(lobby_id, None, LobbyToClient::PlayerLeftLobby(player_id));
+        }
+        let _ = self.send_message(player_id, LobbyToClient::YouLeftLobby);
+
+        Ok(())
+    }
+
+    fn send_message(&self, player: PlayerId, message: LobbyToClient) -> Result<()> {
+        let connection = &self
+            .players
+            .get(&player)
+            .ok_or(anyhow!("No such player exists"))?
+            .connection;
+        trace!(player = %player.0, ?message, "Sending message");
+        METRICS.messages_sent.inc();
+        connection.send(message);
+        Ok(())
+    }
+
+    fn broadcast_message(
+        &self,
+        lobby: LobbyId,
+        exclude_player: impl Into<Option<PlayerId>>,
+        message: LobbyToClient,
+    ) -> Result<()> {
+        let exclude_player = exclude_player.into();
+        for player in self
+            .lobbies
+            .get(&lobby)
+            .ok_or(anyhow!("No such lobby exists"))?
+            .teams
+            .iter()
+            .flatten()
+            .copied()
+        {
+            if exclude_player == Some(player) {
+                continue;
+            }
+
+            let _ = self.send_message(player, message.clone());
+        }
+
+        Ok(())
+    }
+
+    fn start_game(&mut self, lobby_id: LobbyId) -> Result<()> {
+        let span = info_span!("lobby", id = %lobby_id.0);
+        let _enter = span.enter();
+        let started = Instant::now();
+
+        if self.is_draining() {
+            bail!("Server is shutting down");
+        }
+
+        let lobby = self
+            .lobbies
+            .get_mut(&lobby_id)
+            .ok_or(anyhow!("No such lobby"))?;
+        if lobby.selected_champs.len() < lobby.player_count() {
+            bail!("Not all players have selected a champion");
+        }
+
+        if lobby.selected_champs.values().any(|s| !s.locked) {
+            bail!("Not all players have locked their selection");
+        }
+
+        info!("Starting game server");
+
+        let Some(internal_port) = self
+            .options
+            .internal_ports
+            .into_iter()
+            .find(|p| !self.used_internal_ports.contains(p))
+        else {
+            _ = self
+                .sender
+                .send(InternalMessage::GameServerClosed(lobby_id));
+            bail!("No internal port available");
+        };
+        let Some(external_port) = self
+            .options
+            .external_ports
+            .into_iter()
+            .find(|p| !self.used_external_ports.contains(p))
+        else {
+            _ = self
+                .sender
+                .send(InternalMessage::GameServerClosed(lobby_id));
+            bail!("No external port available");
+        };
+        let Some(webtransport_port) = self
+            .options
+            .external_ports
+            .into_iter()
+            .find(|p| *p != external_port && !self.used_external_ports.contains(p))
+        else {
+            _ = self
+                .sender
+                .send(InternalMessage::GameServerClosed(lobby_id));
+            bail!("No external port available for WebTransport");
+        };
+
+        let settings = lobby.settings.clone();
+        let lobby = &*lobby;
+        let players = &self.players;
+        let players = lobby
+            .teams
+            .iter()
+            .enumerate()
+            .flat_map(|(i, p)| {
+                p.iter().map(move |p| {
+                    let player = players.get(p).unwrap();
+                    let (is_ipv4, is_local) = address_hints(player.connection.remote_address());
+
+                    PlayerGameInfo {
+                        id: *p,
+                        name: player.name.clone(),
+                        team: Team(i),
+                        champ: lobby.selected_champs.get(p).unwrap().id.clone(),
+                        is_ipv4,
+                        is_local,
+                    }
+                })
+            })
+            .collect();
+
+        let (kill_sender, kill_receiver) = oneshot::channel::<()>();
+        let (control_sender, control_receiver) = unbounded_channel();
+        let launched = self.launcher.launch(
+            GameLaunch {
+                lobby_id,
+                internal_port,
+                external_port,
+                webtransport_port,
+                settings,
+                players,
+                started,
+            },
+            self.sender.clone(),
+            kill_receiver,
+            control_receiver,
+        );
+        if let Err(err) = launched {
+            _ = self
+                .sender
+                .send(InternalMessage::GameServerClosed(lobby_id));
+            return Err(err.context("Failed to launch game server"));
+        }
+
+        self.used_internal_ports.insert(internal_port);
+        self.used_external_ports.insert(external_port);
+        self.used_external_ports.insert(webtransport_port);
+        self.running_games.insert(
+            lobby_id,
+            RunningGame {
+                kill: kill_sender,
+                control: control_sender,
+                connected_players: vec![],
+                kicked_players: HashSet::new(),
+            },
+        );
+        METRICS.game_starts.inc();
+
+        if let Some(lobby) = self.lobbies.get_mut(&lobby_id) {
+            lobby.lobby_state = LobbyState::InGame;
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether a player reached us over ipv4 and from a local address,
+/// which the game server uses to order their connect candidates
+fn address_hints(address: SocketAddr) -> (bool, bool) {
+    match address {
+        SocketAddr::V4(address) => (true, !address.ip().is_global()),
+        SocketAddr::V6(address) => (
+            address.ip().is_ipv4_mapped(),
+            !(address.ip().is_global()
+                || address
+                    .ip()
+                    .to_ipv4_mapped()
+                    .is_some_and(|ip| ip.is_global())),
+        ),
+    }
+}
@@ -0,0 +1,297 @@
+//! Drives [`State`] with simulated players instead of real connections.
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use lobby_common::{
+    ClientToLobby, ConnectCandidate, LobbyId, LobbyInfo, LobbyShortInfo, LobbyToClient,
+    LobbyToServer, PlayerId,
+};
+use lobby_server::{
+    InternalMessage, Player, PortRange, State, StateOptions,
+    launcher::{GameLaunch, GameLauncher},
+    transport::PlayerConnection,
+};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    oneshot,
+};
+use uuid::Uuid;
+
+pub struct Harness {
+    state: State,
+    options: StateOptions,
+    sender: UnboundedSender<InternalMessage>,
+    receiver: UnboundedReceiver<InternalMessage>,
+    launched: Arc<Mutex<Vec<LaunchedGame>>>,
+    fail_launches: Arc<AtomicBool>,
+    /// Errors returned by the state, player messages that were rejected are only logged
+    pub errors: Vec<anyhow::Error>,
+}
+
+pub struct SimPlayer {
+    pub id: PlayerId,
+    inbox: UnboundedReceiver<LobbyToClient>,
+}
+
+pub struct LaunchedGame {
+    pub launch: GameLaunch,
+    pub kill: oneshot::Receiver<()>,
+    pub control: UnboundedReceiver<LobbyToServer>,
+}
+
+impl LaunchedGame {
+    /// Everything the lobby sent to this game server since the last call
+    pub fn take_control(&mut self) -> Vec<LobbyToServer> {
+        let mut messages = vec![];
+        while let Ok(message) = self.control.try_recv() {
+            messages.push(message);
+        }
+        messages
+    }
+}
+
+struct SimConnection(UnboundedSender<LobbyToClient>);
+
+impl PlayerConnection for SimConnection {
+    fn send(&self, message: LobbyToClient) {
+        _ = self.0.send(message);
+    }
+
+    fn remote_address(&self) -> SocketAddr {
+        (Ipv4Addr::LOCALHOST, 0).into()
+    }
+}
+
+/// Hands out tokens right away instead of starting a game server
+struct SimLauncher {
+    launched: Arc<Mutex<Vec<LaunchedGame>>>,
+    /// Fail every launch, as if the game server couldn't be started
+    fail: Arc<AtomicBool>,
+}
+
+impl GameLauncher for SimLauncher {
+    fn launch(
+        &mut self,
+        launch: GameLaunch,
+        sender: UnboundedSender<InternalMessage>,
+        kill: oneshot::Receiver<()>,
+        control: UnboundedReceiver<LobbyToServer>,
+    ) -> Result<()> {
+        if self.fail.load(Ordering::Relaxed) {
+            bail!("Simulated launch failure");
+        }
+        for player in &launch.players {
+            sender.send(InternalMessage::GameTokenCreated(
+                player.id,
+                vec![ConnectCandidate {
+                    address: (Ipv4Addr::LOCALHOST, launch.external_port).into(),
+                    token: player.id.0.as_bytes().to_vec(),
+                    certificate_digest: None,
+                }],
+            ))?;
+        }
+        sender.send(InternalMessage::InternalPortReleased(launch.internal_port))?;
+        self.launched.lock().unwrap().push(LaunchedGame {
+            launch,
+            kill,
+            control,
+        });
+        Ok(())
+    }
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        let (sender, receiver) = unbounded_channel();
+        let launched = Arc::new(Mutex::new(vec![]));
+        let fail_launches = Arc::new(AtomicBool::new(false));
+        let options = StateOptions {
+            internal_ports: PortRange {
+                first: 20000,
+                last: 20000,
+            },
+            external_ports: PortRange {
+                first: 54000,
+                last: 54003,
+            },
+            state_file: std::env::temp_dir().join(format!("lobby_state_{}.json", Uuid::new_v4())),
+            drain_timeout: Duration::from_secs(60),
+        };
+        let state = State::new(
+            sender.clone(),
+            options.clone(),
+            Box::new(SimLauncher {
+                launched: launched.clone(),
+                fail: fail_launches.clone(),
+            }),
+        );
+
+        Self {
+            state,
+            options,
+            sender,
+            receiver,
+            launched,
+            fail_launches,
+            errors: vec![],
+        }
+    }
+
+    /// Handles everything that has been sent to the state so far
+    pub async fn run_until_idle(&mut self) {
+        while !self.receiver.is_empty() {
+            if let Err(err) = self.state.handle(&mut self.receiver).await {
+                self.errors.push(err);
+            }
+        }
+    }
+
+    pub async fn connect(&mut self, name: &str) -> SimPlayer {
+        let (outbox, inbox) = unbounded_channel();
+        let id = PlayerId(Uuid::new_v4());
+        self.internal(InternalMessage::NewPlayer(Player {
+            id,
+            name: name.to_string(),
+            current_lobby: None,
+            connection: Arc::new(SimConnection(outbox)),
+        }))
+        .await;
+        SimPlayer { id, inbox }
+    }
+
+    pub async fn send(&mut self, player: &SimPlayer, message: ClientToLobby) {
+        self.internal(InternalMessage::PlayerMessage {
+            player: player.id,
+            message,
+        })
+        .await;
+    }
+
+    pub async fn internal(&mut self, message: InternalMessage) {
+        self.sender.send(message).unwrap();
+        self.run_until_idle().await;
+    }
+
+    pub async fn disconnect(&mut self, player: SimPlayer) {
+        self.internal(InternalMessage::PlayerDisconnected(player.id))
+            .await;
+    }
+
+    /// Creates a lobby led by `player`, and clears their inbox
+    pub async fn create_lobby(&mut self, player: &mut SimPlayer) -> LobbyId {
+        self.send(player, ClientToLobby::CreateAndJoinLobby).await;
+        player
+            .take()
+            .into_iter()
+            .find_map(|m| match m {
+                LobbyToClient::YouJoinedLobby(lobby) => Some(lobby),
+                _ => None,
+            })
+            .expect("lobby was not created")
+    }
+
+    /// Has `players` join `lobby`, then clears their inboxes
+    pub async fn join_lobby(&mut self, lobby: LobbyId, players: &mut [&mut SimPlayer]) {
+        for player in players.iter() {
+            self.send(player, ClientToLobby::JoinLobby(lobby)).await;
+        }
+        for player in players.iter_mut() {
+            player.take();
+        }
+    }
+
+    pub async fn lobby_info(&mut self, asking: &mut SimPlayer, lobby: LobbyId) -> LobbyInfo {
+        self.send(asking, ClientToLobby::GetLobbyInfo(lobby)).await;
+        asking
+            .take()
+            .into_iter()
+            .rev()
+            .find_map(|m| match m {
+                LobbyToClient::LobbyInfo(info) => Some(info),
+                _ => None,
+            })
+            .expect("no lobby info received")
+    }
+
+    pub async fn lobby_list(&mut self, asking: &mut SimPlayer) -> Vec<LobbyShortInfo> {
+        self.send(asking, ClientToLobby::FetchLobbyList).await;
+        asking
+            .take()
+            .into_iter()
+            .rev()
+            .find_map(|m| match m {
+                LobbyToClient::LobbyList(list) => Some(list),
+                _ => None,
+            })
+            .expect("no lobby list received")
+    }
+
+    pub fn launched_games(&self) -> std::sync::MutexGuard<'_, Vec<LaunchedGame>> {
+        self.launched.lock().unwrap()
+    }
+
+    /// Saves the lobbies and starts over with a new state that restores them, like a restart of
+    /// the lobby server. Players of the old state are gone and have to connect again.
+    pub fn restart(&mut self) -> usize {
+        self.state.persist_lobbies().unwrap();
+        self.state = State::new(
+            self.sender.clone(),
+            self.options.clone(),
+            Box::new(SimLauncher {
+                launched: self.launched.clone(),
+                fail: self.fail_launches.clone(),
+            }),
+        );
+        self.state.restore_lobbies().unwrap()
+    }
+
+    /// Makes every game launch from now on fail
+    pub fn fail_launches(&self) {
+        self.fail_launches.store(true, Ordering::Relaxed);
+    }
+
+    /// Simulates the game server of `lobby` exiting
+    pub async fn finish_game(&mut self, lobby: LobbyId) {
+        let game = {
+            let mut launched = self.launched.lock().unwrap();
+            let index = launched
+                .iter()
+                .position(|g| g.launch.lobby_id == lobby)
+                .expect("no game running for lobby");
+            launched.remove(index)
+        };
+        self.internal(InternalMessage::GameServerClosed(lobby))
+            .await;
+        self.internal(InternalMessage::ExternalPortReleased(
+            game.launch.external_port,
+        ))
+        .await;
+        self.internal(InternalMessage::ExternalPortReleased(
+            game.launch.webtransport_port,
+        ))
+        .await;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+}
+
+impl SimPlayer {
+    /// Everything this player received since the last call
+    pub fn take(&mut self) -> Vec<LobbyToClient> {
+        let mut messages = vec![];
+        while let Ok(message) = self.inbox.try_recv() {
+            messages.push(message);
+        }
+        messages
+    }
+}
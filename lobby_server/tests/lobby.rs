@@ -0,0 +1,650 @@
+mod harness;
+
+use engine_common::ChampionId;
+use harness::Harness;
+use lobby_common::{
+    ClientToLobby, LobbySettings, LobbyState, LobbyToClient, LobbyToServer, MatchPhase, Team,
+};
+use lobby_server::InternalMessage;
+use tokio::sync::oneshot::error::TryRecvError;
+
+fn settings(team_count: usize, max_players_per_team: usize) -> LobbySettings {
+    LobbySettings {
+        name: "Test lobby".to_string(),
+        locked: false,
+        team_count,
+        max_players_per_team,
+    }
+}
+
+#[tokio::test]
+async fn created_lobby_shows_up_in_lobby_list() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.send(&b, ClientToLobby::FetchLobbyList).await;
+
+    let messages = b.take();
+    let [LobbyToClient::LobbyList(list)] = messages.as_slice() else {
+        panic!("unexpected messages {messages:?}");
+    };
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].id, lobby);
+    assert_eq!(list[0].player_count, 1);
+}
+
+#[tokio::test]
+async fn joining_notifies_players_in_lobby() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.send(&b, ClientToLobby::JoinLobby(lobby)).await;
+
+    assert!(matches!(b.take().as_slice(), [LobbyToClient::YouJoinedLobby(l)] if *l == lobby));
+    assert!(matches!(a.take().as_slice(), [LobbyToClient::PlayerJoinedLobby(p)] if *p == b.id));
+
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams, vec![vec![a.id], vec![b.id]]);
+    assert_eq!(info.leader, a.id);
+}
+
+#[tokio::test]
+async fn cannot_join_full_or_locked_lobby() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+    let mut c = h.connect("c").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.send(&a, ClientToLobby::SetLobbySettings(settings(1, 1)))
+        .await;
+    h.send(&b, ClientToLobby::JoinLobby(lobby)).await;
+    assert!(b.take().is_empty());
+
+    let mut locked = settings(2, 5);
+    locked.locked = true;
+    h.send(&a, ClientToLobby::SetLobbySettings(locked)).await;
+    h.send(&c, ClientToLobby::JoinLobby(lobby)).await;
+    assert!(c.take().is_empty());
+
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams.iter().flatten().count(), 1);
+}
+
+#[tokio::test]
+async fn duplicate_names_get_numbered() {
+    let mut h = Harness::new();
+    let mut a = h.connect("player").await;
+    let b = h.connect("player").await;
+
+    h.send(&a, ClientToLobby::GetPlayerInfo(b.id)).await;
+
+    let messages = a.take();
+    let [LobbyToClient::PlayerInfo(info)] = messages.as_slice() else {
+        panic!("unexpected messages {messages:?}");
+    };
+    assert_eq!(info.name, "player 2");
+}
+
+#[tokio::test]
+async fn leader_can_kick_players() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+    a.take();
+
+    h.send(&a, ClientToLobby::KickPlayer(b.id)).await;
+
+    assert!(matches!(b.take().as_slice(), [LobbyToClient::YouLeftLobby]));
+    assert!(matches!(a.take().as_slice(), [LobbyToClient::PlayerLeftLobby(p)] if *p == b.id));
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams.iter().flatten().count(), 1);
+}
+
+#[tokio::test]
+async fn only_leader_can_kick_players() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+    a.take();
+
+    h.send(&b, ClientToLobby::KickPlayer(a.id)).await;
+
+    assert!(a.take().is_empty());
+    assert!(b.take().is_empty());
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams.iter().flatten().count(), 2);
+}
+
+#[tokio::test]
+async fn leadership_passes_on_when_leader_leaves() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+
+    h.send(&a, ClientToLobby::LeaveCurrentLobby).await;
+
+    let info = h.lobby_info(&mut b, lobby).await;
+    assert_eq!(info.leader, b.id);
+    assert_eq!(info.teams.iter().flatten().collect::<Vec<_>>(), [&b.id]);
+}
+
+#[tokio::test]
+async fn lobby_is_removed_once_empty() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.disconnect(a).await;
+
+    let mut b = h.connect("b").await;
+    h.send(&b, ClientToLobby::GetLobbyInfo(lobby)).await;
+    assert!(b.take().is_empty());
+}
+
+#[tokio::test]
+async fn players_can_change_their_own_team() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+    a.take();
+
+    h.send(&b, ClientToLobby::ChangePlayerTeam(b.id, Team(0)))
+        .await;
+
+    assert!(
+        matches!(a.take().as_slice(), [LobbyToClient::PlayerChangedTeam(p, Team(0))] if *p == b.id)
+    );
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams, vec![vec![a.id, b.id], vec![]]);
+}
+
+#[tokio::test]
+async fn only_leader_can_move_other_players() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+
+    h.send(&b, ClientToLobby::ChangePlayerTeam(a.id, Team(1)))
+        .await;
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams, vec![vec![a.id], vec![b.id]]);
+
+    h.send(&a, ClientToLobby::ChangePlayerTeam(b.id, Team(0)))
+        .await;
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams, vec![vec![a.id, b.id], vec![]]);
+}
+
+#[tokio::test]
+async fn cannot_change_into_full_or_missing_team() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.send(&a, ClientToLobby::SetLobbySettings(settings(2, 1)))
+        .await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+
+    h.send(&b, ClientToLobby::ChangePlayerTeam(b.id, Team(0)))
+        .await;
+    h.send(&b, ClientToLobby::ChangePlayerTeam(b.id, Team(2)))
+        .await;
+
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams, vec![vec![a.id], vec![b.id]]);
+}
+
+#[tokio::test]
+async fn leader_can_switch_player_positions() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+
+    h.send(&a, ClientToLobby::SwitchPlayerPositions(a.id, b.id))
+        .await;
+
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams, vec![vec![b.id], vec![a.id]]);
+}
+
+#[tokio::test]
+async fn adding_a_team_rebalances_players() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+    let mut c = h.connect("c").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b, &mut c]).await;
+
+    h.send(&a, ClientToLobby::SetLobbySettings(settings(3, 1)))
+        .await;
+
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams.len(), 3);
+    assert!(info.teams.iter().all(|team| team.len() == 1));
+}
+
+#[tokio::test]
+async fn removing_a_team_moves_its_players() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+
+    h.send(&a, ClientToLobby::SetLobbySettings(settings(1, 5)))
+        .await;
+
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.teams, vec![vec![a.id, b.id]]);
+}
+
+#[tokio::test]
+async fn only_leader_can_go_to_champ_select() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+    a.take();
+
+    h.send(&b, ClientToLobby::GoToChampSelect).await;
+    assert!(a.take().is_empty());
+    assert!(b.take().is_empty());
+
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    assert!(matches!(
+        a.take().as_slice(),
+        [LobbyToClient::GoToChampSelect]
+    ));
+    assert!(matches!(
+        b.take().as_slice(),
+        [LobbyToClient::GoToChampSelect]
+    ));
+}
+
+#[tokio::test]
+async fn game_starts_once_everyone_locked_in() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+
+    h.send(&a, ClientToLobby::SelectChamp(ChampionId("champ_a".into())))
+        .await;
+    h.send(&a, ClientToLobby::LockSelection).await;
+    h.send(&b, ClientToLobby::SelectChamp(ChampionId("champ_b".into())))
+        .await;
+    assert!(h.launched_games().is_empty());
+
+    // Locked selections can't be changed
+    h.send(&a, ClientToLobby::SelectChamp(ChampionId("other".into())))
+        .await;
+
+    h.send(&b, ClientToLobby::LockSelection).await;
+
+    {
+        let launched = h.launched_games();
+        let [game] = launched.as_slice() else {
+            panic!("expected exactly one game");
+        };
+        assert_eq!(game.launch.lobby_id, lobby);
+        let players = game
+            .launch
+            .players
+            .iter()
+            .map(|p| (p.id, p.team, p.champ.0.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            players,
+            [(a.id, Team(0), "champ_a"), (b.id, Team(1), "champ_b")]
+        );
+    }
+
+    for player in [&mut a, &mut b] {
+        let id = player.id;
+        let messages = player.take();
+        assert!(
+            messages.iter().any(
+                |m| matches!(m, LobbyToClient::GameStarted(candidates) if candidates[0].token == id.0.as_bytes())
+            ),
+            "no game token in {messages:?}"
+        );
+    }
+
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.lobby_state, LobbyState::InGame);
+}
+
+#[tokio::test]
+async fn lobby_returns_after_game_ends() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    h.send(&a, ClientToLobby::SelectChamp(ChampionId("champ".into())))
+        .await;
+    h.send(&a, ClientToLobby::LockSelection).await;
+    a.take();
+
+    h.finish_game(lobby).await;
+
+    assert!(matches!(
+        a.take().as_slice(),
+        [LobbyToClient::ReturnFromChampSelect]
+    ));
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.lobby_state, LobbyState::InLobby);
+    assert!(info.selected_champs.is_empty());
+}
+
+#[tokio::test]
+async fn game_time_shows_in_lobby_list() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    h.send(&a, ClientToLobby::SelectChamp(ChampionId("champ".into())))
+        .await;
+    h.send(&a, ClientToLobby::LockSelection).await;
+
+    h.internal(InternalMessage::GameStatus {
+        lobby,
+        phase: MatchPhase::Loading,
+        game_time: 0,
+        connected_players: vec![],
+    })
+    .await;
+    assert_eq!(h.lobby_list(&mut a).await[0].game_time, None);
+
+    h.internal(InternalMessage::GameStatus {
+        lobby,
+        phase: MatchPhase::InProgress,
+        game_time: 754,
+        connected_players: vec![a.id],
+    })
+    .await;
+    assert_eq!(h.lobby_list(&mut a).await[0].game_time, Some(754));
+
+    h.finish_game(lobby).await;
+    assert_eq!(h.lobby_list(&mut a).await[0].game_time, None);
+}
+
+#[tokio::test]
+async fn players_can_ask_to_rejoin_their_game() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let b = h.connect("b").await;
+
+    h.create_lobby(&mut a).await;
+    h.send(&b, ClientToLobby::RejoinGame).await;
+    h.send(&a, ClientToLobby::RejoinGame).await;
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    h.send(&a, ClientToLobby::SelectChamp(ChampionId("champ".into())))
+        .await;
+    h.send(&a, ClientToLobby::LockSelection).await;
+    assert!(h.launched_games()[0].take_control().is_empty());
+
+    h.send(&a, ClientToLobby::RejoinGame).await;
+    assert!(matches!(
+        h.launched_games()[0].take_control().as_slice(),
+        [LobbyToServer::IssueToken { player, .. }] if *player == a.id
+    ));
+
+    // Players outside the lobby can't get into its game
+    h.send(&b, ClientToLobby::RejoinGame).await;
+    assert!(h.launched_games()[0].take_control().is_empty());
+}
+
+#[tokio::test]
+async fn kicked_players_are_removed_from_the_game() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    for player in [&a, &b] {
+        h.send(
+            player,
+            ClientToLobby::SelectChamp(ChampionId("champ".into())),
+        )
+        .await;
+        h.send(player, ClientToLobby::LockSelection).await;
+    }
+    b.take();
+
+    h.internal(InternalMessage::KickFromGame(b.id)).await;
+
+    assert!(matches!(
+        h.launched_games()[0].take_control().as_slice(),
+        [LobbyToServer::KickPlayer(player)] if *player == b.id
+    ));
+    assert!(
+        b.take()
+            .iter()
+            .any(|m| matches!(m, LobbyToClient::YouLeftLobby))
+    );
+    assert!(h.errors.is_empty());
+}
+
+#[tokio::test]
+async fn operator_can_end_running_game() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.internal(InternalMessage::EndGame(lobby)).await;
+    assert_eq!(h.errors.len(), 1);
+
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    h.send(&a, ClientToLobby::SelectChamp(ChampionId("champ".into())))
+        .await;
+    h.send(&a, ClientToLobby::LockSelection).await;
+    h.internal(InternalMessage::EndGame(lobby)).await;
+
+    assert!(matches!(
+        h.launched_games()[0].take_control().as_slice(),
+        [LobbyToServer::EndMatch]
+    ));
+}
+
+#[tokio::test]
+async fn games_are_limited_by_external_ports() {
+    let mut h = Harness::new();
+    let mut players = vec![];
+
+    // The harness has external ports for two games, each using a UDP and a WebTransport port
+    for i in 0..3 {
+        let mut player = h.connect(&format!("p{i}")).await;
+        h.create_lobby(&mut player).await;
+        h.send(&player, ClientToLobby::GoToChampSelect).await;
+        h.send(
+            &player,
+            ClientToLobby::SelectChamp(ChampionId("champ".into())),
+        )
+        .await;
+        h.send(&player, ClientToLobby::LockSelection).await;
+        players.push(player);
+    }
+
+    assert_eq!(h.launched_games().len(), 2);
+    let third = players.last_mut().unwrap();
+    assert!(
+        !third
+            .take()
+            .iter()
+            .any(|m| matches!(m, LobbyToClient::GameStarted(_)))
+    );
+}
+
+#[tokio::test]
+async fn first_player_to_join_leads_restored_lobby() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let lobby = h.create_lobby(&mut a).await;
+
+    assert_eq!(h.restart(), 1);
+    let mut b = h.connect("b").await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+
+    let info = h.lobby_info(&mut b, lobby).await;
+    assert_eq!(info.leader, b.id);
+    assert_eq!(info.teams.concat(), [b.id]);
+
+    // Only lobbies nobody rejoined expire
+    h.internal(InternalMessage::RestoredLobbiesExpired).await;
+    assert_eq!(h.lobby_list(&mut b).await.len(), 1);
+}
+
+#[tokio::test]
+async fn restored_lobbies_nobody_rejoins_expire() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    h.create_lobby(&mut a).await;
+
+    assert_eq!(h.restart(), 1);
+    let mut b = h.connect("b").await;
+    assert_eq!(h.lobby_list(&mut b).await.len(), 1);
+
+    h.internal(InternalMessage::RestoredLobbiesExpired).await;
+    assert!(h.lobby_list(&mut b).await.is_empty());
+}
+
+#[tokio::test]
+async fn failed_launch_returns_lobby_to_lobby_state() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.fail_launches();
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    h.send(&a, ClientToLobby::SelectChamp(ChampionId("champ".into())))
+        .await;
+    h.send(&a, ClientToLobby::LockSelection).await;
+
+    assert!(h.launched_games().is_empty());
+    assert!(
+        a.take()
+            .iter()
+            .any(|m| matches!(m, LobbyToClient::ReturnFromChampSelect))
+    );
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.lobby_state, LobbyState::InLobby);
+    assert!(info.selected_champs.is_empty());
+}
+
+#[tokio::test]
+async fn leaving_champ_select_returns_everyone_to_lobby() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+    let mut b = h.connect("b").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.join_lobby(lobby, &mut [&mut b]).await;
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    a.take();
+
+    h.disconnect(b).await;
+
+    let messages = a.take();
+    assert!(matches!(
+        messages.as_slice(),
+        [
+            LobbyToClient::ReturnFromChampSelect,
+            LobbyToClient::PlayerLeftLobby(_)
+        ]
+    ));
+    let info = h.lobby_info(&mut a, lobby).await;
+    assert_eq!(info.lobby_state, LobbyState::InLobby);
+}
+
+#[tokio::test]
+async fn draining_stops_new_lobbies_and_finishes_without_games() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+
+    h.internal(InternalMessage::BeginDrain).await;
+
+    assert!(matches!(
+        a.take().as_slice(),
+        [LobbyToClient::ServerShuttingDown { .. }]
+    ));
+    assert!(h.is_finished());
+
+    h.send(&a, ClientToLobby::CreateAndJoinLobby).await;
+    assert!(a.take().is_empty());
+}
+
+#[tokio::test]
+async fn draining_waits_for_running_games() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+
+    let lobby = h.create_lobby(&mut a).await;
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    h.send(&a, ClientToLobby::SelectChamp(ChampionId("champ".into())))
+        .await;
+    h.send(&a, ClientToLobby::LockSelection).await;
+
+    h.internal(InternalMessage::BeginDrain).await;
+    assert!(!h.is_finished());
+
+    h.finish_game(lobby).await;
+    assert!(h.is_finished());
+    assert!(h.errors.is_empty());
+}
+
+#[tokio::test]
+async fn drain_deadline_kills_running_games() {
+    let mut h = Harness::new();
+    let mut a = h.connect("a").await;
+
+    h.create_lobby(&mut a).await;
+    h.send(&a, ClientToLobby::GoToChampSelect).await;
+    h.send(&a, ClientToLobby::SelectChamp(ChampionId("champ".into())))
+        .await;
+    h.send(&a, ClientToLobby::LockSelection).await;
+
+    h.internal(InternalMessage::DrainDeadlineReached).await;
+
+    // Killed by dropping the sender, rather than left waiting for a kill that never comes
+    assert!(matches!(
+        h.launched_games()[0].kill.try_recv(),
+        Err(TryRecvError::Closed)
+    ));
+    assert!(h.is_finished());
+}